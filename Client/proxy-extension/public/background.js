@@ -93,7 +93,7 @@ chrome.runtime.onMessage.addListener((request, sender, sendResponse) => {
   
   if (request.action === 'checkServerStatus') {
     // Check if proxy server is reachable
-    fetch(`${PROXY_SERVER}/pqc-info`)
+    fetch(`${PROXY_SERVER}/healthz`)
       .then(response => {
         sendResponse({ 
           online: response.ok,
@@ -49,7 +49,7 @@ const ProxyToggle = () => {
         );
       } else {
         // Direct fetch for development
-        const response = await fetch('http://localhost:8080/pqc-info', {
+        const response = await fetch('http://localhost:8080/healthz', {
           method: 'GET',
           headers: {
             'Content-Type': 'application/json',
@@ -0,0 +1,514 @@
+use actix_web::body::{BodySize, BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+
+use crate::config::ProxyConfig;
+use crate::telemetry::RequestInfo;
+
+/// Layout of each access log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common plus the quoted `Referer` and `User-Agent`
+    Combined,
+}
+
+impl AccessLogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "common" | "clf" => Some(AccessLogFormat::Common),
+            "combined" => Some(AccessLogFormat::Combined),
+            _ => None,
+        }
+    }
+}
+
+/// When the access log is additionally rotated regardless of its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "never" | "none" => Some(Rotation::Never),
+            "hourly" => Some(Rotation::Hourly),
+            "daily" => Some(Rotation::Daily),
+            _ => None,
+        }
+    }
+
+    /// Which rotation period a UNIX time falls in, if rotating by time
+    fn period(self, unix_secs: u64) -> Option<u64> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(unix_secs / 3600),
+            Rotation::Daily => Some(unix_secs / 86400),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogSettings {
+    /// File the log is appended to; no access log is written when unset
+    pub path: Option<PathBuf>,
+    pub format: AccessLogFormat,
+    /// Replace query parameter values in target URLs with `REDACTED`
+    pub redact_query: bool,
+    /// Rotate once the file reaches this many bytes; 0 disables size-based rotation
+    pub max_bytes: u64,
+    pub rotation: Rotation,
+    /// Rotated files kept next to the active one; older ones are deleted
+    pub keep: usize,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            path: None,
+            format: AccessLogFormat::Combined,
+            redact_query: false,
+            max_bytes: 100 * 1024 * 1024,
+            rotation: Rotation::Never,
+            keep: 10,
+        }
+    }
+}
+
+/// One finished request, as written to the log
+struct Entry {
+    client: String,
+    user: Option<String>,
+    time: OffsetDateTime,
+    method: String,
+    target: String,
+    protocol: String,
+    status: u16,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    info: RequestInfo,
+}
+
+struct Output {
+    file: File,
+    size: u64,
+    period: Option<u64>,
+}
+
+/// Append-only access log, rotated by size or time
+pub struct AccessLog {
+    settings: AccessLogSettings,
+    output: Mutex<Option<Output>>,
+}
+
+impl AccessLog {
+    /// Open the log file for appending; a disabled log opens nothing
+    pub fn open(settings: &AccessLogSettings) -> Result<Self, String> {
+        let output = match &settings.path {
+            Some(path) => {
+                let file = open_append(path)?;
+                let metadata = file.metadata().map_err(|e| format!("{}: {}", path.display(), e))?;
+                // A file left by an earlier run belongs to the period it was last written in
+                let modified = metadata.modified().map_or_else(|_| unix_now(), unix_secs);
+                Some(Output { file, size: metadata.len(), period: settings.rotation.period(modified) })
+            }
+            None => None,
+        };
+        Ok(Self { settings: settings.clone(), output: Mutex::new(output) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.settings.path.is_some()
+    }
+
+    /// Push everything written so far to disk
+    pub fn flush(&self) {
+        if let Some(out) = self.output.lock().unwrap().as_mut()
+            && let Err(e) = out.file.sync_data()
+        {
+            tracing::warn!(error = %e, "failed to flush access log");
+        }
+    }
+
+    fn write(&self, entry: &Entry, bytes: u64, duration_ms: u128) {
+        let Some(path) = &self.settings.path else {
+            return;
+        };
+        let line = self.format(entry, bytes, duration_ms);
+
+        let mut output = self.output.lock().unwrap();
+        let now = unix_now();
+        let rotate = output.as_ref().is_some_and(|out| {
+            (self.settings.max_bytes > 0 && out.size + line.len() as u64 > self.settings.max_bytes && out.size > 0)
+                || out.period != self.settings.rotation.period(now)
+        });
+        if rotate || output.is_none() {
+            *output = None;
+            if rotate && let Err(e) = self.rotate(path) {
+                tracing::warn!(error = %e, "failed to rotate access log");
+            }
+            match open_append(path) {
+                Ok(file) => {
+                    let size = file.metadata().map_or(0, |m| m.len());
+                    *output = Some(Output { file, size, period: self.settings.rotation.period(now) });
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to open access log");
+                    return;
+                }
+            }
+        }
+
+        if let Some(out) = output.as_mut() {
+            match out.file.write_all(line.as_bytes()) {
+                Ok(()) => out.size += line.len() as u64,
+                Err(e) => tracing::warn!(error = %e, "failed to write access log"),
+            }
+        }
+    }
+
+    /// Move the active file aside under a timestamp suffix and delete the oldest beyond `keep`
+    fn rotate(&self, path: &Path) -> Result<(), String> {
+        let stamp = OffsetDateTime::now_utc()
+            .format(time::macros::format_description!("[year][month][day]T[hour][minute][second]Z"))
+            .map_err(|e| e.to_string())?;
+        let mut rotated = suffixed(path, &stamp);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = suffixed(path, &format!("{}-{}", stamp, n));
+            n += 1;
+        }
+        fs::rename(path, &rotated).map_err(|e| format!("{}: {}", path.display(), e))?;
+        tracing::info!(path = %rotated.display(), "rotated access log");
+
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let prefix = format!("{}.", name);
+        let mut old: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|n| n.starts_with(&prefix)))
+            .map(|entry| entry.path())
+            .collect();
+        // Timestamp suffixes sort chronologically
+        old.sort();
+        let excess = old.len().saturating_sub(self.settings.keep);
+        for file in &old[..excess] {
+            if let Err(e) = fs::remove_file(file) {
+                tracing::warn!(path = %file.display(), error = %e, "failed to delete old access log");
+            }
+        }
+        Ok(())
+    }
+
+    fn format(&self, entry: &Entry, bytes: u64, duration_ms: u128) -> String {
+        let time = entry
+            .time
+            .format(time::macros::format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+            ))
+            .unwrap_or_default();
+        let target = match &entry.info.target_url {
+            Some(url) => self.redact(url),
+            None => self.redact(&entry.target),
+        };
+        let mut line = format!(
+            "{} - {} [{}] \"{} {} {}\" {} {}",
+            entry.client,
+            entry.user.as_deref().map_or_else(|| "-".to_string(), escape),
+            time,
+            entry.method,
+            escape(&target),
+            entry.protocol,
+            entry.status,
+            if bytes == 0 { "-".to_string() } else { bytes.to_string() },
+        );
+        if self.settings.format == AccessLogFormat::Combined {
+            line.push_str(&format!(" \"{}\" \"{}\"", quoted(entry.referer.as_deref()), quoted(entry.user_agent.as_deref())));
+        }
+        line.push_str(&format!(
+            " session=\"{}\" upstream_status={} duration_ms={}\n",
+            quoted(entry.info.session_id.as_deref()),
+            entry.info.upstream_status.map_or_else(|| "-".to_string(), |s| s.to_string()),
+            duration_ms,
+        ));
+        line
+    }
+
+    /// With `redact_query`, keep the query's parameter names but drop their values
+    fn redact(&self, target: &str) -> String {
+        if !self.settings.redact_query {
+            return target.to_string();
+        }
+        let Some((base, query)) = target.split_once('?') else {
+            return target.to_string();
+        };
+        let (query, fragment) = match query.split_once('#') {
+            Some((query, fragment)) => (query, Some(fragment)),
+            None => (query, None),
+        };
+        let redacted: Vec<String> = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) => format!("{}=REDACTED", name),
+                None => pair.to_string(),
+            })
+            .collect();
+        match fragment {
+            Some(fragment) => format!("{}?{}#{}", base, redacted.join("&"), fragment),
+            None => format!("{}?{}", base, redacted.join("&")),
+        }
+    }
+}
+
+/// Write an access log line for each request once its response body has been sent
+pub async fn record(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<BoxBody, LoggedBody>>, Error> {
+    let Some(log) = req.app_data::<web::Data<AccessLog>>().filter(|log| log.enabled()).cloned() else {
+        return next.call(req).await.map(|res| res.map_body(|_, body| body.boxed()).map_into_left_body());
+    };
+
+    let client = match req.app_data::<web::Data<ProxyConfig>>() {
+        Some(config) => crate::forwarding::client_ip(req.request(), &config.forwarding).to_string(),
+        None => req.peer_addr().map_or_else(|| "-".to_string(), |addr| addr.ip().to_string()),
+    };
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let mut entry = Entry {
+        client,
+        user: None,
+        time: OffsetDateTime::now_utc(),
+        method: req.method().to_string(),
+        // Absent a destination, the request's own path is logged without its query
+        target: req.path().to_string(),
+        protocol: format!("{:?}", req.version()),
+        status: 0,
+        referer: header("referer"),
+        user_agent: header("user-agent"),
+        info: RequestInfo::default(),
+    };
+    let started = Instant::now();
+
+    let response = next.call(req).await?;
+    entry.status = response.status().as_u16();
+    entry.info = RequestInfo::of(response.request());
+    entry.user = entry.info.subject.clone();
+
+    Ok(response
+        .map_body(|_, body| LoggedBody { body: body.boxed(), bytes: 0, started, entry: Some(entry), log })
+        .map_into_right_body())
+}
+
+/// A response body that counts the bytes sent and logs the request when it is dropped, so
+/// streamed responses are logged with their full size and duration
+pub struct LoggedBody {
+    body: BoxBody,
+    bytes: u64,
+    started: Instant,
+    entry: Option<Entry>,
+    log: web::Data<AccessLog>,
+}
+
+impl MessageBody for LoggedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.log.write(&entry, self.bytes, self.started.elapsed().as_millis());
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn unix_now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn quoted(value: Option<&str>) -> String {
+    value.map_or_else(|| "-".to_string(), escape)
+}
+
+/// Escape quotes, backslashes and control characters so a field can't break the line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own, since rotation looks at every file next to the log
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("vpn-server-access-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut names: Vec<String> =
+                fs::read_dir(&self.0).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            client: "192.0.2.1".to_string(),
+            user: Some("alice".to_string()),
+            time: OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+            method: "GET".to_string(),
+            target: "/proxy".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: 200,
+            referer: None,
+            user_agent: Some("curl/8 \"quoted\"".to_string()),
+            info: RequestInfo {
+                session_id: Some("session_abc".to_string()),
+                target_url: Some("https://example.com/a?q=secret&page=2".to_string()),
+                upstream_status: Some(200),
+                ..RequestInfo::default()
+            },
+        }
+    }
+
+    fn log(settings: AccessLogSettings) -> AccessLog {
+        AccessLog { settings, output: Mutex::new(None) }
+    }
+
+    #[test]
+    fn formats_common_and_combined_lines() {
+        let common = log(AccessLogSettings { format: AccessLogFormat::Common, ..AccessLogSettings::default() });
+        assert_eq!(
+            common.format(&entry(), 512, 7),
+            "192.0.2.1 - alice [14/Nov/2023:22:13:20 +0000] \"GET https://example.com/a?q=secret&page=2 HTTP/1.1\" 200 512 \
+             session=\"session_abc\" upstream_status=200 duration_ms=7\n"
+        );
+
+        let combined = log(AccessLogSettings { redact_query: true, ..AccessLogSettings::default() });
+        let mut unauthenticated = entry();
+        unauthenticated.user = None;
+        unauthenticated.info = RequestInfo::default();
+        unauthenticated.target = "/healthz".to_string();
+        assert_eq!(
+            combined.format(&entry(), 0, 7),
+            "192.0.2.1 - alice [14/Nov/2023:22:13:20 +0000] \"GET https://example.com/a?q=REDACTED&page=REDACTED HTTP/1.1\" 200 - \
+             \"-\" \"curl/8 \\\"quoted\\\"\" session=\"session_abc\" upstream_status=200 duration_ms=7\n"
+        );
+        assert_eq!(
+            combined.format(&unauthenticated, 2, 0),
+            "192.0.2.1 - - [14/Nov/2023:22:13:20 +0000] \"GET /healthz HTTP/1.1\" 200 2 \
+             \"-\" \"curl/8 \\\"quoted\\\"\" session=\"-\" upstream_status=- duration_ms=0\n"
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = TempDir::new("size");
+        let path = dir.0.join("access.log");
+        let line_len = log(AccessLogSettings::default()).format(&entry(), 1, 1).len() as u64;
+        let settings = AccessLogSettings { path: Some(path.clone()), max_bytes: line_len * 2, keep: 2, ..AccessLogSettings::default() };
+        let access_log = AccessLog::open(&settings).unwrap();
+
+        for _ in 0..4 {
+            access_log.write(&entry(), 1, 1);
+        }
+        // Two lines fit in each file; the first rotation happened on the third line
+        assert_eq!(dir.files().len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        for _ in 0..6 {
+            access_log.write(&entry(), 1, 1);
+        }
+        let files = dir.files();
+        assert_eq!(files.len(), 3, "{:?}", files);
+        assert!(files.iter().all(|name| name == "access.log" || name.starts_with("access.log.")));
+    }
+
+    #[test]
+    fn rotates_a_file_left_from_an_earlier_day() {
+        let dir = TempDir::new("daily");
+        let path = dir.0.join("access.log");
+        fs::write(&path, "old line\n").unwrap();
+        let yesterday = SystemTime::now() - std::time::Duration::from_secs(86400);
+        File::options().write(true).open(&path).unwrap().set_modified(yesterday).unwrap();
+
+        let settings = AccessLogSettings { path: Some(path.clone()), rotation: Rotation::Daily, ..AccessLogSettings::default() };
+        let access_log = AccessLog::open(&settings).unwrap();
+        access_log.write(&entry(), 1, 1);
+        access_log.write(&entry(), 1, 1);
+
+        assert_eq!(dir.files().len(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let rotated = dir.files().into_iter().find(|name| name != "access.log").unwrap();
+        assert_eq!(fs::read_to_string(dir.0.join(rotated)).unwrap(), "old line\n");
+    }
+}
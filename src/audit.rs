@@ -0,0 +1,483 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::pqc::PqcCrypto;
+
+const CHECKPOINT: &str = "checkpoint";
+
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct AuditSettings {
+    /// Where the log is appended; no audit log is kept when unset
+    pub path: Option<PathBuf>,
+    /// Sign a checkpoint after this many entries
+    pub checkpoint_every: u64,
+    /// ... and at least this often while new entries arrive, in seconds
+    pub checkpoint_secs: u64,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self { path: None, checkpoint_every: 100, checkpoint_secs: 300 }
+    }
+}
+
+/// The hashed part of an entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Body {
+    seq: u64,
+    time: u64,
+    event: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, String>,
+    /// Hash of the previous entry; empty for the first
+    prev: String,
+}
+
+/// One line of the log: the body, its SHA3-256 hash, and for checkpoints a Dilithium
+/// signature over that hash
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    #[serde(flatten)]
+    body: Body,
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+struct Chain {
+    file: File,
+    next_seq: u64,
+    prev: String,
+    since_checkpoint: u64,
+}
+
+/// Append-only, hash-chained log of security-relevant events
+pub struct AuditLog {
+    settings: AuditSettings,
+    pqc: &'static PqcCrypto,
+    chain: Mutex<Chain>,
+}
+
+impl AuditLog {
+    /// Open the log, continuing the chain from its last entry
+    fn open(settings: &AuditSettings, path: &Path, pqc: &'static PqcCrypto) -> Result<Self, String> {
+        let mut torn = None;
+        let (next_seq, prev, since_checkpoint) = match File::open(path) {
+            Ok(file) => {
+                let mut last = None;
+                let mut since_checkpoint = 0;
+                let (complete, partial) = each_line(file, |n, text| {
+                    let line: Line = serde_json::from_str(text)
+                        .map_err(|e| format!("line {} is not an audit entry: {}", n + 1, e))?;
+                    since_checkpoint = if line.body.event == CHECKPOINT { 0 } else { since_checkpoint + 1 };
+                    last = Some(line);
+                    Ok(())
+                })
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+                if partial > 0 {
+                    // Only the entry being appended when the process died can be incomplete
+                    tracing::warn!(path = %path.display(), bytes = partial, "audit log ends in a partly written entry, dropping it");
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .and_then(|file| file.set_len(complete))
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+                    torn = Some(partial);
+                }
+                match last {
+                    Some(line) => (line.body.seq + 1, line.hash, since_checkpoint),
+                    None => (0, String::new(), 0),
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, String::new(), 0),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let log = Self {
+            settings: settings.clone(),
+            pqc,
+            chain: Mutex::new(Chain { file, next_seq, prev, since_checkpoint }),
+        };
+        if let Some(bytes) = torn {
+            log.record("audit_torn_entry_dropped", BTreeMap::from([("bytes".to_string(), bytes.to_string())]));
+        }
+        if since_checkpoint > 0 {
+            // The last run stopped without signing its head, or the log was cut back to an entry
+            // boundary; say so in the chain before the next checkpoint covers those entries
+            tracing::warn!(path = %path.display(), entries = since_checkpoint, "audit log ends in entries no checkpoint covers");
+            log.record("audit_unsigned_tail_found", BTreeMap::from([("entries".to_string(), since_checkpoint.to_string())]));
+            log.checkpoint();
+        }
+        Ok(log)
+    }
+
+    fn append(&self, chain: &mut Chain, event: &str, fields: BTreeMap<String, String>) -> Result<(), String> {
+        let body = Body {
+            seq: chain.next_seq,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            event: event.to_string(),
+            fields,
+            prev: chain.prev.clone(),
+        };
+        let hash = self.pqc.hash_data(&serde_json::to_vec(&body).map_err(|e| e.to_string())?);
+        let signature = if event == CHECKPOINT {
+            Some(self.pqc.dilithium_sign(hash.as_bytes()).map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+
+        let mut text = serde_json::to_string(&Line { body, hash: hash.clone(), signature }).map_err(|e| e.to_string())?;
+        text.push('\n');
+        chain.file.write_all(text.as_bytes()).map_err(|e| e.to_string())?;
+
+        chain.next_seq += 1;
+        chain.prev = hash;
+        chain.since_checkpoint = if event == CHECKPOINT { 0 } else { chain.since_checkpoint + 1 };
+        Ok(())
+    }
+
+    fn record(&self, event: &str, fields: BTreeMap<String, String>) {
+        let mut chain = self.chain.lock().unwrap();
+        if let Err(e) = self.append(&mut chain, event, fields) {
+            tracing::error!(error = %e, event, "failed to write audit log");
+            return;
+        }
+        if chain.since_checkpoint >= self.settings.checkpoint_every {
+            self.checkpoint_locked(&mut chain);
+        }
+    }
+
+    /// Sign the chain head, if anything was recorded since the last checkpoint
+    fn checkpoint(&self) {
+        let mut chain = self.chain.lock().unwrap();
+        if chain.since_checkpoint > 0 {
+            self.checkpoint_locked(&mut chain);
+        }
+    }
+
+    fn checkpoint_locked(&self, chain: &mut Chain) {
+        let fields = BTreeMap::from([("entries".to_string(), chain.since_checkpoint.to_string())]);
+        match self.append(chain, CHECKPOINT, fields) {
+            Ok(()) => {
+                let _ = chain.file.sync_data();
+                // The head also goes to the server log, so truncating the audit log can be noticed
+                tracing::info!(seq = chain.next_seq - 1, hash = %chain.prev, "audit checkpoint");
+            }
+            Err(e) => tracing::error!(error = %e, "failed to write audit checkpoint"),
+        }
+    }
+}
+
+/// Call `each` with the number and text of every complete line of the log. Returns the length
+/// in bytes of those lines, and of a final line left without its newline by a crash mid-append
+/// (0 if there is none), which `each` doesn't see.
+fn each_line(file: File, mut each: impl FnMut(usize, &str) -> Result<(), String>) -> Result<(u64, u64), String> {
+    let mut reader = BufReader::new(file);
+    let mut buf = Vec::new();
+    let mut complete = 0;
+    for n in 0.. {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf).map_err(|e| e.to_string())?;
+        if buf.last() != Some(&b'\n') {
+            return Ok((complete, read as u64));
+        }
+        let text = std::str::from_utf8(&buf[..read - 1]).map_err(|_| format!("line {} is not valid UTF-8", n + 1))?;
+        each(n, text)?;
+        complete += read as u64;
+    }
+    unreachable!("a log can't have more lines than usize::MAX")
+}
+
+/// Open the global audit log and sign checkpoints on a timer. Without a path, recording is a no-op.
+pub fn init(settings: &AuditSettings, pqc: &'static PqcCrypto) -> Result<(), String> {
+    let Some(path) = &settings.path else {
+        return Ok(());
+    };
+    let log = AuditLog::open(settings, path, pqc)?;
+    if AUDIT_LOG.set(log).is_err() {
+        return Err("audit log already initialised".to_string());
+    }
+
+    let interval = Duration::from_secs(settings.checkpoint_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            checkpoint();
+        }
+    });
+    Ok(())
+}
+
+/// Record a security-relevant event
+pub fn record(event: &str, fields: &[(&str, &str)]) {
+    if let Some(log) = AUDIT_LOG.get() {
+        let fields = fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        log.record(event, fields);
+    }
+}
+
+/// Sign the current chain head; called periodically and before shutdown
+pub fn checkpoint() {
+    if let Some(log) = AUDIT_LOG.get() {
+        log.checkpoint();
+    }
+}
+
+/// What `verify` found in an intact log
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub entries: u64,
+    pub checkpoints: u64,
+    /// Sequence number and hash of the last entry, to compare with the `audit checkpoint` lines
+    /// in the server log
+    pub head: Option<(u64, String)>,
+    /// Entries after the last checkpoint, which no signature covers yet
+    pub unsigned_tail: u64,
+    /// Length in bytes of a partly written last entry, left out of the check
+    pub torn_tail: u64,
+    /// Sequence number of the entry whose hash was given as the expected head, if found
+    pub expected_head: Option<u64>,
+}
+
+impl VerifyReport {
+    /// Whether the log ends in a signed checkpoint with nothing after it. Anything else may be
+    /// a log cut short, whether by a crash or on purpose.
+    pub fn is_complete(&self) -> bool {
+        self.checkpoints > 0 && self.unsigned_tail == 0 && self.torn_tail == 0
+    }
+}
+
+/// Check every entry's hash and link to its predecessor, that sequence numbers start at zero
+/// without gaps, and every checkpoint's signature under `dilithium_pk`. With `expected_head`,
+/// a hash the server logged at a checkpoint, also note where that entry is, so a log cut back
+/// to before it can be told apart from one that was never longer.
+pub fn verify(path: &Path, pqc: &PqcCrypto, dilithium_pk: &str, expected_head: Option<&str>) -> Result<VerifyReport, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut report =
+        VerifyReport { entries: 0, checkpoints: 0, head: None, unsigned_tail: 0, torn_tail: 0, expected_head: None };
+    let mut prev = String::new();
+
+    let (_, torn) = each_line(file, |n, text| {
+        let at = |problem: String| format!("line {}: {}", n + 1, problem);
+        let line: Line = serde_json::from_str(text).map_err(|e| at(format!("not an audit entry: {}", e)))?;
+
+        if line.body.seq != report.entries {
+            return Err(at(format!(
+                "expected entry {} but found {}; entries were removed or reordered",
+                report.entries, line.body.seq
+            )));
+        }
+        if line.body.prev != prev {
+            return Err(at("does not follow the previous entry; the log was truncated or edited".to_string()));
+        }
+        let hash = pqc.hash_data(&serde_json::to_vec(&line.body).map_err(|e| e.to_string())?);
+        if hash != line.hash {
+            return Err(at("hash mismatch; the entry was edited".to_string()));
+        }
+
+        if line.body.event == CHECKPOINT {
+            let signature = line.signature.as_deref().ok_or_else(|| at("checkpoint is not signed".to_string()))?;
+            let signed = pqc
+                .dilithium_verify(signature, dilithium_pk)
+                .map_err(|_| at("checkpoint signature does not verify under the server key".to_string()))?;
+            if signed != hash.as_bytes() {
+                return Err(at("checkpoint signature covers a different hash".to_string()));
+            }
+            report.checkpoints += 1;
+            report.unsigned_tail = 0;
+        } else {
+            report.unsigned_tail += 1;
+        }
+
+        if expected_head == Some(hash.as_str()) {
+            report.expected_head = Some(line.body.seq);
+        }
+        report.entries += 1;
+        report.head = Some((line.body.seq, hash.clone()));
+        prev = hash;
+        Ok(())
+    })?;
+    report.torn_tail = torn;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("vpn-server-audit-{}-{}.log", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempLog(path)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            std::fs::read_to_string(&self.0).unwrap().lines().map(str::to_string).collect()
+        }
+
+        fn rewrite(&self, lines: &[String], tail: &str) {
+            std::fs::write(&self.0, lines.iter().map(|line| format!("{}\n", line)).collect::<String>() + tail).unwrap();
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn pqc() -> &'static PqcCrypto {
+        Box::leak(Box::new(PqcCrypto::new()))
+    }
+
+    /// A log with four events and a checkpoint after every second one
+    fn write_log(log: &TempLog, pqc: &'static PqcCrypto) {
+        let settings = AuditSettings { path: Some(log.0.clone()), checkpoint_every: 2, checkpoint_secs: 300 };
+        let audit = AuditLog::open(&settings, &log.0, pqc).unwrap();
+        for event in ["server_started", "token_issued", "auth_failed", "server_stopped"] {
+            audit.record(event, BTreeMap::from([("subject".to_string(), "ops".to_string())]));
+        }
+    }
+
+    fn verify_log(log: &TempLog, pqc: &PqcCrypto) -> Result<VerifyReport, String> {
+        verify(&log.0, pqc, &pqc.key_pair.dilithium_public_key, None)
+    }
+
+    #[test]
+    fn intact_log_verifies() {
+        let (log, pqc) = (TempLog::new("intact"), pqc());
+        write_log(&log, pqc);
+        let report = verify_log(&log, pqc).unwrap();
+        assert_eq!((report.entries, report.checkpoints, report.unsigned_tail, report.torn_tail), (6, 2, 0, 0));
+        assert_eq!(report.head.as_ref().unwrap().0, 5);
+        assert!(report.is_complete());
+    }
+
+    #[test]
+    fn log_cut_at_a_line_boundary_is_incomplete() {
+        let (log, pqc) = (TempLog::new("truncated"), pqc());
+        write_log(&log, pqc);
+        let lines = log.lines();
+        log.rewrite(&lines[..4], "");
+        let report = verify_log(&log, pqc).unwrap();
+        assert_eq!((report.entries, report.unsigned_tail), (4, 1));
+        assert!(!report.is_complete());
+
+        // Cutting back to an earlier checkpoint leaves a complete log, which only the head
+        // from the server log shows to be short
+        let head: Line = serde_json::from_str(&lines[5]).unwrap();
+        log.rewrite(&lines[..3], "");
+        let report = verify(&log.0, pqc, &pqc.key_pair.dilithium_public_key, Some(&head.hash)).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.expected_head, None);
+
+        log.rewrite(&lines, "");
+        let report = verify(&log.0, pqc, &pqc.key_pair.dilithium_public_key, Some(&head.hash)).unwrap();
+        assert_eq!(report.expected_head, Some(5));
+    }
+
+    #[test]
+    fn unsigned_tail_is_recorded_and_signed_on_open() {
+        let (log, pqc) = (TempLog::new("unsigned"), pqc());
+        write_log(&log, pqc);
+        let lines = log.lines();
+        log.rewrite(&lines[..4], "");
+
+        let settings = AuditSettings { path: Some(log.0.clone()), ..AuditSettings::default() };
+        AuditLog::open(&settings, &log.0, pqc).unwrap();
+        let report = verify_log(&log, pqc).unwrap();
+        assert_eq!((report.entries, report.checkpoints), (6, 2));
+        assert!(report.is_complete());
+        assert!(log.lines()[4].contains("audit_unsigned_tail_found"));
+    }
+
+    #[test]
+    fn edited_entry_fails() {
+        let (log, pqc) = (TempLog::new("edited"), pqc());
+        write_log(&log, pqc);
+        let mut lines = log.lines();
+        lines[1] = lines[1].replace(r#""subject":"ops""#, r#""subject":"eve""#);
+        log.rewrite(&lines, "");
+        let error = verify_log(&log, pqc).unwrap_err();
+        assert!(error.starts_with("line 2: hash mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn removed_entry_fails() {
+        let (log, pqc) = (TempLog::new("removed"), pqc());
+        write_log(&log, pqc);
+        let mut lines = log.lines();
+        lines.remove(1);
+        log.rewrite(&lines, "");
+        let error = verify_log(&log, pqc).unwrap_err();
+        assert!(error.contains("removed or reordered"), "{}", error);
+    }
+
+    #[test]
+    fn checkpoint_signed_by_another_key_fails() {
+        let (log, pqc) = (TempLog::new("forged"), pqc());
+        write_log(&log, pqc);
+        assert!(verify(&log.0, pqc, &PqcCrypto::new().key_pair.dilithium_public_key, None).unwrap_err().contains("signature"));
+    }
+
+    #[test]
+    fn line_cut_short_before_the_end_fails() {
+        let (log, pqc) = (TempLog::new("cut"), pqc());
+        write_log(&log, pqc);
+        let mut lines = log.lines();
+        let half = lines[2].len() / 2;
+        lines[2].truncate(half);
+        log.rewrite(&lines, "");
+        let error = verify_log(&log, pqc).unwrap_err();
+        assert!(error.starts_with("line 3: not an audit entry"), "{}", error);
+    }
+
+    #[test]
+    fn torn_last_line_is_reported_then_dropped_on_open() {
+        let (log, pqc) = (TempLog::new("torn"), pqc());
+        write_log(&log, pqc);
+        let lines = log.lines();
+        let torn = &lines[5][..lines[5].len() / 2];
+        log.rewrite(&lines[..5], torn);
+
+        let report = verify_log(&log, pqc).unwrap();
+        assert_eq!((report.entries, report.torn_tail), (5, torn.len() as u64));
+        assert!(!report.is_complete());
+
+        let settings = AuditSettings { path: Some(log.0.clone()), ..AuditSettings::default() };
+        let audit = AuditLog::open(&settings, &log.0, pqc).unwrap();
+        audit.checkpoint();
+        let report = verify_log(&log, pqc).unwrap();
+        assert_eq!((report.entries, report.torn_tail, report.unsigned_tail), (8, 0, 0));
+        assert!(log.lines()[5].contains("audit_torn_entry_dropped"));
+        assert!(log.lines()[6].contains("audit_unsigned_tail_found"));
+    }
+
+    #[test]
+    fn garbage_before_the_end_still_fails_to_open() {
+        let (log, pqc) = (TempLog::new("garbage"), pqc());
+        write_log(&log, pqc);
+        let mut lines = log.lines();
+        lines.insert(3, "not json".to_string());
+        log.rewrite(&lines, "");
+        let settings = AuditSettings { path: Some(log.0.clone()), ..AuditSettings::default() };
+        assert!(AuditLog::open(&settings, &log.0, pqc).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use actix_web::HttpRequest;
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::pqc::PqcCrypto;
+
+/// Prefix that tells signed tokens apart from static API keys
+const TOKEN_PREFIX: &str = "pqc.";
+
+/// Signed ahead of the claims, so no other signature made with the server's Dilithium key
+/// (audit checkpoints, content hashes, handshakes) can pass for a token
+const TOKEN_CONTEXT: &[u8] = b"vpn-server auth token v1\0";
+/// `typ` claim of access tokens
+const TOKEN_TYPE: &str = "access";
+
+/// Realm advertised in authentication challenges
+pub const REALM: &str = "vpn-server";
+
+/// What a credential is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `/proxy` and the legacy proxy endpoint
+    Proxy,
+    /// The PQC handshake and PQC proxy endpoints
+    Pqc,
+    /// Operational endpoints; implies every other scope
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "proxy" => Some(Scope::Proxy),
+            "pqc" => Some(Scope::Pqc),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A static key and the scopes it grants
+#[derive(Clone)]
+pub struct ApiKey {
+    pub name: String,
+    /// SHA3-256 of the key, so plaintext keys don't linger in memory longer than parsing
+    digest: [u8; 32],
+    pub scopes: Vec<Scope>,
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey").field("name", &self.name).field("scopes", &self.scopes).finish()
+    }
+}
+
+impl ApiKey {
+    /// A key from its plaintext, or from `sha3:<hex digest>` to keep the plaintext out of the configuration
+    pub fn new(name: &str, key: &str, scopes: Vec<Scope>) -> Result<Self, String> {
+        let digest = match key.strip_prefix("sha3:") {
+            Some(hex) => parse_digest(hex).ok_or_else(|| format!("invalid sha3 digest for key '{}'", name))?,
+            None if key.is_empty() => return Err(format!("empty key for '{}'", name)),
+            None => Sha3_256::digest(key.as_bytes()).into(),
+        };
+        Ok(ApiKey { name: name.trim().to_string(), digest, scopes })
+    }
+
+    /// Parse `name:key:scope+scope` entries separated by commas
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (name, rest) = entry.split_once(':').ok_or_else(|| format!("expected name:key:scopes, got '{}'", entry))?;
+                let (key, scopes) = rest.rsplit_once(':').ok_or_else(|| format!("missing scopes for key '{}'", name))?;
+                let scopes = scopes
+                    .split('+')
+                    .map(|s| Scope::parse(s).ok_or_else(|| format!("unknown scope '{}' for key '{}'", s, name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                ApiKey::new(name, key, scopes)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// Reject unauthenticated requests to protected endpoints
+    pub required: bool,
+    pub api_keys: Vec<ApiKey>,
+    /// Lifetime of issued tokens when the request doesn't ask for one
+    pub token_ttl_secs: u64,
+    /// Upper bound on the lifetime a token request may ask for
+    pub max_token_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            api_keys: Vec::new(),
+            token_ttl_secs: 900,
+            max_token_ttl_secs: 3600,
+        }
+    }
+}
+
+/// Which request header carried the credential
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialSource {
+    ProxyAuthorization,
+    Authorization,
+}
+
+/// The caller behind an authenticated request
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub subject: String,
+    pub scopes: Vec<Scope>,
+    /// `None` when authentication is disabled and nothing was presented
+    pub source: Option<CredentialSource>,
+    /// Whether the credential was a static key, which alone may mint tokens
+    pub is_api_key: bool,
+}
+
+impl Identity {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| *s == scope || *s == Scope::Admin)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthError {
+    /// No credential was presented
+    Missing,
+    /// The credential is unknown, malformed, forged or expired
+    Invalid(String),
+    /// The credential is valid but lacks the scope
+    Forbidden(Scope),
+}
+
+/// Claims carried inside a signed token
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenClaims {
+    typ: String,
+    aud: String,
+    sub: String,
+    scopes: Vec<Scope>,
+    iat: u64,
+    exp: u64,
+}
+
+pub struct Authenticator {
+    config: AuthConfig,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        Self { config }
+    }
+
+    /// Identify the caller, falling back to an anonymous identity when authentication isn't required
+    pub fn authenticate(&self, req: &HttpRequest, pqc: &PqcCrypto) -> Result<Identity, AuthError> {
+        match credential(req) {
+            Some((source, secret)) => match self.verify(&secret, source, pqc) {
+                Ok(identity) => Ok(identity),
+                // Without mandatory auth, an unrecognised Authorization header is meant for the upstream site
+                Err(_) if !self.config.required && source == CredentialSource::Authorization => Ok(anonymous()),
+                Err(e) => Err(e),
+            },
+            None if self.config.required => Err(AuthError::Missing),
+            None => Ok(anonymous()),
+        }
+    }
+
+    /// Authenticate the request and check it may use `scope`
+    pub fn authorize(&self, req: &HttpRequest, scope: Scope, pqc: &PqcCrypto) -> Result<Identity, AuthError> {
+        let identity = self.authenticate(req, pqc)?;
+        if identity.has_scope(scope) {
+            Ok(identity)
+        } else if identity.source.is_none() {
+            Err(AuthError::Missing)
+        } else {
+            Err(AuthError::Forbidden(scope))
+        }
+    }
+
+    fn verify(&self, secret: &str, source: CredentialSource, pqc: &PqcCrypto) -> Result<Identity, AuthError> {
+        if let Some(token) = secret.strip_prefix(TOKEN_PREFIX) {
+            let claims = verify_token(token, pqc)?;
+            return Ok(Identity { subject: claims.sub, scopes: claims.scopes, source: Some(source), is_api_key: false });
+        }
+
+        let digest: [u8; 32] = Sha3_256::digest(secret.as_bytes()).into();
+        self.config
+            .api_keys
+            .iter()
+            .find(|key| constant_time_eq(&key.digest, &digest))
+            .map(|key| Identity {
+                subject: key.name.clone(),
+                scopes: key.scopes.clone(),
+                source: Some(source),
+                is_api_key: true,
+            })
+            .ok_or_else(|| AuthError::Invalid("unknown API key".to_string()))
+    }
+
+    /// Mint a token for `identity`, limited to `scopes` (which it must already hold) and
+    /// `ttl_secs` (capped by the configuration). Returns the token and its expiry.
+    pub fn issue_token(
+        &self,
+        identity: &Identity,
+        scopes: Option<Vec<Scope>>,
+        ttl_secs: Option<u64>,
+        pqc: &PqcCrypto,
+    ) -> Result<(String, u64), AuthError> {
+        let scopes = scopes.unwrap_or_else(|| identity.scopes.clone());
+        if let Some(missing) = scopes.iter().find(|s| !identity.has_scope(**s)) {
+            return Err(AuthError::Forbidden(*missing));
+        }
+
+        let now = unix_now();
+        let ttl = ttl_secs.unwrap_or(self.config.token_ttl_secs).min(self.config.max_token_ttl_secs);
+        let claims = TokenClaims {
+            typ: TOKEN_TYPE.to_string(),
+            aud: REALM.to_string(),
+            sub: identity.subject.clone(),
+            scopes,
+            iat: now,
+            exp: now + ttl,
+        };
+        let mut payload = TOKEN_CONTEXT.to_vec();
+        serde_json::to_writer(&mut payload, &claims).map_err(|e| AuthError::Invalid(e.to_string()))?;
+        let signed = pqc.dilithium_sign(&payload).map_err(|e| AuthError::Invalid(e.to_string()))?;
+        Ok((format!("{}{}", TOKEN_PREFIX, signed), claims.exp))
+    }
+}
+
+/// Unauthenticated callers, when authentication isn't required, may use the proxy and PQC endpoints
+fn anonymous() -> Identity {
+    Identity {
+        subject: "anonymous".to_string(),
+        scopes: vec![Scope::Proxy, Scope::Pqc],
+        source: None,
+        is_api_key: false,
+    }
+}
+
+/// Check a token's Dilithium signature against the server's key, that it was signed as a
+/// token for this server, and its expiry
+fn verify_token(token: &str, pqc: &PqcCrypto) -> Result<TokenClaims, AuthError> {
+    let payload = pqc
+        .dilithium_verify(token, &pqc.key_pair.dilithium_public_key)
+        .map_err(|_| AuthError::Invalid("token signature is invalid".to_string()))?;
+    let claims = payload
+        .strip_prefix(TOKEN_CONTEXT)
+        .ok_or_else(|| AuthError::Invalid("signature is not for a token".to_string()))?;
+    let claims: TokenClaims =
+        serde_json::from_slice(claims).map_err(|_| AuthError::Invalid("token is malformed".to_string()))?;
+    if claims.typ != TOKEN_TYPE || claims.aud != REALM {
+        return Err(AuthError::Invalid("token is not an access token for this server".to_string()));
+    }
+    if claims.exp <= unix_now() {
+        return Err(AuthError::Invalid("token has expired".to_string()));
+    }
+    Ok(claims)
+}
+
+/// The secret from `Proxy-Authorization` (preferred) or `Authorization`, accepting
+/// `Bearer <secret>` and `Basic` with the secret as the password
+fn credential(req: &HttpRequest) -> Option<(CredentialSource, String)> {
+    [
+        ("proxy-authorization", CredentialSource::ProxyAuthorization),
+        ("authorization", CredentialSource::Authorization),
+    ]
+    .into_iter()
+    .find_map(|(header, source)| {
+        let value = req.headers().get(header)?.to_str().ok()?.trim();
+        let (scheme, rest) = value.split_once(' ')?;
+        let secret = match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => rest.trim().to_string(),
+            "basic" => {
+                let decoded = general_purpose::STANDARD.decode(rest.trim()).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                decoded.split_once(':')?.1.to_string()
+            }
+            _ => return None,
+        };
+        (!secret.is_empty()).then_some((source, secret))
+    })
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn authenticator() -> Authenticator {
+        let key = ApiKey::new("ops", "secret", vec![Scope::Proxy]).unwrap();
+        Authenticator::new(AuthConfig { required: true, api_keys: vec![key], ..AuthConfig::default() })
+    }
+
+    fn identity(scopes: Vec<Scope>) -> Identity {
+        Identity { subject: "ops".to_string(), scopes, source: None, is_api_key: true }
+    }
+
+    fn sign(pqc: &PqcCrypto, context: &[u8], claims: &TokenClaims) -> String {
+        let mut payload = context.to_vec();
+        serde_json::to_writer(&mut payload, claims).unwrap();
+        format!("{}{}", TOKEN_PREFIX, pqc.dilithium_sign(&payload).unwrap())
+    }
+
+    fn claims(typ: &str, exp: u64) -> TokenClaims {
+        TokenClaims { typ: typ.to_string(), aud: REALM.to_string(), sub: "ops".to_string(), scopes: vec![Scope::Proxy], iat: 0, exp }
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default().insert_header(("Authorization", format!("Bearer {}", token))).to_http_request()
+    }
+
+    #[test]
+    fn issued_token_round_trips() {
+        let pqc = PqcCrypto::new();
+        let auth = authenticator();
+        let (token, exp) = auth.issue_token(&identity(vec![Scope::Proxy]), None, Some(60), &pqc).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert!(exp > unix_now());
+
+        let caller = auth.authorize(&bearer(&token), Scope::Proxy, &pqc).unwrap();
+        assert_eq!(caller.subject, "ops");
+        assert_eq!(caller.source, Some(CredentialSource::Authorization));
+        assert!(!caller.is_api_key);
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let pqc = PqcCrypto::new();
+        let (token, _) = authenticator().issue_token(&identity(vec![Scope::Proxy]), None, None, &pqc).unwrap();
+        let mut bytes = general_purpose::STANDARD.decode(&token[TOKEN_PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", TOKEN_PREFIX, general_purpose::STANDARD.encode(bytes));
+        assert!(matches!(verify_token(&tampered[TOKEN_PREFIX.len()..], &pqc), Err(AuthError::Invalid(_))));
+    }
+
+    #[test]
+    fn token_from_another_key_is_rejected() {
+        let (issuer, verifier) = (PqcCrypto::new(), PqcCrypto::new());
+        let (token, _) = authenticator().issue_token(&identity(vec![Scope::Proxy]), None, None, &issuer).unwrap();
+        assert!(verify_token(&token[TOKEN_PREFIX.len()..], &verifier).is_err());
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let pqc = PqcCrypto::new();
+        let token = sign(&pqc, TOKEN_CONTEXT, &claims(TOKEN_TYPE, unix_now() - 1));
+        match verify_token(&token[TOKEN_PREFIX.len()..], &pqc) {
+            Err(AuthError::Invalid(reason)) => assert!(reason.contains("expired")),
+            other => panic!("expected expiry, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn signature_outside_token_context_is_rejected() {
+        let pqc = PqcCrypto::new();
+        let future = unix_now() + 60;
+        for token in [
+            sign(&pqc, b"", &claims(TOKEN_TYPE, future)),
+            sign(&pqc, b"vpn-server audit checkpoint\0", &claims(TOKEN_TYPE, future)),
+            sign(&pqc, TOKEN_CONTEXT, &claims("refresh", future)),
+        ] {
+            assert!(verify_token(&token[TOKEN_PREFIX.len()..], &pqc).is_err());
+        }
+    }
+
+    #[test]
+    fn scopes_are_enforced() {
+        let pqc = PqcCrypto::new();
+        let auth = authenticator();
+        assert!(matches!(
+            auth.issue_token(&identity(vec![Scope::Proxy]), Some(vec![Scope::Admin]), None, &pqc),
+            Err(AuthError::Forbidden(Scope::Admin))
+        ));
+
+        let (token, _) = auth.issue_token(&identity(vec![Scope::Proxy]), None, None, &pqc).unwrap();
+        assert!(matches!(auth.authorize(&bearer(&token), Scope::Pqc, &pqc), Err(AuthError::Forbidden(Scope::Pqc))));
+        assert!(identity(vec![Scope::Admin]).has_scope(Scope::Pqc));
+    }
+
+    #[test]
+    fn api_keys_and_missing_credentials() {
+        let pqc = PqcCrypto::new();
+        let auth = authenticator();
+        assert!(auth.authorize(&bearer("secret"), Scope::Proxy, &pqc).unwrap().is_api_key);
+        assert!(matches!(auth.authorize(&bearer("wrong"), Scope::Proxy, &pqc), Err(AuthError::Invalid(_))));
+        let bare = TestRequest::default().to_http_request();
+        assert!(matches!(auth.authorize(&bare, Scope::Proxy, &pqc), Err(AuthError::Missing)));
+    }
+}
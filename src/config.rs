@@ -0,0 +1,1070 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::env;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::access_log::{AccessLogFormat, AccessLogSettings, Rotation};
+use crate::audit::AuditSettings;
+use crate::auth::{ApiKey, AuthConfig, Scope};
+use crate::forwarding::{ForwardingConfig, ForwardingMode};
+use crate::headers::HeaderPolicy;
+use crate::limits::{RateLimits, ScopeLimit};
+use crate::policy::DomainPolicy;
+use crate::redirect::UrlMode;
+use crate::ssrf::SsrfPolicy;
+use crate::telemetry::{LogFormat, LoggingSettings};
+use crate::tls::{CertStore, CertificatePaths, TlsSettings};
+
+/// How `/proxy` deals with 3xx responses from the upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectMode {
+    /// Follow redirects server-side and return the final response
+    Follow,
+    /// Hand the redirect back to the client with its location rewritten onto the proxy
+    Passthrough,
+}
+
+impl RedirectMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "follow" => Some(RedirectMode::Follow),
+            "passthrough" | "pass" | "client" => Some(RedirectMode::Passthrough),
+            _ => None,
+        }
+    }
+}
+
+/// Upstream request timeouts and connection pool settings
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// Whole-request timeout for `/proxy`
+    pub proxy_secs: u64,
+    /// Whole-request timeout for the legacy and PQC proxy endpoints
+    pub legacy_proxy_secs: u64,
+    /// Timeout for the warm-up requests made before Google searches
+    pub prefetch_secs: u64,
+    pub tcp_keepalive_secs: u64,
+    pub pool_idle_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            proxy_secs: 45,
+            legacy_proxy_secs: 30,
+            prefetch_secs: 30,
+            tcp_keepalive_secs: 60,
+            pool_idle_secs: 90,
+        }
+    }
+}
+
+/// Lifetime and history bounds of the per-client browsing sessions
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    /// Sessions idle for longer than this are dropped
+    pub ttl_secs: u64,
+    /// Visited URLs kept per session
+    pub max_visited_urls: usize,
+    /// Interaction history entries kept per session
+    pub max_history: usize,
+    /// Cookies kept per session; those expiring soonest are dropped first
+    pub max_cookies: usize,
+    /// Sessions held at once; past this the least recently used are evicted
+    pub max_sessions: usize,
+    /// PQC handshake sessions held at once; past this the least recently used are evicted
+    pub max_pqc_sessions: usize,
+    /// How often idle sessions are looked for and dropped, in seconds
+    pub reap_secs: u64,
+    /// Key sessions on the client address when no session token is presented, instead of
+    /// issuing a new token
+    pub ip_fallback: bool,
+    /// Where sessions and their cookie jars are kept across restarts
+    pub store_file: Option<PathBuf>,
+    /// How often sessions are saved to `store_file`, in seconds
+    pub save_secs: u64,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 3600,
+            max_visited_urls: 50,
+            max_history: 100,
+            max_cookies: 300,
+            max_sessions: 10_000,
+            max_pqc_sessions: 10_000,
+            reap_secs: 60,
+            ip_fallback: false,
+            store_file: None,
+            save_secs: 60,
+        }
+    }
+}
+
+/// What happens when the server is asked to stop
+#[derive(Debug, Clone)]
+pub struct ShutdownSettings {
+    /// How long in-flight requests and streamed downloads may take to finish, in seconds
+    pub timeout_secs: u64,
+    /// Where PQC sessions are saved on shutdown and restored from at startup
+    pub pqc_sessions_file: Option<PathBuf>,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self { timeout_secs: 30, pqc_sessions_file: None }
+    }
+}
+
+/// Runtime settings for the server and the proxy endpoints
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Addresses the HTTP server listens on
+    pub listeners: Vec<String>,
+    /// HTTPS listeners, their certificates and the optional HTTP-to-HTTPS redirect listeners
+    pub tls: TlsSettings,
+    /// Worker threads; 0 uses one per CPU core
+    pub workers: usize,
+    pub shutdown: ShutdownSettings,
+    pub logging: LoggingSettings,
+    /// Compliance log of proxied and PQC requests, separate from the diagnostic output
+    pub access_log: AccessLogSettings,
+    /// Hash-chained log of security events with signed checkpoints
+    pub audit: AuditSettings,
+    pub timeouts: Timeouts,
+    pub session: SessionSettings,
+    /// Where the server's PQC key pair is kept; without it a fresh pair is generated per run
+    pub pqc_key_file: Option<PathBuf>,
+    /// Warm-up requests, delays and retries for Google destinations
+    pub google_anti_bot: bool,
+    pub redirect_mode: RedirectMode,
+    pub max_redirects: usize,
+    /// How links in proxied HTML are rewritten, or `None` to pass HTML through untouched
+    pub rewrite_html: Option<UrlMode>,
+    /// How `url()`/`@import` references in proxied stylesheets are rewritten, or `None` to skip
+    pub rewrite_css: Option<UrlMode>,
+    /// Namespace upstream cookies per site on the proxy instead of forwarding them verbatim
+    pub translate_cookies: bool,
+    /// Re-compress rewritten text bodies to the client's `Accept-Encoding` instead of sending them identity-encoded
+    pub recompress: bool,
+    /// Which client request headers are passed on to the upstream
+    pub header_policy: HeaderPolicy,
+    /// What the upstream is told about the client's address
+    pub forwarding: ForwardingConfig,
+    /// Destinations the proxy refuses to fetch
+    pub ssrf: SsrfPolicy,
+    /// Allow/deny rules for destinations, reloaded when the file changes
+    pub policy_file: Option<PathBuf>,
+    /// How often the policy file is checked for changes, in seconds
+    pub policy_reload_secs: u64,
+    /// Request rate and concurrency limits per client, session and destination host
+    pub limits: RateLimits,
+    /// API keys and token settings for the proxy and PQC endpoints
+    pub auth: AuthConfig,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listeners: vec!["127.0.0.1:8080".to_string()],
+            tls: TlsSettings::default(),
+            workers: 0,
+            shutdown: ShutdownSettings::default(),
+            logging: LoggingSettings::default(),
+            access_log: AccessLogSettings::default(),
+            audit: AuditSettings::default(),
+            timeouts: Timeouts::default(),
+            session: SessionSettings::default(),
+            pqc_key_file: None,
+            google_anti_bot: true,
+            redirect_mode: RedirectMode::Passthrough,
+            max_redirects: 10,
+            rewrite_html: Some(UrlMode::Proxied),
+            rewrite_css: Some(UrlMode::Proxied),
+            translate_cookies: true,
+            recompress: true,
+            header_policy: HeaderPolicy {
+                allow: Vec::new(),
+                // The browser's Origin is the proxy's
+                deny: HeaderPolicy::parse_list("origin"),
+            },
+            forwarding: ForwardingConfig::default(),
+            ssrf: SsrfPolicy::default(),
+            policy_file: None,
+            policy_reload_secs: 5,
+            limits: RateLimits::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+/// Command-line flags; these override the configuration file and environment
+#[derive(Debug, Parser)]
+#[command(version, about = "Post-quantum VPN proxy server")]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "PATH", env = "PROXY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on; repeat for several listeners
+    #[arg(long = "bind", value_name = "ADDR")]
+    pub bind: Vec<String>,
+    /// Address to serve HTTPS on; repeat for several listeners
+    #[arg(long = "tls-bind", value_name = "ADDR")]
+    pub tls_bind: Vec<String>,
+    /// PEM certificate chain for the HTTPS listeners
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Worker threads (0 = one per CPU core)
+    #[arg(long, value_name = "N")]
+    pub workers: Option<usize>,
+    /// Seconds in-flight requests get to finish after SIGTERM or Ctrl-C
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_timeout: Option<u64>,
+    /// `pretty` or `json`
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<String>,
+    /// Log filter, e.g. `info` or `info,vpn_server=debug`
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Append an access log of proxied requests to this file
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+    /// Record security events in this hash-chained audit log
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+    /// Check an audit log's hash chain and checkpoint signatures, then exit
+    #[arg(long, value_name = "PATH")]
+    pub verify_audit_log: Option<PathBuf>,
+    /// With --verify-audit-log, fail unless the log contains the entry with this hash, taken
+    /// from an `audit checkpoint` line in the server log
+    #[arg(long, value_name = "HASH", requires = "verify_audit_log")]
+    pub expect_head: Option<String>,
+    /// `follow` or `passthrough`
+    #[arg(long, value_name = "MODE")]
+    pub redirect_mode: Option<String>,
+    /// `anonymous`, `transparent` or `trusted`
+    #[arg(long, value_name = "MODE")]
+    pub forwarding_mode: Option<String>,
+    /// Whole-request timeout for `/proxy`, in seconds
+    #[arg(long, value_name = "SECS")]
+    pub proxy_timeout: Option<u64>,
+    /// Idle time after which a session is dropped, in seconds
+    #[arg(long, value_name = "SECS")]
+    pub session_ttl: Option<u64>,
+    /// Domain policy rule file
+    #[arg(long, value_name = "PATH")]
+    pub policy_file: Option<PathBuf>,
+    /// File holding the server's PQC key pair (created if missing)
+    #[arg(long, value_name = "PATH")]
+    pub pqc_key_file: Option<PathBuf>,
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+impl ProxyConfig {
+    /// Build the configuration from defaults, then the TOML file, then `PROXY_*` environment
+    /// variables, then command-line flags. Every problem found is reported, not just the first.
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut errors = Vec::new();
+
+        if let Some(path) = &cli.config {
+            match FileConfig::load(path) {
+                Ok(file) => file.apply(&mut config, &mut errors),
+                Err(e) => errors.push(e),
+            }
+        }
+        config.apply_env(&mut errors);
+        config.apply_cli(cli, &mut errors);
+        config.validate(&mut errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(format!("invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        if let Some(listeners) = env_value("PROXY_BIND", errors, |v| Ok(split_list(v))) {
+            self.listeners = listeners;
+        }
+        if let Some(listeners) = env_value("PROXY_TLS_BIND", errors, |v| Ok(split_list(v))) {
+            self.tls.listeners = listeners;
+        }
+        if let Some(listeners) = env_value("PROXY_TLS_REDIRECT_BIND", errors, |v| Ok(split_list(v))) {
+            self.tls.redirect_listeners = listeners;
+        }
+        if let Some(host) = env_value("PROXY_TLS_PUBLIC_HOST", errors, |v| Ok(v.trim().to_string())) {
+            self.tls.public_host = (!host.is_empty()).then_some(host);
+        }
+        match (env::var("PROXY_TLS_CERT"), env::var("PROXY_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => self.tls.certificates = vec![single_certificate(cert.trim().into(), key.trim().into())],
+            (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+                errors.push("PROXY_TLS_CERT and PROXY_TLS_KEY must be set together".to_string())
+            }
+            _ => {}
+        }
+        if let Some(workers) = env_value("PROXY_WORKERS", errors, number) {
+            self.workers = workers;
+        }
+        if let Some(secs) = env_value("PROXY_SHUTDOWN_TIMEOUT_SECS", errors, number) {
+            self.shutdown.timeout_secs = secs;
+        }
+        if let Some(format) = env_value("PROXY_LOG_FORMAT", errors, log_format) {
+            self.logging.format = format;
+        }
+        if let Some(level) = env_value("PROXY_LOG_LEVEL", errors, |v| Ok(v.trim().to_string())) {
+            self.logging.level = level;
+        }
+        if let Some(path) = env_value("PROXY_ACCESS_LOG", errors, |v| Ok(v.trim().to_string())) {
+            self.access_log.path = (!path.is_empty()).then(|| PathBuf::from(path));
+        }
+        if let Some(format) = env_value("PROXY_ACCESS_LOG_FORMAT", errors, access_log_format) {
+            self.access_log.format = format;
+        }
+        if let Some(redact) = env_value("PROXY_ACCESS_LOG_REDACT_QUERY", errors, boolean) {
+            self.access_log.redact_query = redact;
+        }
+        if let Some(path) = env_value("PROXY_AUDIT_LOG", errors, |v| Ok(v.trim().to_string())) {
+            self.audit.path = (!path.is_empty()).then(|| PathBuf::from(path));
+        }
+        if let Some(secs) = env_value("PROXY_TIMEOUT_SECS", errors, number) {
+            self.timeouts.proxy_secs = secs;
+        }
+        if let Some(secs) = env_value("PROXY_SESSION_TTL_SECS", errors, number) {
+            self.session.ttl_secs = secs;
+        }
+        if let Some(fallback) = env_value("PROXY_SESSION_IP_FALLBACK", errors, boolean) {
+            self.session.ip_fallback = fallback;
+        }
+        if let Some(max) = env_value("PROXY_MAX_SESSIONS", errors, number) {
+            self.session.max_sessions = max;
+        }
+        if let Some(max) = env_value("PROXY_MAX_PQC_SESSIONS", errors, number) {
+            self.session.max_pqc_sessions = max;
+        }
+        if let Some(path) = env_value("PROXY_SESSION_STORE_FILE", errors, |v| Ok(v.trim().to_string())) {
+            self.session.store_file = (!path.is_empty()).then(|| PathBuf::from(path));
+        }
+        if let Some(path) = env_value("PROXY_PQC_KEY_FILE", errors, |v| Ok(PathBuf::from(v.trim()))) {
+            self.pqc_key_file = Some(path);
+        }
+        if let Some(enabled) = env_value("PROXY_GOOGLE_ANTI_BOT", errors, boolean) {
+            self.google_anti_bot = enabled;
+        }
+        if let Some(mode) = env_value("PROXY_REDIRECT_MODE", errors, redirect_mode) {
+            self.redirect_mode = mode;
+        }
+        if let Some(max) = env_value("PROXY_MAX_REDIRECTS", errors, number) {
+            self.max_redirects = max;
+        }
+        if let Some(mode) = env_value("PROXY_REWRITE_HTML", errors, rewrite_mode) {
+            self.rewrite_html = mode;
+        }
+        if let Some(mode) = env_value("PROXY_REWRITE_CSS", errors, rewrite_mode) {
+            self.rewrite_css = mode;
+        }
+        if let Some(enabled) = env_value("PROXY_TRANSLATE_COOKIES", errors, boolean) {
+            self.translate_cookies = enabled;
+        }
+        if let Some(enabled) = env_value("PROXY_RECOMPRESS", errors, boolean) {
+            self.recompress = enabled;
+        }
+        if let Some(allow) = env_value("PROXY_FORWARD_HEADERS_ALLOW", errors, |v| Ok(HeaderPolicy::parse_list(v))) {
+            self.header_policy.allow = allow;
+        }
+        if let Some(deny) = env_value("PROXY_FORWARD_HEADERS_DENY", errors, |v| Ok(HeaderPolicy::parse_list(v))) {
+            self.header_policy.deny = deny;
+        }
+        if let Some(mode) = env_value("PROXY_FORWARDING_MODE", errors, forwarding_mode) {
+            self.forwarding.mode = mode;
+        }
+        if let Some(proxies) = env_value("PROXY_TRUSTED_PROXIES", errors, ForwardingConfig::parse_cidrs) {
+            self.forwarding.trusted_proxies = proxies;
+        }
+        if let Some(enabled) = env_value("PROXY_SSRF_PROTECTION", errors, boolean) {
+            self.ssrf.enabled = enabled;
+        }
+        if let Some(networks) = env_value("PROXY_BLOCKED_NETWORKS", errors, ForwardingConfig::parse_cidrs) {
+            self.ssrf.blocked = networks;
+        }
+        if let Some(networks) = env_value("PROXY_ALLOWED_NETWORKS", errors, ForwardingConfig::parse_cidrs) {
+            self.ssrf.allowed = networks;
+        }
+        if let Some(path) = env_value("PROXY_POLICY_FILE", errors, |v| Ok(v.trim().to_string()))
+            && !path.is_empty()
+        {
+            self.policy_file = Some(PathBuf::from(path));
+        }
+        if let Some(secs) = env_value("PROXY_POLICY_RELOAD_SECS", errors, number) {
+            self.policy_reload_secs = secs;
+        }
+        for (var, limit) in [
+            ("PROXY_LIMIT_CLIENT", &mut self.limits.client),
+            ("PROXY_LIMIT_SESSION", &mut self.limits.session),
+            ("PROXY_LIMIT_HOST", &mut self.limits.host),
+        ] {
+            if let Some(parsed) = env_value(var, errors, ScopeLimit::parse) {
+                *limit = parsed;
+            }
+        }
+        if let Some(keys) = env_value("PROXY_API_KEYS", errors, ApiKey::parse_list) {
+            // Configuring keys turns authentication on unless explicitly disabled below
+            self.auth.required = !keys.is_empty();
+            self.auth.api_keys = keys;
+        }
+        if let Some(required) = env_value("PROXY_AUTH_REQUIRED", errors, boolean) {
+            self.auth.required = required;
+        }
+        if let Some(secs) = env_value("PROXY_TOKEN_TTL_SECS", errors, number) {
+            self.auth.token_ttl_secs = secs;
+        }
+        if let Some(secs) = env_value("PROXY_MAX_TOKEN_TTL_SECS", errors, number) {
+            self.auth.max_token_ttl_secs = secs;
+        }
+    }
+
+    fn apply_cli(&mut self, cli: &Cli, errors: &mut Vec<String>) {
+        if !cli.bind.is_empty() {
+            self.listeners = cli.bind.clone();
+        }
+        if !cli.tls_bind.is_empty() {
+            self.tls.listeners = cli.tls_bind.clone();
+        }
+        if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+            self.tls.certificates = vec![single_certificate(cert.clone(), key.clone())];
+        }
+        if let Some(workers) = cli.workers {
+            self.workers = workers;
+        }
+        if let Some(secs) = cli.shutdown_timeout {
+            self.shutdown.timeout_secs = secs;
+        }
+        if let Some(value) = &cli.log_format {
+            match log_format(value) {
+                Ok(format) => self.logging.format = format,
+                Err(e) => errors.push(format!("--log-format: {}", e)),
+            }
+        }
+        if let Some(level) = &cli.log_level {
+            self.logging.level = level.clone();
+        }
+        if let Some(path) = &cli.access_log {
+            self.access_log.path = Some(path.clone());
+        }
+        if let Some(path) = &cli.audit_log {
+            self.audit.path = Some(path.clone());
+        }
+        if let Some(value) = &cli.redirect_mode {
+            match redirect_mode(value) {
+                Ok(mode) => self.redirect_mode = mode,
+                Err(e) => errors.push(format!("--redirect-mode: {}", e)),
+            }
+        }
+        if let Some(value) = &cli.forwarding_mode {
+            match forwarding_mode(value) {
+                Ok(mode) => self.forwarding.mode = mode,
+                Err(e) => errors.push(format!("--forwarding-mode: {}", e)),
+            }
+        }
+        if let Some(secs) = cli.proxy_timeout {
+            self.timeouts.proxy_secs = secs;
+        }
+        if let Some(secs) = cli.session_ttl {
+            self.session.ttl_secs = secs;
+        }
+        if let Some(path) = &cli.policy_file {
+            self.policy_file = Some(path.clone());
+        }
+        if let Some(path) = &cli.pqc_key_file {
+            self.pqc_key_file = Some(path.clone());
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.listeners.is_empty() && self.tls.listeners.is_empty() {
+            errors.push("at least one listen address is required".to_string());
+        }
+        for listener in self.listeners.iter().chain(&self.tls.listeners).chain(&self.tls.redirect_listeners) {
+            if listener.to_socket_addrs().map_or(true, |mut addrs| addrs.next().is_none()) {
+                errors.push(format!("listen address '{}' is not a valid host:port", listener));
+            }
+        }
+
+        if let Err(e) = self.logging.filter() {
+            errors.push(format!("logging.level: {}", e));
+        }
+
+        if let Some(path) = &self.access_log.path {
+            let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !parent.is_dir() {
+                errors.push(format!("access log directory {} does not exist", parent.display()));
+            } else if path.exists() && !path.is_file() {
+                errors.push(format!("access log {} is not a regular file", path.display()));
+            }
+        }
+        if self.access_log.keep == 0 {
+            errors.push("access_log.keep must be greater than zero".to_string());
+        }
+        if let Some(path) = &self.audit.path {
+            let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !parent.is_dir() {
+                errors.push(format!("audit log directory {} does not exist", parent.display()));
+            } else if path.exists() && !path.is_file() {
+                errors.push(format!("audit log {} is not a regular file", path.display()));
+            }
+            // Checkpoints signed with a per-run key couldn't be verified after a restart
+            if self.pqc_key_file.is_none() {
+                errors.push("audit.file needs pqc.key_file so checkpoint signatures stay verifiable".to_string());
+            }
+        }
+        if self.audit.checkpoint_every == 0 {
+            errors.push("audit.checkpoint_every must be greater than zero".to_string());
+        }
+
+        if self.tls.enabled() {
+            if let Err(e) = CertStore::open(&self.tls) {
+                errors.push(format!("tls: {}", e));
+            }
+        } else if !self.tls.redirect_listeners.is_empty() {
+            errors.push("tls.redirect_bind needs at least one HTTPS listener in tls.bind".to_string());
+        }
+        if let Some(host) = &self.tls.public_host
+            && crate::tls::parse_authority(host).is_none()
+        {
+            errors.push(format!("tls.public_host {:?} is not a host name with an optional port", host));
+        }
+
+        for (name, secs) in [
+            ("tls.reload_secs", self.tls.reload_secs),
+            ("timeouts.proxy_secs", self.timeouts.proxy_secs),
+            ("timeouts.legacy_proxy_secs", self.timeouts.legacy_proxy_secs),
+            ("timeouts.prefetch_secs", self.timeouts.prefetch_secs),
+            ("session.ttl_secs", self.session.ttl_secs),
+            ("session.save_secs", self.session.save_secs),
+            ("session.reap_secs", self.session.reap_secs),
+            ("audit.checkpoint_secs", self.audit.checkpoint_secs),
+            ("policy.reload_secs", self.policy_reload_secs),
+            ("auth.max_token_ttl_secs", self.auth.max_token_ttl_secs),
+        ] {
+            if secs == 0 {
+                errors.push(format!("{} must be greater than zero", name));
+            }
+        }
+        if self.session.max_visited_urls == 0 || self.session.max_history == 0 || self.session.max_cookies == 0 {
+            errors.push("session history limits must be greater than zero".to_string());
+        }
+        if self.session.max_sessions == 0 {
+            errors.push("session.max_sessions must be greater than zero".to_string());
+        }
+        if self.session.max_pqc_sessions == 0 {
+            errors.push("session.max_pqc_sessions must be greater than zero".to_string());
+        }
+
+        if let Some(path) = &self.policy_file
+            && let Err(e) = DomainPolicy::load(path)
+        {
+            errors.push(format!("policy file {}", e));
+        }
+        if let Some(path) = &self.pqc_key_file
+            && path.exists()
+            && !path.is_file()
+        {
+            errors.push(format!("PQC key file {} is not a regular file", path.display()));
+        }
+        if let Some(path) = &self.shutdown.pqc_sessions_file
+            && path.exists()
+            && !path.is_file()
+        {
+            errors.push(format!("PQC sessions file {} is not a regular file", path.display()));
+        }
+        if let Some(path) = &self.session.store_file
+            && path.exists()
+            && !path.is_file()
+        {
+            errors.push(format!("session store file {} is not a regular file", path.display()));
+        }
+
+        for (name, limit) in [("client", self.limits.client), ("session", self.limits.session), ("host", self.limits.host)] {
+            if limit.rate < 0.0 || limit.burst < 0.0 || !limit.rate.is_finite() || !limit.burst.is_finite() {
+                errors.push(format!("limits.{}: rate and burst must be non-negative numbers", name));
+            }
+        }
+
+        if self.auth.required && self.auth.api_keys.is_empty() {
+            errors.push("authentication is required but no API keys are configured".to_string());
+        }
+        if self.auth.token_ttl_secs > self.auth.max_token_ttl_secs {
+            errors.push("auth.token_ttl_secs exceeds auth.max_token_ttl_secs".to_string());
+        }
+    }
+}
+
+/// Layout of the TOML configuration file. Every setting is optional and falls back to the
+/// built-in default; unknown keys are rejected so typos don't go unnoticed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: ServerSection,
+    shutdown: ShutdownSection,
+    tls: TlsSection,
+    logging: LoggingSection,
+    access_log: AccessLogSection,
+    audit: AuditSection,
+    timeouts: TimeoutsSection,
+    session: SessionSection,
+    pqc: PqcSection,
+    features: FeaturesSection,
+    proxy: ProxySection,
+    ssrf: SsrfSection,
+    policy: PolicySection,
+    limits: LimitsSection,
+    auth: AuthSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    bind: Option<Vec<String>>,
+    workers: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownSection {
+    timeout_secs: Option<u64>,
+    pqc_sessions_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    format: Option<String>,
+    level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AccessLogSection {
+    file: Option<PathBuf>,
+    format: Option<String>,
+    redact_query: Option<bool>,
+    max_size_mb: Option<u64>,
+    rotate: Option<String>,
+    keep: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuditSection {
+    file: Option<PathBuf>,
+    checkpoint_every: Option<u64>,
+    checkpoint_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    bind: Option<Vec<String>>,
+    redirect_bind: Option<Vec<String>>,
+    public_host: Option<String>,
+    reload_secs: Option<u64>,
+    certificates: Option<Vec<CertificateEntry>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CertificateEntry {
+    cert: PathBuf,
+    key: PathBuf,
+    #[serde(default)]
+    names: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsSection {
+    proxy_secs: Option<u64>,
+    legacy_proxy_secs: Option<u64>,
+    prefetch_secs: Option<u64>,
+    tcp_keepalive_secs: Option<u64>,
+    pool_idle_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SessionSection {
+    ttl_secs: Option<u64>,
+    max_visited_urls: Option<usize>,
+    max_history: Option<usize>,
+    max_cookies: Option<usize>,
+    max_sessions: Option<usize>,
+    max_pqc_sessions: Option<usize>,
+    reap_secs: Option<u64>,
+    ip_fallback: Option<bool>,
+    store_file: Option<PathBuf>,
+    save_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PqcSection {
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FeaturesSection {
+    rewrite_html: Option<String>,
+    rewrite_css: Option<String>,
+    translate_cookies: Option<bool>,
+    recompress: Option<bool>,
+    google_anti_bot: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProxySection {
+    redirect_mode: Option<String>,
+    max_redirects: Option<usize>,
+    forward_headers_allow: Option<Vec<String>>,
+    forward_headers_deny: Option<Vec<String>>,
+    forwarding_mode: Option<String>,
+    trusted_proxies: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SsrfSection {
+    enabled: Option<bool>,
+    blocked_networks: Option<Vec<String>>,
+    allowed_networks: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicySection {
+    file: Option<PathBuf>,
+    reload_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    client: Option<LimitTable>,
+    session: Option<LimitTable>,
+    host: Option<LimitTable>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitTable {
+    rate: f64,
+    burst: Option<f64>,
+    concurrent: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuthSection {
+    required: Option<bool>,
+    token_ttl_secs: Option<u64>,
+    max_token_ttl_secs: Option<u64>,
+    api_keys: Vec<ApiKeyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeyEntry {
+    name: String,
+    /// Plaintext key, or `sha3:<hex digest>`
+    key: String,
+    scopes: Vec<Scope>,
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn apply(self, config: &mut ProxyConfig, errors: &mut Vec<String>) {
+        let mut set = |field: &str, result: Result<(), String>| {
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", field, e));
+            }
+        };
+
+        if let Some(bind) = self.server.bind {
+            config.listeners = bind;
+        }
+        if let Some(workers) = self.server.workers {
+            config.workers = workers;
+        }
+        if let Some(secs) = self.shutdown.timeout_secs {
+            config.shutdown.timeout_secs = secs;
+        }
+        if let Some(path) = self.shutdown.pqc_sessions_file {
+            config.shutdown.pqc_sessions_file = Some(path);
+        }
+        if let Some(value) = self.logging.format {
+            set("logging.format", log_format(&value).map(|format| config.logging.format = format));
+        }
+        if let Some(level) = self.logging.level {
+            config.logging.level = level;
+        }
+
+        let access_log = self.access_log;
+        if let Some(path) = access_log.file {
+            config.access_log.path = Some(path);
+        }
+        if let Some(value) = access_log.format {
+            set("access_log.format", access_log_format(&value).map(|format| config.access_log.format = format));
+        }
+        if let Some(redact) = access_log.redact_query {
+            config.access_log.redact_query = redact;
+        }
+        if let Some(mb) = access_log.max_size_mb {
+            config.access_log.max_bytes = mb.saturating_mul(1024 * 1024);
+        }
+        if let Some(value) = access_log.rotate {
+            set("access_log.rotate", rotation(&value).map(|rotation| config.access_log.rotation = rotation));
+        }
+        if let Some(keep) = access_log.keep {
+            config.access_log.keep = keep;
+        }
+
+        if let Some(path) = self.audit.file {
+            config.audit.path = Some(path);
+        }
+        if let Some(every) = self.audit.checkpoint_every {
+            config.audit.checkpoint_every = every;
+        }
+        if let Some(secs) = self.audit.checkpoint_secs {
+            config.audit.checkpoint_secs = secs;
+        }
+
+        if let Some(bind) = self.tls.bind {
+            config.tls.listeners = bind;
+        }
+        if let Some(bind) = self.tls.redirect_bind {
+            config.tls.redirect_listeners = bind;
+        }
+        if let Some(host) = self.tls.public_host {
+            config.tls.public_host = Some(host);
+        }
+        if let Some(secs) = self.tls.reload_secs {
+            config.tls.reload_secs = secs;
+        }
+        if let Some(certificates) = self.tls.certificates {
+            config.tls.certificates = certificates
+                .into_iter()
+                .map(|entry| CertificatePaths { cert: entry.cert, key: entry.key, names: entry.names })
+                .collect();
+        }
+
+        let t = self.timeouts;
+        let timeouts = &mut config.timeouts;
+        for (value, target) in [
+            (t.proxy_secs, &mut timeouts.proxy_secs),
+            (t.legacy_proxy_secs, &mut timeouts.legacy_proxy_secs),
+            (t.prefetch_secs, &mut timeouts.prefetch_secs),
+            (t.tcp_keepalive_secs, &mut timeouts.tcp_keepalive_secs),
+            (t.pool_idle_secs, &mut timeouts.pool_idle_secs),
+        ] {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+
+        if let Some(secs) = self.session.ttl_secs {
+            config.session.ttl_secs = secs;
+        }
+        if let Some(max) = self.session.max_visited_urls {
+            config.session.max_visited_urls = max;
+        }
+        if let Some(max) = self.session.max_history {
+            config.session.max_history = max;
+        }
+        if let Some(max) = self.session.max_cookies {
+            config.session.max_cookies = max;
+        }
+        if let Some(max) = self.session.max_sessions {
+            config.session.max_sessions = max;
+        }
+        if let Some(max) = self.session.max_pqc_sessions {
+            config.session.max_pqc_sessions = max;
+        }
+        if let Some(secs) = self.session.reap_secs {
+            config.session.reap_secs = secs;
+        }
+        if let Some(fallback) = self.session.ip_fallback {
+            config.session.ip_fallback = fallback;
+        }
+        if let Some(path) = self.session.store_file {
+            config.session.store_file = Some(path);
+        }
+        if let Some(secs) = self.session.save_secs {
+            config.session.save_secs = secs;
+        }
+        if let Some(path) = self.pqc.key_file {
+            config.pqc_key_file = Some(path);
+        }
+
+        let features = self.features;
+        if let Some(value) = features.rewrite_html {
+            set("features.rewrite_html", rewrite_mode(&value).map(|mode| config.rewrite_html = mode));
+        }
+        if let Some(value) = features.rewrite_css {
+            set("features.rewrite_css", rewrite_mode(&value).map(|mode| config.rewrite_css = mode));
+        }
+        if let Some(enabled) = features.translate_cookies {
+            config.translate_cookies = enabled;
+        }
+        if let Some(enabled) = features.recompress {
+            config.recompress = enabled;
+        }
+        if let Some(enabled) = features.google_anti_bot {
+            config.google_anti_bot = enabled;
+        }
+
+        let proxy = self.proxy;
+        if let Some(value) = proxy.redirect_mode {
+            set("proxy.redirect_mode", redirect_mode(&value).map(|mode| config.redirect_mode = mode));
+        }
+        if let Some(max) = proxy.max_redirects {
+            config.max_redirects = max;
+        }
+        if let Some(allow) = proxy.forward_headers_allow {
+            config.header_policy.allow = HeaderPolicy::parse_list(&allow.join(","));
+        }
+        if let Some(deny) = proxy.forward_headers_deny {
+            config.header_policy.deny = HeaderPolicy::parse_list(&deny.join(","));
+        }
+        if let Some(value) = proxy.forwarding_mode {
+            set("proxy.forwarding_mode", forwarding_mode(&value).map(|mode| config.forwarding.mode = mode));
+        }
+        if let Some(proxies) = proxy.trusted_proxies {
+            set(
+                "proxy.trusted_proxies",
+                ForwardingConfig::parse_cidrs(&proxies.join(",")).map(|nets| config.forwarding.trusted_proxies = nets),
+            );
+        }
+
+        if let Some(enabled) = self.ssrf.enabled {
+            config.ssrf.enabled = enabled;
+        }
+        if let Some(networks) = self.ssrf.blocked_networks {
+            set(
+                "ssrf.blocked_networks",
+                ForwardingConfig::parse_cidrs(&networks.join(",")).map(|nets| config.ssrf.blocked = nets),
+            );
+        }
+        if let Some(networks) = self.ssrf.allowed_networks {
+            set(
+                "ssrf.allowed_networks",
+                ForwardingConfig::parse_cidrs(&networks.join(",")).map(|nets| config.ssrf.allowed = nets),
+            );
+        }
+
+        if let Some(path) = self.policy.file {
+            config.policy_file = Some(path);
+        }
+        if let Some(secs) = self.policy.reload_secs {
+            config.policy_reload_secs = secs;
+        }
+
+        for (table, limit) in [
+            (self.limits.client, &mut config.limits.client),
+            (self.limits.session, &mut config.limits.session),
+            (self.limits.host, &mut config.limits.host),
+        ] {
+            if let Some(table) = table {
+                *limit = ScopeLimit::new(table.rate, table.burst, table.concurrent);
+            }
+        }
+
+        let auth = self.auth;
+        if !auth.api_keys.is_empty() {
+            config.auth.required = true;
+            config.auth.api_keys.clear();
+            for entry in auth.api_keys {
+                set("auth.api_keys", ApiKey::new(&entry.name, &entry.key, entry.scopes).map(|key| config.auth.api_keys.push(key)));
+            }
+        }
+        if let Some(required) = auth.required {
+            config.auth.required = required;
+        }
+        if let Some(secs) = auth.token_ttl_secs {
+            config.auth.token_ttl_secs = secs;
+        }
+        if let Some(secs) = auth.max_token_ttl_secs {
+            config.auth.max_token_ttl_secs = secs;
+        }
+    }
+}
+
+/// Read and parse an environment variable, recording a parse failure against its name
+fn env_value<T>(var: &str, errors: &mut Vec<String>, parse: impl FnOnce(&str) -> Result<T, String>) -> Option<T> {
+    let value = env::var(var).ok()?;
+    parse(&value).map_err(|e| errors.push(format!("{}: {}", var, e))).ok()
+}
+
+fn redirect_mode(value: &str) -> Result<RedirectMode, String> {
+    RedirectMode::parse(value).ok_or_else(|| format!("unknown redirect mode '{}'", value))
+}
+
+fn log_format(value: &str) -> Result<LogFormat, String> {
+    LogFormat::parse(value).ok_or_else(|| format!("unknown log format '{}'", value))
+}
+
+fn access_log_format(value: &str) -> Result<AccessLogFormat, String> {
+    AccessLogFormat::parse(value).ok_or_else(|| format!("unknown access log format '{}'", value))
+}
+
+fn rotation(value: &str) -> Result<Rotation, String> {
+    Rotation::parse(value).ok_or_else(|| format!("unknown rotation '{}'", value))
+}
+
+fn forwarding_mode(value: &str) -> Result<ForwardingMode, String> {
+    ForwardingMode::parse(value).ok_or_else(|| format!("unknown forwarding mode '{}'", value))
+}
+
+/// Parse an `off`/`absolute`/`proxy` rewrite setting
+fn rewrite_mode(value: &str) -> Result<Option<UrlMode>, String> {
+    if value.trim().eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    UrlMode::parse(value)
+        .map(Some)
+        .ok_or_else(|| format!("unknown rewrite mode '{}'", value))
+}
+
+fn boolean(value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected a boolean, got '{}'", value)),
+    }
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("expected a number, got '{}'", value))
+}
+
+/// A certificate given on the command line or in the environment, served for every name
+fn single_certificate(cert: PathBuf, key: PathBuf) -> CertificatePaths {
+    CertificatePaths { cert, key, names: Vec::new() }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect()
+}
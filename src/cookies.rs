@@ -0,0 +1,206 @@
+use sha3::{Digest, Sha3_256};
+use url::Url;
+
+/// Prefix of every cookie the proxy hands to the browser on behalf of an upstream site.
+///
+/// Browser-side names look like `__pxy_<scope>_<upstream name>`, where `<scope>` is a short
+/// hash of the cookie's upstream host (host-only cookies) or domain (domain cookies). That
+/// keeps cookies from different sites apart even though they all live on the proxy host.
+pub const COOKIE_PREFIX: &str = "__pxy_";
+
+/// Path the translated cookies are scoped to on the proxy
+const COOKIE_PATH: &str = "/proxy";
+
+const SCOPE_LEN: usize = 12;
+
+/// Translate an upstream `Set-Cookie` header into one the browser will accept for the proxy.
+///
+/// `Domain` and `Path` are replaced by the per-origin namespace, `Secure` is only kept when the
+/// client talks to us over HTTPS, and cookies whose domain doesn't match the upstream host are
+/// dropped (`None`), just like a browser would.
+pub fn rewrite_set_cookie(value: &str, upstream: &Url, secure_client: bool) -> Option<String> {
+    let host = upstream.host_str()?.to_ascii_lowercase();
+    let mut parts = value.split(';');
+
+    let (name, cookie_value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut domain = None;
+    let mut attributes = Vec::new();
+    for attribute in parts {
+        let attribute = attribute.trim();
+        let (key, val) = attribute.split_once('=').unwrap_or((attribute, ""));
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" => {
+                let val = val.trim().trim_start_matches('.').to_ascii_lowercase();
+                if !val.is_empty() {
+                    domain = Some(val);
+                }
+            }
+            "path" => {}
+            "secure" | "partitioned" => {
+                if secure_client {
+                    attributes.push(attribute.to_string());
+                }
+            }
+            "samesite" if !secure_client && val.trim().eq_ignore_ascii_case("none") => {
+                // SameSite=None requires Secure, which a plain-HTTP proxy can't offer
+                attributes.push("SameSite=Lax".to_string());
+            }
+            "" => {}
+            _ => attributes.push(attribute.to_string()),
+        }
+    }
+
+    let scope = match domain {
+        Some(domain) if domain_matches(&host, &domain) => scope_hash("domain", &domain),
+        Some(_) => return None,
+        None => scope_hash("host", &host),
+    };
+
+    let mut translated = format!("{}{}_{}={}; Path={}", COOKIE_PREFIX, scope, name, cookie_value.trim(), COOKIE_PATH);
+    for attribute in attributes {
+        translated.push_str("; ");
+        translated.push_str(&attribute);
+    }
+    Some(translated)
+}
+
+/// Pick the translated cookies out of a browser `Cookie` header that apply to `upstream`,
+/// returned under their original upstream names
+pub fn upstream_cookies(cookie_header: &str, upstream: &Url) -> Vec<(String, String)> {
+    let Some(host) = upstream.host_str().map(str::to_ascii_lowercase) else {
+        return Vec::new();
+    };
+
+    let mut scopes = vec![scope_hash("host", &host)];
+    let mut domain = host.as_str();
+    loop {
+        scopes.push(scope_hash("domain", domain));
+        match domain.split_once('.') {
+            Some((_, parent)) if parent.contains('.') => domain = parent,
+            _ => break,
+        }
+    }
+
+    cookie_header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            let rest = name.strip_prefix(COOKIE_PREFIX)?;
+            let (scope, upstream_name) = rest.split_at_checked(SCOPE_LEN)?;
+            let upstream_name = upstream_name.strip_prefix('_')?;
+
+            scopes.iter().any(|s| s == scope)
+                .then(|| (upstream_name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Combine cookies from the session jar with the ones the browser sent, the browser winning
+/// on name clashes since it holds the most recent value
+pub fn merge_cookie_header(jar_cookies: Option<&str>, browser_cookies: &[(String, String)]) -> String {
+    let mut merged: Vec<(String, String)> = jar_cookies
+        .unwrap_or("")
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .filter(|(name, _)| !browser_cookies.iter().any(|(b, _)| b == name))
+        .collect();
+    merged.extend(browser_cookies.iter().cloned());
+
+    merged
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// RFC 6265 domain matching: the host is the domain or one of its subdomains
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn scope_hash(kind: &str, name: &str) -> String {
+    let digest = Sha3_256::digest(format!("{}:{}", kind, name).as_bytes());
+    digest.iter().take(SCOPE_LEN / 2).map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    /// The browser-side name and value of a translated Set-Cookie
+    fn browser_pair(set_cookie: &str) -> String {
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn set_cookie_is_moved_under_the_proxy_path() {
+        let translated = rewrite_set_cookie(
+            "sid=abc; Path=/account; Domain=.example.com; HttpOnly; SameSite=Strict; Max-Age=60",
+            &url("https://www.example.com/"),
+            false,
+        )
+        .unwrap();
+        let expected_name = format!("{}{}_sid", COOKIE_PREFIX, scope_hash("domain", "example.com"));
+        assert_eq!(translated, format!("{}=abc; Path=/proxy; HttpOnly; SameSite=Strict; Max-Age=60", expected_name));
+    }
+
+    #[test]
+    fn secure_is_kept_only_for_https_clients() {
+        let upstream = url("https://example.com/");
+        let plain = rewrite_set_cookie("a=1; Secure; SameSite=None", &upstream, false).unwrap();
+        assert!(plain.ends_with("; Path=/proxy; SameSite=Lax"), "{}", plain);
+        let tls = rewrite_set_cookie("a=1; Secure; SameSite=None", &upstream, true).unwrap();
+        assert!(tls.ends_with("; Path=/proxy; Secure; SameSite=None"), "{}", tls);
+    }
+
+    #[test]
+    fn cookie_for_a_foreign_domain_is_dropped() {
+        let upstream = url("https://example.com/");
+        assert_eq!(rewrite_set_cookie("a=1; Domain=evil.com", &upstream, true), None);
+        assert_eq!(rewrite_set_cookie("a=1; Domain=notexample.com", &upstream, true), None);
+        assert_eq!(rewrite_set_cookie("a=1; Domain=www.example.com", &upstream, true), None);
+        assert_eq!(rewrite_set_cookie("=1", &upstream, true), None);
+    }
+
+    #[test]
+    fn host_only_cookie_round_trips_to_its_host_only() {
+        let set = rewrite_set_cookie("sid=abc", &url("https://a.example.com/"), false).unwrap();
+        let header = format!("other=1; {}", browser_pair(&set));
+
+        assert_eq!(upstream_cookies(&header, &url("https://a.example.com/x")), vec![("sid".to_string(), "abc".to_string())]);
+        assert!(upstream_cookies(&header, &url("https://b.example.com/")).is_empty());
+        assert!(upstream_cookies(&header, &url("https://sub.a.example.com/")).is_empty());
+    }
+
+    #[test]
+    fn domain_cookie_round_trips_to_subdomains() {
+        let set = rewrite_set_cookie("pref=dark; Domain=example.com", &url("https://www.example.com/"), false).unwrap();
+        let header = browser_pair(&set);
+
+        for host in ["https://example.com/", "https://www.example.com/", "https://a.b.example.com/"] {
+            assert_eq!(upstream_cookies(&header, &url(host)), vec![("pref".to_string(), "dark".to_string())], "{}", host);
+        }
+        assert!(upstream_cookies(&header, &url("https://example.org/")).is_empty());
+        assert!(upstream_cookies(&header, &url("https://badexample.com/")).is_empty());
+    }
+
+    #[test]
+    fn browser_cookies_win_over_the_jar() {
+        let browser = vec![("a".to_string(), "new".to_string())];
+        assert_eq!(merge_cookie_header(Some("a=old; b=2"), &browser), "b=2; a=new");
+        assert_eq!(merge_cookie_header(None, &browser), "a=new");
+    }
+}
@@ -0,0 +1,297 @@
+use url::Url;
+
+use crate::redirect::{self, UrlMode};
+
+/// Rewrite every URL reference in a stylesheet: `url()`, `@import` strings and the
+/// string candidates inside `image-set()`.
+///
+/// This walks CSS tokens rather than pattern-matching text, so comments, unrelated
+/// strings and escaped characters are left exactly as they were.
+pub fn rewrite_css(css: &str, base: &Url, mode: UrlMode) -> String {
+    CssRewriter { base, mode, out: String::with_capacity(css.len() + 64) }.run(css)
+}
+
+struct CssRewriter<'a> {
+    base: &'a Url,
+    mode: UrlMode,
+    out: String,
+}
+
+impl CssRewriter<'_> {
+    fn run(mut self, css: &str) -> String {
+        let bytes = css.as_bytes();
+        let mut functions: Vec<String> = Vec::new();
+        let mut in_import = false;
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let b = bytes[i];
+
+            // Comments
+            if css[i..].starts_with("/*") {
+                let end = css[i + 2..].find("*/").map_or(css.len(), |p| i + 2 + p + 2);
+                self.out.push_str(&css[i..end]);
+                i = end;
+                continue;
+            }
+
+            // Strings: URLs only inside @import or image-set()
+            if b == b'"' || b == b'\'' {
+                let end = string_end(bytes, i);
+                let in_image_set = functions.last().is_some_and(|f| is_image_set(f));
+                if in_import || in_image_set {
+                    self.push_string(&css[i..end]);
+                    in_import = false;
+                } else {
+                    self.out.push_str(&css[i..end]);
+                }
+                i = end;
+                continue;
+            }
+
+            // At-keywords
+            if b == b'@' {
+                let end = ident_end(bytes, i + 1);
+                in_import = css[i + 1..end].eq_ignore_ascii_case("import");
+                self.out.push_str(&css[i..end]);
+                i = end.max(i + 1);
+                continue;
+            }
+
+            // Identifiers and functions
+            if is_ident_start(bytes, i) {
+                let end = ident_end(bytes, i);
+                let name = &css[i..end];
+                self.out.push_str(name);
+                i = end;
+
+                if bytes.get(i) == Some(&b'(') {
+                    if name.eq_ignore_ascii_case("url") {
+                        i = self.push_url_function(css, i);
+                        in_import = false;
+                    } else {
+                        self.out.push('(');
+                        functions.push(name.to_ascii_lowercase());
+                        i += 1;
+                    }
+                }
+                continue;
+            }
+
+            match b {
+                b'(' => functions.push(String::new()),
+                b')' => {
+                    functions.pop();
+                }
+                b';' | b'{' | b'}' => in_import = false,
+                b'\\' if i + 1 < bytes.len() => {
+                    // Keep escaped characters paired with their backslash
+                    let next = css[i + 1..].chars().next().map_or(1, char::len_utf8);
+                    self.out.push_str(&css[i..i + 1 + next]);
+                    i += 1 + next;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let ch = css[i..].chars().next().unwrap();
+            self.out.push(ch);
+            i += ch.len_utf8();
+        }
+
+        self.out
+    }
+
+    /// Emit `url(...)` starting at the opening parenthesis, returning the index after it
+    fn push_url_function(&mut self, css: &str, open: usize) -> usize {
+        let bytes = css.as_bytes();
+        let mut i = open + 1;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        // url("...") is a regular function whose argument is a string
+        if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+            let end = string_end(bytes, i);
+            self.out.push_str(&css[open..i]);
+            self.push_string(&css[i..end]);
+
+            let close = css[end..].find(')').map_or(css.len(), |p| end + p + 1);
+            self.out.push_str(&css[end..close]);
+            return close;
+        }
+
+        // url(foo.png) is a single unquoted url token
+        let mut end = i;
+        while end < bytes.len() && bytes[end] != b')' {
+            end += if bytes[end] == b'\\' { 2 } else { 1 };
+        }
+        let end = end.min(bytes.len());
+        let raw = &css[i..end];
+        let close = if end < bytes.len() { end + 1 } else { end };
+
+        match redirect::resolve(self.base, &unescape(raw.trim_end()), self.mode) {
+            Some(resolved) if resolved.contains(|c: char| c.is_ascii_whitespace() || "\"'()\\".contains(c)) => {
+                self.out.push_str("(\"");
+                self.out.push_str(&escape_string(&resolved, '"'));
+                self.out.push_str("\")");
+            }
+            Some(resolved) => {
+                self.out.push('(');
+                self.out.push_str(&resolved);
+                self.out.push(')');
+            }
+            None => self.out.push_str(&css[open..close]),
+        }
+        close
+    }
+
+    /// Emit a quoted string token whose contents are a URL
+    fn push_string(&mut self, token: &str) {
+        let quote = token.chars().next().unwrap();
+        let inner = token[1..].strip_suffix(quote).unwrap_or(&token[1..]);
+
+        match redirect::resolve(self.base, &unescape(inner), self.mode) {
+            Some(resolved) => {
+                self.out.push(quote);
+                self.out.push_str(&escape_string(&resolved, quote));
+                self.out.push(quote);
+            }
+            None => self.out.push_str(token),
+        }
+    }
+}
+
+fn is_image_set(function: &str) -> bool {
+    function == "image-set" || function == "-webkit-image-set"
+}
+
+/// Index just past the closing quote of the string starting at `start`
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b if b == quote => return i + 1,
+            // An unescaped newline ends a bad string
+            b'\n' => return i,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn is_ident_start(bytes: &[u8], i: usize) -> bool {
+    match bytes[i] {
+        b'a'..=b'z' | b'A'..=b'Z' | b'_' | 0x80.. => true,
+        b'-' => bytes.get(i + 1).is_some_and(|&b| b.is_ascii_alphabetic() || b == b'-' || b == b'_'),
+        _ => false,
+    }
+}
+
+fn ident_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start;
+    while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || matches!(bytes[i], b'-' | b'_') || bytes[i] >= 0x80) {
+        i += 1;
+    }
+    i
+}
+
+/// Resolve CSS escapes (`\"`, `\29 `) inside a string or url token
+fn unescape(value: &str) -> String {
+    if !value.contains('\\') {
+        return value.to_string();
+    }
+
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        let mut hex = String::new();
+        while hex.len() < 6 && chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            hex.push(chars.next().unwrap());
+        }
+
+        if hex.is_empty() {
+            match chars.next() {
+                // Escaped newlines are line continuations
+                Some('\n') | None => {}
+                Some(other) => out.push(other),
+            }
+        } else {
+            if chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                chars.next();
+            }
+            let code = u32::from_str_radix(&hex, 16).unwrap_or(0xFFFD);
+            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+        }
+    }
+    out
+}
+
+fn escape_string(value: &str, quote: char) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == quote || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(css: &str) -> String {
+        rewrite_css(css, &Url::parse("https://example.com/css/site.css").unwrap(), UrlMode::Absolute)
+    }
+
+    #[test]
+    fn rewrites_quoted_and_unquoted_url() {
+        assert_eq!(
+            rewrite(r#"a { background: url(img/a.png) } b { background: URL( "/b.png" ) } c { background: url('../c.png') }"#),
+            r#"a { background: url(https://example.com/css/img/a.png) } b { background: URL( "https://example.com/b.png" ) } c { background: url('https://example.com/c.png') }"#
+        );
+    }
+
+    #[test]
+    fn unquoted_url_needing_quotes_gets_them() {
+        assert_eq!(rewrite(r"a { background: url(a\(1\).png) }"), r#"a { background: url("https://example.com/css/a(1).png") }"#);
+        assert_eq!(rewrite(r"a { background: url(a\ b.png) }"), "a { background: url(https://example.com/css/a%20b.png) }");
+    }
+
+    #[test]
+    fn rewrites_import_strings_and_urls() {
+        assert_eq!(
+            rewrite(r#"@import "base.css"; @import url(print.css) print; a { content: "x.css" }"#),
+            r#"@import "https://example.com/css/base.css"; @import url(https://example.com/css/print.css) print; a { content: "x.css" }"#
+        );
+    }
+
+    #[test]
+    fn rewrites_image_set_candidates() {
+        assert_eq!(
+            rewrite(r#"a { background: image-set("a.png" 1x, url(b.png) 2x) } b { background: -webkit-image-set('c.png' 1x) }"#),
+            r#"a { background: image-set("https://example.com/css/a.png" 1x, url(https://example.com/css/b.png) 2x) } b { background: -webkit-image-set('https://example.com/css/c.png' 1x) }"#
+        );
+    }
+
+    #[test]
+    fn leaves_data_urls_fragments_and_comments() {
+        let css = r##"a { background: url(data:image/png;base64,AAAA) } b { mask: url("#m") } /* url(x.png) */ c { background: url('data:image/svg+xml;utf8,<svg/>') }"##;
+        assert_eq!(rewrite(css), css);
+    }
+
+    #[test]
+    fn proxied_urls_point_back_at_the_proxy() {
+        let css = rewrite_css("a { background: url(a.png) }", &Url::parse("https://example.com/").unwrap(), UrlMode::Proxied);
+        assert_eq!(css, "a { background: url(/proxy?url=https%3A%2F%2Fexample.com%2Fa.png) }");
+    }
+}
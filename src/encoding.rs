@@ -0,0 +1,142 @@
+use std::io::{self, Read, Write};
+
+/// Content codings the proxy can decode and produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// Parse a `Content-Encoding` value; stacked or unknown codings return `None`
+    pub fn from_header(value: Option<&str>) -> Option<Self> {
+        let value = value.unwrap_or("").trim();
+        if value.contains(',') {
+            return None;
+        }
+        match value.to_ascii_lowercase().as_str() {
+            "" | "identity" => Some(Encoding::Identity),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Token for the `Content-Encoding` header, or `None` for identity
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Deflate => Some("deflate"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+}
+
+/// Decompress a body that was sent with `encoding`
+pub fn decode(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(body.len() * 4);
+    match encoding {
+        Encoding::Identity => out.extend_from_slice(body),
+        Encoding::Gzip => {
+            flate2::read::MultiGzDecoder::new(body).read_to_end(&mut out)?;
+        }
+        Encoding::Deflate => {
+            // "deflate" is meant to be zlib-wrapped, but plenty of servers send raw deflate
+            if flate2::read::ZlibDecoder::new(body).read_to_end(&mut out).is_err() {
+                out.clear();
+                flate2::read::DeflateDecoder::new(body).read_to_end(&mut out)?;
+            }
+        }
+        Encoding::Brotli => {
+            brotli::Decompressor::new(body, 4096).read_to_end(&mut out)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Compress a body with `encoding`
+pub fn encode(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                encoder.write_all(body)?;
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Pick the best coding the client accepts, preferring brotli, then gzip, then deflate
+pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+    let Some(accept) = accept_encoding else {
+        return Encoding::Identity;
+    };
+
+    [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+        .into_iter()
+        .map(|e| (e, quality(accept, e.header_value().unwrap())))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(Encoding, f32)>, (e, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((e, q)),
+        })
+        .map_or(Encoding::Identity, |(e, _)| e)
+}
+
+/// Whether the client's `Accept-Encoding` allows a body sent with `encoding`
+pub fn accepts(accept_encoding: Option<&str>, encoding: Encoding) -> bool {
+    match (encoding.header_value(), accept_encoding) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(coding), Some(accept)) => quality(accept, coding) > 0.0,
+    }
+}
+
+/// The q-value the `Accept-Encoding` header gives `coding`, falling back to `*`
+fn quality(accept: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()))
+            .unwrap_or(1.0);
+        if name == coding {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Whether a content type benefits from compression (text formats, not images or archives)
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(mime.as_str(),
+            "application/json" | "application/javascript" | "application/x-javascript" |
+            "application/xml" | "application/xhtml+xml" | "image/svg+xml" |
+            "application/wasm" | "application/manifest+json"
+        )
+}
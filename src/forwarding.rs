@@ -0,0 +1,248 @@
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// What `/proxy` tells the upstream about the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingMode {
+    /// Strip every forwarding header; the upstream only sees the proxy
+    Anonymous,
+    /// Append the real client address to any existing chain and emit RFC 7239 `Forwarded`
+    Transparent,
+    /// Like transparent, but inbound forwarding headers are only honoured from trusted proxies
+    TrustedProxy,
+}
+
+impl ForwardingMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "anonymous" => Some(ForwardingMode::Anonymous),
+            "transparent" => Some(ForwardingMode::Transparent),
+            "trusted" | "trusted-proxy" | "trusted_proxy" => Some(ForwardingMode::TrustedProxy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ForwardingConfig {
+    pub mode: ForwardingMode,
+    /// Peers whose `X-Forwarded-For`/`Forwarded` headers are believed in trusted-proxy mode
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            mode: ForwardingMode::Anonymous,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl ForwardingConfig {
+    /// Parse a comma-separated CIDR list; bare addresses are treated as single hosts
+    pub fn parse_cidrs(value: &str) -> Result<Vec<IpNet>, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid CIDR '{}'", entry))
+            })
+            .collect()
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&addr))
+    }
+
+    /// Whether the inbound forwarding headers on a request from `peer` can be believed
+    fn honours_inbound(&self, peer: IpAddr) -> bool {
+        match self.mode {
+            ForwardingMode::Anonymous => false,
+            ForwardingMode::Transparent => true,
+            ForwardingMode::TrustedProxy => self.is_trusted(peer),
+        }
+    }
+}
+
+/// Address of the TCP peer, falling back to loopback when unavailable
+pub fn peer_ip(req: &HttpRequest) -> IpAddr {
+    req.peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::from([127, 0, 0, 1]))
+}
+
+/// Whether the request arrived on one of our own TLS listeners. Unlike
+/// `connection_info().scheme()`, this ignores `X-Forwarded-Proto` and `Forwarded`, which any
+/// client can send.
+pub fn is_tls(req: &HttpRequest) -> bool {
+    req.app_config().secure()
+}
+
+/// The address we attribute a request to, for sessions, limits and logs.
+///
+/// Only in trusted-proxy mode, and only when the peer is trusted, is the inbound
+/// `X-Forwarded-For` chain walked (right to left, skipping trusted hops) to find the client.
+/// Everywhere else the TCP peer is the client, so spoofed headers can't impersonate anyone.
+pub fn client_ip(req: &HttpRequest, config: &ForwardingConfig) -> IpAddr {
+    let peer = peer_ip(req);
+    if config.mode != ForwardingMode::TrustedProxy || !config.is_trusted(peer) {
+        return peer;
+    }
+
+    inbound_chain(req)
+        .into_iter()
+        .rev()
+        .find(|addr| !config.is_trusted(*addr))
+        .unwrap_or(peer)
+}
+
+/// Forwarding headers to send upstream for this request
+pub fn upstream_headers(req: &HttpRequest, config: &ForwardingConfig) -> Vec<(&'static str, String)> {
+    if config.mode == ForwardingMode::Anonymous {
+        return Vec::new();
+    }
+
+    let peer = peer_ip(req);
+    let honour_inbound = config.honours_inbound(peer);
+    // A trusted proxy in front of us may have terminated TLS, so its scheme wins over ours
+    let inbound_proto = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| honour_inbound && (v == "http" || v == "https"));
+    let proto = inbound_proto.unwrap_or_else(|| if is_tls(req) { "https" } else { "http" }.to_string());
+
+    let inbound = |name: &str| -> Option<String> {
+        if !honour_inbound {
+            return None;
+        }
+        let values: Vec<&str> = req.headers().get_all(name).filter_map(|v| v.to_str().ok()).collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    };
+
+    let x_forwarded_for = match inbound("x-forwarded-for") {
+        Some(chain) => format!("{}, {}", chain, peer),
+        None => peer.to_string(),
+    };
+
+    // The proxy's own host is left out: upstreams would build absolute links pointing at it
+    let element = format!("for={};proto={}", forwarded_node(peer), proto);
+    let forwarded = match inbound("forwarded") {
+        Some(chain) => format!("{}, {}", chain, element),
+        None => element,
+    };
+
+    vec![
+        ("X-Forwarded-For", x_forwarded_for),
+        ("Forwarded", forwarded),
+        ("X-Forwarded-Proto", proto),
+        ("X-Real-IP", client_ip(req, config).to_string()),
+    ]
+}
+
+/// Addresses listed in the request's `X-Forwarded-For` headers, oldest first
+fn inbound_chain(req: &HttpRequest) -> Vec<IpAddr> {
+    req.headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|entry| parse_forwarded_addr(entry.trim()))
+        .collect()
+}
+
+/// Parse an address as it appears in forwarding headers (`1.2.3.4`, `1.2.3.4:80`, `[::1]:80`)
+fn parse_forwarded_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim_matches('"');
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value.rsplit_once(':')?.0.parse().ok()
+}
+
+/// RFC 7239 node syntax: IPv6 addresses are bracketed and quoted
+fn forwarded_node(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::{self, HeaderPolicy};
+    use actix_web::test::TestRequest;
+
+    const PEER: &str = "198.51.100.7:40000";
+
+    fn config(mode: ForwardingMode, trusted: &str) -> ForwardingConfig {
+        ForwardingConfig { mode, trusted_proxies: ForwardingConfig::parse_cidrs(trusted).unwrap() }
+    }
+
+    /// Every header the upstream would get with the given mode, from a client sending its own
+    /// forwarding headers, with no deny list configured
+    fn outgoing(config: &ForwardingConfig) -> Vec<(String, String)> {
+        let req = TestRequest::default()
+            .peer_addr(PEER.parse().unwrap())
+            .insert_header(("Forwarded", "for=203.0.113.9;proto=https"))
+            .insert_header(("X-Forwarded-For", "203.0.113.9"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "evil.example"))
+            .insert_header(("Via", "1.1 spoof"))
+            .to_http_request();
+        let mut outgoing: Vec<(String, String)> = headers::forwarded_request_headers(req.headers(), &HeaderPolicy::default(), true)
+            .into_iter()
+            .map(|(name, value)| (name, String::from_utf8(value).unwrap()))
+            .collect();
+        outgoing.extend(upstream_headers(&req, config).into_iter().map(|(name, value)| (name.to_ascii_lowercase(), value)));
+        outgoing
+    }
+
+    fn values<'a>(headers: &'a [(String, String)], name: &str) -> Vec<&'a str> {
+        headers.iter().filter(|(n, _)| n == name).map(|(_, v)| v.as_str()).collect()
+    }
+
+    #[test]
+    fn anonymous_mode_sends_no_forwarding_headers() {
+        assert!(outgoing(&config(ForwardingMode::Anonymous, "")).is_empty());
+    }
+
+    #[test]
+    fn transparent_mode_extends_the_client_chain_once() {
+        let headers = outgoing(&config(ForwardingMode::Transparent, ""));
+        assert_eq!(values(&headers, "forwarded"), vec!["for=203.0.113.9;proto=https, for=198.51.100.7;proto=https"]);
+        assert_eq!(values(&headers, "x-forwarded-for"), vec!["203.0.113.9, 198.51.100.7"]);
+        assert_eq!(values(&headers, "x-forwarded-proto"), vec!["https"]);
+        assert!(values(&headers, "x-forwarded-host").is_empty());
+        assert!(values(&headers, "via").is_empty());
+    }
+
+    #[test]
+    fn trusted_mode_ignores_an_untrusted_peer() {
+        let headers = outgoing(&config(ForwardingMode::TrustedProxy, "10.0.0.0/8"));
+        assert_eq!(values(&headers, "forwarded"), vec!["for=198.51.100.7;proto=http"]);
+        assert_eq!(values(&headers, "x-forwarded-for"), vec!["198.51.100.7"]);
+        assert_eq!(values(&headers, "x-forwarded-proto"), vec!["http"]);
+        assert_eq!(values(&headers, "x-real-ip"), vec!["198.51.100.7"]);
+        assert!(values(&headers, "via").is_empty());
+    }
+
+    #[test]
+    fn trusted_mode_extends_the_chain_of_a_trusted_peer() {
+        let headers = outgoing(&config(ForwardingMode::TrustedProxy, "198.51.100.0/24"));
+        assert_eq!(values(&headers, "forwarded"), vec!["for=203.0.113.9;proto=https, for=198.51.100.7;proto=https"]);
+        assert_eq!(values(&headers, "x-forwarded-for"), vec!["203.0.113.9, 198.51.100.7"]);
+        assert_eq!(values(&headers, "x-real-ip"), vec!["203.0.113.9"]);
+    }
+}
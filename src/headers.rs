@@ -0,0 +1,146 @@
+use actix_web::http::header::HeaderMap;
+
+/// Which client request headers `/proxy` passes on to the upstream
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicy {
+    /// When non-empty, only these headers are forwarded. Listing a header the proxy
+    /// generates itself (e.g. `user-agent`) forwards the client's value instead.
+    pub allow: Vec<String>,
+    /// Headers that are never forwarded
+    pub deny: Vec<String>,
+}
+
+impl HeaderPolicy {
+    /// Parse comma-separated header lists, as used by the environment variables
+    pub fn parse_list(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|a| a == name)
+    }
+
+    fn is_denied(&self, name: &str) -> bool {
+        self.deny.iter().any(|d| d == name)
+    }
+
+    fn is_explicitly_allowed(&self, name: &str) -> bool {
+        self.allow.iter().any(|a| a == name)
+    }
+}
+
+pub fn is_hop_by_hop_header(name: &str) -> bool {
+    matches!(name,
+        "connection" | "proxy-connection" | "te" | "trailer" |
+        "transfer-encoding" | "upgrade" | "keep-alive" | "proxy-authenticate" |
+        "proxy-authorization"
+    )
+}
+
+/// Headers describing the forwarding chain. The client's own values are never passed on:
+/// `forwarding::upstream_headers` emits these according to the forwarding mode, and copies
+/// of the client's would reach the upstream as second values or spoofed ones.
+pub fn is_forwarding_header(name: &str) -> bool {
+    matches!(name,
+        "forwarded" | "x-forwarded-for" | "x-forwarded-host" | "x-forwarded-port" |
+        "x-forwarded-proto" | "x-real-ip" | "via"
+    )
+}
+
+pub fn is_already_set_header(name: &str) -> bool {
+    matches!(name,
+        "host" | "content-length" | "accept" | "accept-language" |
+        "accept-encoding" | "user-agent" | "sec-fetch-dest" |
+        "sec-fetch-mode" | "sec-fetch-site" | "sec-fetch-user" |
+        "upgrade-insecure-requests" | "dnt" | "cache-control" |
+        "sec-ch-ua" | "sec-ch-ua-mobile" | "sec-ch-ua-platform" |
+        "sec-ch-ua-arch" | "sec-ch-ua-bitness" | "sec-ch-ua-full-version-list" |
+        "sec-ch-ua-wow64" | "sec-ch-ua-model" | "x-client-data" |
+        "sec-ch-viewport-width" | "sec-ch-viewport-height" | "sec-ch-device-memory" |
+        "sec-ch-prefers-color-scheme" | "sec-ch-prefers-reduced-motion" | "referer" |
+        "sec-ch-ua-platform-version"
+    )
+}
+
+/// Select the client request headers that should be sent upstream.
+///
+/// Hop-by-hop headers (including any named in `Connection`) and forwarding headers never
+/// pass, whatever the policy says. Headers the proxy
+/// generates itself are only forwarded when explicitly allowed, in which case the caller
+/// should drop its generated value. `Cookie` is left to the cookie translation layer when
+/// that is enabled.
+pub fn forwarded_request_headers(
+    headers: &HeaderMap,
+    policy: &HeaderPolicy,
+    translate_cookies: bool,
+) -> Vec<(String, Vec<u8>)> {
+    let connection_tokens: Vec<String> = headers
+        .get_all("connection")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            let blocked = is_hop_by_hop_header(name)
+                || is_forwarding_header(name)
+                || connection_tokens.iter().any(|t| t == name)
+                || (translate_cookies && name == "cookie")
+                || policy.is_denied(name);
+            let generated = is_already_set_header(name) && !policy.is_explicitly_allowed(name);
+
+            !blocked && !generated && policy.is_allowed(name)
+        })
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn forwarded_names(request: TestRequest, policy: &HeaderPolicy) -> Vec<String> {
+        let req = request.to_http_request();
+        forwarded_request_headers(req.headers(), policy, true).into_iter().map(|(name, _)| name).collect()
+    }
+
+    fn client_request() -> TestRequest {
+        TestRequest::default()
+            .insert_header(("Forwarded", "for=203.0.113.9"))
+            .insert_header(("X-Forwarded-Host", "evil.example"))
+            .insert_header(("X-Forwarded-For", "203.0.113.9"))
+            .insert_header(("Via", "1.1 spoof"))
+            .insert_header(("Connection", "keep-alive, X-Hop"))
+            .insert_header(("X-Hop", "1"))
+            .insert_header(("Cookie", "a=1"))
+            .insert_header(("User-Agent", "client"))
+            .insert_header(("X-Custom", "kept"))
+    }
+
+    #[test]
+    fn forwarding_and_hop_headers_never_pass_without_a_deny_list() {
+        assert_eq!(forwarded_names(client_request(), &HeaderPolicy::default()), vec!["x-custom"]);
+    }
+
+    #[test]
+    fn allowing_a_forwarding_header_does_not_forward_it() {
+        let policy = HeaderPolicy { allow: HeaderPolicy::parse_list("forwarded, via, user-agent, x-custom"), deny: Vec::new() };
+        let mut names = forwarded_names(client_request(), &policy);
+        names.sort();
+        assert_eq!(names, vec!["user-agent", "x-custom"]);
+    }
+
+    #[test]
+    fn deny_list_removes_ordinary_headers() {
+        let policy = HeaderPolicy { allow: Vec::new(), deny: HeaderPolicy::parse_list("X-Custom") };
+        assert!(forwarded_names(client_request(), &policy).is_empty());
+    }
+}
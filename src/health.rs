@@ -0,0 +1,80 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Process start time and bound listeners, reported by `/readyz`
+pub struct Health {
+    started: Instant,
+    started_at: u64,
+    listeners: OnceLock<Vec<SocketAddr>>,
+}
+
+/// Outcome of each readiness check
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Checks {
+    /// The server's PQC keys sign and verify
+    pub pqc_keys: bool,
+    /// The browsing and PQC session stores can be locked
+    pub session_store: bool,
+    /// Every configured listener is bound
+    pub listeners: bool,
+}
+
+impl Checks {
+    pub fn passed(&self) -> bool {
+        self.pqc_keys && self.session_store && self.listeners
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: Checks,
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub started_at: u64,
+    pub listeners: Vec<String>,
+    pub build: BuildInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub profile: &'static str,
+    pub target: String,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            listeners: OnceLock::new(),
+        }
+    }
+
+    /// Called once every listener has been bound
+    pub fn set_listeners(&self, addresses: Vec<SocketAddr>) {
+        let _ = self.listeners.set(addresses);
+    }
+
+    /// Combine the caller's dependency checks with the listener state
+    pub fn readiness(&self, pqc_keys: bool, session_store: bool) -> Readiness {
+        let listeners = self.listeners.get().map_or_else(Vec::new, |addrs| addrs.iter().map(ToString::to_string).collect());
+        let checks = Checks { pqc_keys, session_store, listeners: !listeners.is_empty() };
+        Readiness {
+            status: if checks.passed() { "ready" } else { "not_ready" },
+            checks,
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: self.started.elapsed().as_secs(),
+            started_at: self.started_at,
+            listeners,
+            build: BuildInfo {
+                name: env!("CARGO_PKG_NAME"),
+                profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+                target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            },
+        }
+    }
+}
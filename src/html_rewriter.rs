@@ -0,0 +1,539 @@
+use url::Url;
+
+use crate::css_rewriter;
+use crate::redirect::{self, UrlMode};
+
+/// Elements whose content is raw text and must be passed through without parsing
+const RAW_TEXT_ELEMENTS: &[&str] = &[
+    "script", "style", "textarea", "title", "xmp", "iframe", "noembed", "noframes",
+];
+
+/// A parsed attribute inside a start tag, with byte offsets into the tag
+struct Attribute {
+    name: String,
+    value: Option<AttributeValue>,
+}
+
+struct AttributeValue {
+    start: usize,
+    end: usize,
+    quote: Option<u8>,
+}
+
+/// Streaming HTML rewriter that resolves every URL-bearing attribute against the document base.
+///
+/// Input is fed in arbitrary chunks with [`HtmlRewriter::write`]; tags split across chunk
+/// boundaries are buffered until complete. Text, comments and the contents of raw-text
+/// elements such as `<script>` are passed through byte-for-byte, while `<style>` blocks
+/// and `style=` attributes go through the CSS rewriter.
+pub struct HtmlRewriter {
+    base: Url,
+    mode: UrlMode,
+    base_seen: bool,
+    raw_text: Option<&'static str>,
+    pending: Vec<u8>,
+}
+
+impl HtmlRewriter {
+    pub fn new(base: Url, mode: UrlMode) -> Self {
+        Self {
+            base,
+            mode,
+            base_seen: false,
+            raw_text: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feed the next chunk of the document, returning the output that is complete so far
+    pub fn write(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let input = std::mem::take(&mut self.pending);
+
+        let mut out = Vec::with_capacity(input.len());
+        let consumed = self.process(&input, &mut out, false);
+        self.pending = input[consumed..].to_vec();
+        out
+    }
+
+    /// Flush whatever is still buffered once the document has ended
+    pub fn finish(mut self) -> Vec<u8> {
+        let input = std::mem::take(&mut self.pending);
+
+        let mut out = Vec::with_capacity(input.len());
+        let consumed = self.process(&input, &mut out, true);
+        out.extend_from_slice(&input[consumed..]);
+        out
+    }
+
+    /// Rewrite as much of `input` as possible, returning how many bytes were consumed
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>, eof: bool) -> usize {
+        let mut pos = 0;
+
+        while pos < input.len() {
+            if self.raw_text == Some("style") {
+                // Stylesheets are buffered whole so url() tokens are never split
+                let offset = match find_closing_tag(&input[pos..], "style") {
+                    Some(offset) => offset,
+                    None if eof => input.len() - pos,
+                    None => return pos,
+                };
+                let css = String::from_utf8_lossy(&input[pos..pos + offset]);
+                out.extend_from_slice(css_rewriter::rewrite_css(&css, &self.base, self.mode).as_bytes());
+                pos += offset;
+                self.raw_text = None;
+                continue;
+            }
+
+            if let Some(element) = self.raw_text {
+                match find_closing_tag(&input[pos..], element) {
+                    Some(offset) => {
+                        out.extend_from_slice(&input[pos..pos + offset]);
+                        pos += offset;
+                        self.raw_text = None;
+                    }
+                    None => {
+                        // Hold back enough bytes to catch a closing tag split across chunks
+                        let keep = if eof { 0 } else { (element.len() + 2).min(input.len() - pos) };
+                        let flush_to = input.len() - keep;
+                        out.extend_from_slice(&input[pos..flush_to]);
+                        return flush_to;
+                    }
+                }
+                continue;
+            }
+
+            let Some(offset) = input[pos..].iter().position(|&b| b == b'<') else {
+                out.extend_from_slice(&input[pos..]);
+                return input.len();
+            };
+            out.extend_from_slice(&input[pos..pos + offset]);
+            pos += offset;
+
+            let rest = &input[pos..];
+            let markup_end = match rest.get(1) {
+                None => None,
+                Some(b'!') if b"<!--".starts_with(&rest[..rest.len().min(4)]) => {
+                    find(rest, b"-->").map(|i| i + 3)
+                }
+                Some(b'!' | b'?' | b'/') => find(rest, b">").map(|i| i + 1),
+                Some(b) if b.is_ascii_alphabetic() => find_tag_end(rest),
+                Some(_) => {
+                    // A stray '<' in text
+                    out.push(b'<');
+                    pos += 1;
+                    continue;
+                }
+            };
+
+            match markup_end {
+                Some(end) => {
+                    if rest[1].is_ascii_alphabetic() {
+                        let tag = self.rewrite_tag(&rest[..end]);
+                        out.extend_from_slice(&tag);
+                    } else {
+                        out.extend_from_slice(&rest[..end]);
+                    }
+                    pos += end;
+                }
+                None if eof => {
+                    out.extend_from_slice(rest);
+                    return input.len();
+                }
+                None => return pos,
+            }
+        }
+
+        pos
+    }
+
+    /// Rewrite the URL-bearing attributes of a single start tag (`<...>` inclusive)
+    fn rewrite_tag(&mut self, tag: &[u8]) -> Vec<u8> {
+        let (tag_name, attributes) = parse_tag(tag);
+
+        let self_closing = tag.len() >= 2 && tag[tag.len() - 2] == b'/';
+        if !self_closing {
+            self.raw_text = RAW_TEXT_ELEMENTS.iter().copied().find(|e| *e == tag_name);
+        }
+
+        // <base href> changes how every later reference resolves
+        if tag_name == "base" && !self.base_seen {
+            if let Some(href) = attribute_text(tag, &attributes, "href")
+                && let Ok(new_base) = self.base.join(href.trim())
+            {
+                self.base = new_base;
+            }
+            self.base_seen = true;
+        }
+
+        let is_refresh = tag_name == "meta"
+            && attribute_text(tag, &attributes, "http-equiv")
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("refresh"));
+
+        let mut replacements = Vec::new();
+        for attribute in &attributes {
+            let Some(value) = &attribute.value else { continue };
+            let raw = decode_entities(&String::from_utf8_lossy(&tag[value.start..value.end]));
+
+            let rewritten = match attribute.name.as_str() {
+                // The base itself stays on the origin so the browser resolves consistently
+                "href" if tag_name == "base" => redirect::resolve(&self.base, &raw, UrlMode::Absolute),
+                "href" | "src" | "action" | "formaction" | "poster" | "cite" | "background"
+                | "longdesc" | "manifest" | "icon" => redirect::resolve(&self.base, &raw, self.mode),
+                "data" if tag_name == "object" => redirect::resolve(&self.base, &raw, self.mode),
+                "srcset" | "imagesrcset" => self.rewrite_srcset(&raw),
+                "ping" => self.rewrite_url_list(&raw),
+                "content" if is_refresh => redirect::rewrite_refresh(&raw, &self.base, self.mode),
+                "style" => Some(css_rewriter::rewrite_css(&raw, &self.base, self.mode)).filter(|css| *css != raw),
+                _ => None,
+            };
+
+            if let Some(new_value) = rewritten {
+                replacements.push((value, new_value));
+            }
+        }
+
+        if replacements.is_empty() {
+            return tag.to_vec();
+        }
+
+        let mut out = Vec::with_capacity(tag.len() + 64);
+        let mut cursor = 0;
+        for (value, new_value) in replacements {
+            match value.quote {
+                Some(quote) => {
+                    out.extend_from_slice(&tag[cursor..value.start]);
+                    out.extend_from_slice(escape_attribute(&new_value, quote).as_bytes());
+                }
+                None => {
+                    // Unquoted values get quoted so the rewritten URL can't break the tag
+                    out.extend_from_slice(&tag[cursor..value.start]);
+                    out.push(b'"');
+                    out.extend_from_slice(escape_attribute(&new_value, b'"').as_bytes());
+                    out.push(b'"');
+                }
+            }
+            cursor = value.end;
+        }
+        out.extend_from_slice(&tag[cursor..]);
+        out
+    }
+
+    /// Rewrite each candidate URL of a `srcset`, keeping the width/density descriptors
+    fn rewrite_srcset(&self, value: &str) -> Option<String> {
+        let mut candidates = Vec::new();
+        let mut rest = value;
+
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+            if rest.is_empty() {
+                break;
+            }
+
+            let url_end = rest.find(|c: char| c.is_ascii_whitespace()).unwrap_or(rest.len());
+            let mut url = &rest[..url_end];
+            rest = &rest[url_end..];
+
+            // A trailing comma on the URL ends the candidate without descriptors
+            let descriptors = if let Some(stripped) = url.strip_suffix(',') {
+                url = stripped;
+                ""
+            } else {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let descriptors = rest[..end].trim();
+                rest = &rest[end..];
+                descriptors
+            };
+
+            let url = redirect::resolve(&self.base, url, self.mode).unwrap_or_else(|| url.to_string());
+            if descriptors.is_empty() {
+                candidates.push(url);
+            } else {
+                candidates.push(format!("{} {}", url, descriptors));
+            }
+        }
+
+        Some(candidates.join(", "))
+    }
+
+    /// Rewrite a whitespace-separated URL list such as `<a ping>`
+    fn rewrite_url_list(&self, value: &str) -> Option<String> {
+        let urls: Vec<String> = value
+            .split_ascii_whitespace()
+            .map(|url| redirect::resolve(&self.base, url, self.mode).unwrap_or_else(|| url.to_string()))
+            .collect();
+        Some(urls.join(" "))
+    }
+}
+
+/// Rewrite a complete HTML document in one go
+pub fn rewrite_html(html: &str, base: &Url, mode: UrlMode) -> String {
+    let mut rewriter = HtmlRewriter::new(base.clone(), mode);
+    let mut out = rewriter.write(html.as_bytes());
+    out.extend(rewriter.finish());
+    String::from_utf8(out).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// Split a start tag into its lowercase name and attribute spans
+fn parse_tag(tag: &[u8]) -> (String, Vec<Attribute>) {
+    let len = tag.len();
+    let mut i = 1;
+
+    while i < len && !tag[i].is_ascii_whitespace() && tag[i] != b'/' && tag[i] != b'>' {
+        i += 1;
+    }
+    let tag_name = String::from_utf8_lossy(&tag[1..i]).to_ascii_lowercase();
+
+    let mut attributes = Vec::new();
+    loop {
+        while i < len && (tag[i].is_ascii_whitespace() || tag[i] == b'/') {
+            i += 1;
+        }
+        if i >= len || tag[i] == b'>' {
+            break;
+        }
+
+        let name_start = i;
+        while i < len && !tag[i].is_ascii_whitespace() && !matches!(tag[i], b'=' | b'>' | b'/') {
+            i += 1;
+        }
+        if i == name_start {
+            // Stray '=' with no attribute name
+            i += 1;
+            continue;
+        }
+        let name = String::from_utf8_lossy(&tag[name_start..i]).to_ascii_lowercase();
+
+        let mut j = i;
+        while j < len && tag[j].is_ascii_whitespace() {
+            j += 1;
+        }
+        if j >= len || tag[j] != b'=' {
+            attributes.push(Attribute { name, value: None });
+            continue;
+        }
+
+        i = j + 1;
+        while i < len && tag[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let value = if i < len && (tag[i] == b'"' || tag[i] == b'\'') {
+            let quote = tag[i];
+            let start = i + 1;
+            let end = tag[start..].iter().position(|&b| b == quote).map_or(len - 1, |p| start + p);
+            i = (end + 1).min(len);
+            AttributeValue { start, end, quote: Some(quote) }
+        } else {
+            let start = i;
+            while i < len && !tag[i].is_ascii_whitespace() && tag[i] != b'>' {
+                i += 1;
+            }
+            AttributeValue { start, end: i, quote: None }
+        };
+
+        attributes.push(Attribute { name, value: Some(value) });
+    }
+
+    (tag_name, attributes)
+}
+
+/// Decoded text of the named attribute, if present with a value
+fn attribute_text(tag: &[u8], attributes: &[Attribute], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|a| a.name == name)
+        .and_then(|a| a.value.as_ref())
+        .map(|v| decode_entities(&String::from_utf8_lossy(&tag[v.start..v.end])))
+}
+
+/// Find the end of a start tag (index just past `>`), ignoring `>` inside quoted values
+fn find_tag_end(input: &[u8]) -> Option<usize> {
+    let mut quote: Option<u8> = None;
+    let mut last_significant = 0u8;
+
+    for (i, &b) in input.iter().enumerate().skip(1) {
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => {}
+            None if (b == b'"' || b == b'\'') && last_significant == b'=' => quote = Some(b),
+            None if b == b'>' => return Some(i + 1),
+            None => {}
+        }
+        if !b.is_ascii_whitespace() {
+            last_significant = b;
+        }
+    }
+
+    None
+}
+
+/// Offset of `</element` (case-insensitive) in `input`
+fn find_closing_tag(input: &[u8], element: &str) -> Option<usize> {
+    let needle_len = element.len() + 2;
+    if input.len() < needle_len {
+        return None;
+    }
+
+    (0..=input.len() - needle_len).find(|&i| {
+        input[i] == b'<'
+            && input[i + 1] == b'/'
+            && input[i + 2..i + needle_len].eq_ignore_ascii_case(element.as_bytes())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Decode the character references that commonly appear in URL attributes
+fn decode_entities(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let decoded = rest.find(';').filter(|&semi| semi <= 10).and_then(|semi| {
+            let entity = &rest[1..semi];
+            let ch = match entity {
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32)
+                }
+                _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+                _ => None,
+            };
+            ch.map(|c| (c, semi))
+        });
+
+        match decoded {
+            Some((c, semi)) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Escape a value for use inside an attribute quoted with `quote`
+fn escape_attribute(value: &str, quote: u8) -> String {
+    let escaped = value.replace('&', "&amp;");
+    if quote == b'\'' {
+        escaped.replace('\'', "&#39;")
+    } else {
+        escaped.replace('"', "&quot;")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://example.com/dir/page.html").unwrap()
+    }
+
+    fn rewrite(html: &str) -> String {
+        rewrite_html(html, &base(), UrlMode::Absolute)
+    }
+
+    /// Feed `html` split at every byte offset in turn
+    fn rewrite_split_everywhere(html: &str, mode: UrlMode) -> Vec<String> {
+        (1..html.len())
+            .map(|at| {
+                let mut rewriter = HtmlRewriter::new(base(), mode);
+                let mut out = rewriter.write(&html.as_bytes()[..at]);
+                out.extend(rewriter.write(&html.as_bytes()[at..]));
+                out.extend(rewriter.finish());
+                String::from_utf8(out).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_url_attributes() {
+        assert_eq!(
+            rewrite(r#"<a href="next.html">n</a><img src='/i.png'><form action=post>"#),
+            r#"<a href="https://example.com/dir/next.html">n</a><img src='https://example.com/i.png'><form action="https://example.com/dir/post">"#
+        );
+        assert_eq!(
+            rewrite_html(r#"<a href="/x?a=1&amp;b=2">"#, &base(), UrlMode::Proxied),
+            r#"<a href="/proxy?url=https%3A%2F%2Fexample.com%2Fx%3Fa%3D1%26b%3D2">"#
+        );
+    }
+
+    #[test]
+    fn leaves_fragments_other_schemes_and_unknown_attributes() {
+        let html = r##"<a href="#top" title="a.html"></a><a href="javascript:void(0)"><img src="data:image/png;base64,AAAA">"##;
+        assert_eq!(rewrite(html), html);
+    }
+
+    #[test]
+    fn rewrites_each_srcset_candidate() {
+        assert_eq!(
+            rewrite(r#"<img srcset="a.png 1x, /b.png 2x,c.png">"#),
+            r#"<img srcset="https://example.com/dir/a.png 1x, https://example.com/b.png 2x, https://example.com/dir/c.png">"#
+        );
+    }
+
+    #[test]
+    fn base_href_applies_to_later_references_only() {
+        assert_eq!(
+            rewrite(r#"<a href="a"><base href="/other/"><a href="b"><base href="/ignored/"><a href="c">"#),
+            concat!(
+                r#"<a href="https://example.com/dir/a"><base href="https://example.com/other/">"#,
+                r#"<a href="https://example.com/other/b"><base href="https://example.com/ignored/">"#,
+                r#"<a href="https://example.com/other/c">"#
+            )
+        );
+    }
+
+    #[test]
+    fn rewrites_inline_and_embedded_styles() {
+        assert_eq!(
+            rewrite(r#"<div style="background: url(bg.png)"></div><style>a { background: url('/a.png') }</style>"#),
+            r#"<div style="background: url(https://example.com/dir/bg.png)"></div><style>a { background: url('https://example.com/a.png') }</style>"#
+        );
+    }
+
+    #[test]
+    fn passes_scripts_and_comments_through() {
+        let html = r#"<script>var s = "<a href='x'>";</script><!-- <img src="y"> -->"#;
+        assert_eq!(rewrite(html), html);
+    }
+
+    #[test]
+    fn tags_split_across_chunks_are_rewritten_whole() {
+        let html = r#"<p>t</p><a href="a.html" srcset="b.png 2x"><style>p{background:url(c.png)}</style><script>"<img src=d>"</script><img src=e.png>"#;
+        for mode in [UrlMode::Absolute, UrlMode::Proxied] {
+            let whole = rewrite_html(html, &base(), mode);
+            for (at, split) in rewrite_split_everywhere(html, mode).into_iter().enumerate() {
+                assert_eq!(split, whole, "split after byte {}", at + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn unfinished_tag_is_flushed_at_the_end() {
+        let mut rewriter = HtmlRewriter::new(base(), UrlMode::Absolute);
+        assert_eq!(rewriter.write(b"text <a href=\"x"), b"text ");
+        assert_eq!(rewriter.finish(), b"<a href=\"x");
+    }
+}
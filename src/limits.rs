@@ -0,0 +1,367 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Token-bucket rate and concurrency cap for one kind of key; zero disables either part
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ScopeLimit {
+    /// Sustained requests per second
+    pub rate: f64,
+    /// Requests that may be made at once before the rate applies
+    pub burst: f64,
+    /// Requests that may be in flight at the same time
+    pub max_concurrent: usize,
+}
+
+impl ScopeLimit {
+    pub const UNLIMITED: ScopeLimit = ScopeLimit { rate: 0.0, burst: 0.0, max_concurrent: 0 };
+
+    /// A limit whose burst defaults to one second's worth of requests
+    pub fn new(rate: f64, burst: Option<f64>, max_concurrent: usize) -> Self {
+        let burst = match burst {
+            Some(burst) if burst >= 1.0 => burst,
+            _ if rate > 0.0 => rate.max(1.0),
+            _ => 0.0,
+        };
+        ScopeLimit { rate, burst, max_concurrent }
+    }
+
+    /// Parse `rate=10,burst=20,concurrent=4` (any subset), or `off`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.trim().eq_ignore_ascii_case("off") {
+            return Ok(Self::UNLIMITED);
+        }
+        let (mut rate, mut burst, mut max_concurrent) = (0.0, None, 0);
+        for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, val) = part.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", part))?;
+            let invalid = || format!("invalid value for {}: '{}'", key.trim(), val.trim());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "rate" => rate = val.trim().parse().map_err(|_| invalid())?,
+                "burst" => burst = Some(val.trim().parse().map_err(|_| invalid())?),
+                "concurrent" | "concurrency" => max_concurrent = val.trim().parse().map_err(|_| invalid())?,
+                other => return Err(format!("unknown key '{}'", other)),
+            }
+        }
+        Ok(Self::new(rate, burst, max_concurrent))
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.rate <= 0.0 && self.max_concurrent == 0
+    }
+}
+
+/// Limits applied to `/proxy`, keyed by client address, session and destination host
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub client: ScopeLimit,
+    pub session: ScopeLimit,
+    pub host: ScopeLimit,
+}
+
+impl Default for RateLimits {
+    /// No rate limits: a single proxied page pulls its images, scripts and stylesheets through
+    /// `/proxy` as well, so any per-client rate low enough to matter would stall page loads.
+    /// The concurrency caps only stop one client or site from holding every connection.
+    fn default() -> Self {
+        Self {
+            client: ScopeLimit { rate: 0.0, burst: 0.0, max_concurrent: 16 },
+            session: ScopeLimit::UNLIMITED,
+            host: ScopeLimit { rate: 0.0, burst: 0.0, max_concurrent: 32 },
+        }
+    }
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    /// `client`, `session` or `host`
+    pub scope: &'static str,
+    pub reason: &'static str,
+    pub retry_after: Duration,
+}
+
+impl Rejection {
+    /// Whole seconds for the `Retry-After` header, at least one
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    in_flight: usize,
+}
+
+/// Counters for one scope, for the metrics and admin endpoints
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ScopeStats {
+    pub allowed: u64,
+    pub rate_limited: u64,
+    pub concurrency_limited: u64,
+    pub in_flight: usize,
+    pub tracked_keys: usize,
+}
+
+/// Buckets for every key of one scope
+struct Scope {
+    name: &'static str,
+    limit: ScopeLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    allowed: AtomicU64,
+    rate_limited: AtomicU64,
+    concurrency_limited: AtomicU64,
+}
+
+impl Scope {
+    fn new(name: &'static str, limit: ScopeLimit) -> Self {
+        Self {
+            name,
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+            allowed: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            concurrency_limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Refill the key's bucket and check it, without taking anything yet
+    fn check(&self, buckets: &mut HashMap<String, Bucket>, key: &str, now: Instant) -> Result<(), Rejection> {
+        let limit = self.limit;
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: limit.burst, refilled: now, in_flight: 0 });
+
+        if limit.rate > 0.0 {
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+            bucket.refilled = now;
+            if bucket.tokens < 1.0 {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return Err(Rejection {
+                    scope: self.name,
+                    reason: "rate limit exceeded",
+                    retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate),
+                });
+            }
+        }
+        if limit.max_concurrent > 0 && bucket.in_flight >= limit.max_concurrent {
+            self.concurrency_limited.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection {
+                scope: self.name,
+                reason: "too many concurrent requests",
+                retry_after: Duration::from_secs(1),
+            });
+        }
+        Ok(())
+    }
+
+    fn take(&self, buckets: &mut HashMap<String, Bucket>, key: &str) {
+        if let Some(bucket) = buckets.get_mut(key) {
+            if self.limit.rate > 0.0 {
+                bucket.tokens -= 1.0;
+            }
+            bucket.in_flight += 1;
+        }
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn release(&self, key: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.in_flight = bucket.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Forget keys with nothing in flight whose bucket has refilled completely
+    fn prune(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let limit = self.limit;
+        buckets.retain(|_, bucket| {
+            let refilled = limit.rate <= 0.0
+                || bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * limit.rate >= limit.burst;
+            bucket.in_flight > 0 || !refilled
+        });
+    }
+
+    fn stats(&self) -> ScopeStats {
+        let buckets = self.buckets.lock().unwrap();
+        ScopeStats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            concurrency_limited: self.concurrency_limited.load(Ordering::Relaxed),
+            in_flight: buckets.values().map(|b| b.in_flight).sum(),
+            tracked_keys: buckets.len(),
+        }
+    }
+}
+
+/// Shared limiter state for all workers
+pub struct RateLimiter {
+    scopes: [Scope; 3],
+    acquisitions: AtomicU64,
+}
+
+/// Held for the lifetime of a request; releases its concurrency slots when dropped
+pub struct Permit {
+    limiter: Arc<RateLimiter>,
+    keys: Vec<(usize, String)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for (scope, key) in &self.keys {
+            self.limiter.scopes[*scope].release(key);
+        }
+    }
+}
+
+/// How often idle buckets are swept, in acquisitions
+const PRUNE_EVERY: u64 = 1024;
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            scopes: [
+                Scope::new("client", limits.client),
+                Scope::new("session", limits.session),
+                Scope::new("host", limits.host),
+            ],
+            acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    /// Admit a request from `client` in `session` to `host`, or say which limit it hit.
+    ///
+    /// All scopes are checked before anything is taken, so a request refused by one limit
+    /// doesn't use up tokens in the others.
+    pub fn acquire(self: &Arc<Self>, client: &str, session: &str, host: &str) -> Result<Permit, Rejection> {
+        let keys = [client, session, host];
+        let active: Vec<usize> = (0..self.scopes.len())
+            .filter(|&i| !self.scopes[i].limit.is_unlimited())
+            .collect();
+
+        // Locked in a fixed order, so concurrent acquisitions can't deadlock
+        let mut guards: Vec<MutexGuard<HashMap<String, Bucket>>> =
+            active.iter().map(|&i| self.scopes[i].buckets.lock().unwrap()).collect();
+        let now = Instant::now();
+
+        for (guard, &i) in guards.iter_mut().zip(&active) {
+            self.scopes[i].check(guard, keys[i], now)?;
+        }
+        for (guard, &i) in guards.iter_mut().zip(&active) {
+            self.scopes[i].take(guard, keys[i]);
+        }
+
+        if self.acquisitions.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1 {
+            for (guard, &i) in guards.iter_mut().zip(&active) {
+                self.scopes[i].prune(guard, now);
+            }
+        }
+        drop(guards);
+
+        Ok(Permit {
+            limiter: self.clone(),
+            keys: active.into_iter().map(|i| (i, keys[i].to_string())).collect(),
+        })
+    }
+
+    /// Counters per scope, as `(scope, stats)`
+    pub fn stats(&self) -> Vec<(&'static str, ScopeStats)> {
+        self.scopes.iter().map(|scope| (scope.name, scope.stats())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(client: ScopeLimit, host: ScopeLimit) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(&RateLimits { client, session: ScopeLimit::UNLIMITED, host }))
+    }
+
+    /// Check and, if admitted, take a token for `key` at `now`
+    fn admit(scope: &Scope, key: &str, now: Instant) -> Result<(), Rejection> {
+        let mut buckets = scope.buckets.lock().unwrap();
+        scope.check(&mut buckets, key, now)?;
+        scope.take(&mut buckets, key);
+        Ok(())
+    }
+
+    #[test]
+    fn burst_is_spent_then_refilled_at_the_rate() {
+        let scope = Scope::new("client", ScopeLimit::new(2.0, Some(3.0), 0));
+        let start = Instant::now();
+        for _ in 0..3 {
+            admit(&scope, "a", start).unwrap();
+        }
+        let rejection = admit(&scope, "a", start).unwrap_err();
+        assert_eq!((rejection.scope, rejection.reason), ("client", "rate limit exceeded"));
+        assert_eq!(rejection.retry_after, Duration::from_millis(500));
+        assert_eq!(rejection.retry_after_secs(), 1);
+
+        // Other keys have their own bucket
+        admit(&scope, "b", start).unwrap();
+
+        // Half a second refills one token at 2/s
+        admit(&scope, "a", start + Duration::from_millis(500)).unwrap();
+        assert!(admit(&scope, "a", start + Duration::from_millis(500)).is_err());
+
+        // A long pause refills no further than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            admit(&scope, "a", later).unwrap();
+        }
+        assert!(admit(&scope, "a", later).is_err());
+    }
+
+    #[test]
+    fn concurrency_slots_are_released_with_the_permit() {
+        let limiter = limiter(ScopeLimit::new(0.0, None, 2), ScopeLimit::UNLIMITED);
+        let first = limiter.acquire("c", "s", "h").unwrap();
+        let _second = limiter.acquire("c", "s", "h").unwrap();
+        let rejection = limiter.acquire("c", "s", "h").err().unwrap();
+        assert_eq!((rejection.scope, rejection.reason), ("client", "too many concurrent requests"));
+        let _other_client = limiter.acquire("d", "s", "h").unwrap();
+
+        drop(first);
+        let _third = limiter.acquire("c", "s", "h").unwrap();
+        let stats = limiter.stats();
+        assert_eq!(stats[0].1.in_flight, 3);
+        assert_eq!((stats[0].1.allowed, stats[0].1.concurrency_limited), (4, 1));
+    }
+
+    #[test]
+    fn a_refused_request_takes_nothing_from_the_other_scopes() {
+        let limiter = limiter(ScopeLimit::new(1.0, Some(1.0), 0), ScopeLimit::new(0.0, None, 1));
+        let held = limiter.acquire("c1", "s", "busy.test").unwrap();
+
+        // Refused by the host cap, so c2 keeps its only token
+        assert_eq!(limiter.acquire("c2", "s", "busy.test").err().unwrap().scope, "host");
+        drop(held);
+        let _permit = limiter.acquire("c2", "s", "busy.test").unwrap();
+        assert_eq!(limiter.acquire("c2", "s", "other.test").err().unwrap().scope, "client");
+    }
+
+    #[test]
+    fn parses_limits_and_defaults_the_burst() {
+        assert_eq!(ScopeLimit::parse("rate=5").unwrap(), ScopeLimit { rate: 5.0, burst: 5.0, max_concurrent: 0 });
+        assert_eq!(ScopeLimit::parse("rate=0.5, burst=4, concurrent=2").unwrap(), ScopeLimit { rate: 0.5, burst: 4.0, max_concurrent: 2 });
+        assert_eq!(ScopeLimit::parse("rate=0.5").unwrap().burst, 1.0);
+        assert_eq!(ScopeLimit::parse(" OFF ").unwrap(), ScopeLimit::UNLIMITED);
+        assert!(ScopeLimit::parse("rate").is_err());
+        assert!(ScopeLimit::parse("speed=1").is_err());
+        assert!(ScopeLimit::parse("concurrent=-1").is_err());
+    }
+
+    #[test]
+    fn default_limits_cap_concurrency_only() {
+        let limits = RateLimits::default();
+        assert_eq!(limits.client.rate, 0.0);
+        assert_eq!(limits.host.rate, 0.0);
+        assert!(limits.session.is_unlimited());
+        let limiter = Arc::new(RateLimiter::new(&limits));
+        let _permits: Vec<Permit> = (0..16).map(|_| limiter.acquire("c", "s", "h").unwrap()).collect();
+        assert!(limiter.acquire("c", "s", "h").is_err());
+    }
+}
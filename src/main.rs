@@ -1,20 +1,51 @@
-use actix_cors::Cors;
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Result};
-use reqwest::{Client, cookie::Jar};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures_util::StreamExt;
+use reqwest::Client;
+use reqwest::cookie::CookieStore;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use std::hash::Hasher;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use rand::{thread_rng, Rng};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::ToSocketAddrs;
 use std::time::{SystemTime, UNIX_EPOCH};
-use base64::{Engine as _, engine::general_purpose};
 
+mod access_log;
+mod audit;
+mod auth;
+mod config;
+mod cookies;
+mod css_rewriter;
+mod encoding;
+mod forwarding;
+mod health;
+mod headers;
+mod html_rewriter;
+mod limits;
+mod metrics;
+mod policy;
 mod pqc;
-use pqc::PqcCrypto;
+mod redirect;
+mod session_map;
+mod session_store;
+mod session_token;
+mod shutdown;
+mod ssrf;
+mod telemetry;
+mod tls;
+use auth::{Authenticator, Scope};
+use clap::Parser;
+use config::{Cli, ProxyConfig, RedirectMode, SessionSettings, Timeouts};
+use health::Health;
+use limits::RateLimiter;
+use metrics::{Metrics, PqcOperation};
+use policy::PolicyStore;
+use redirect::UrlMode;
+use session_token::SessionTokens;
+use pqc::{PqcCrypto, PqcKeyPair};
 
 // Advanced browser fingerprint data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct BrowserFingerprint {
     user_agent: String,
     sec_ch_ua: String,
@@ -78,386 +109,24 @@ impl BrowserFingerprint {
     }
 }
 
-// Enhanced session management with persistent state
-#[derive(Clone)]
-struct BrowserSession {
-    fingerprint: BrowserFingerprint,
-    cookies: Arc<Jar>,
-    visited_urls: Vec<String>,
-    interaction_history: Vec<String>,
-    session_start: u64,
-    last_activity: u64,
-    request_count: u32,
-}
-
-impl BrowserSession {
-    fn new() -> Self {
-        let fingerprint = BrowserFingerprint::new();
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        
-        Self {
-            fingerprint,
-            cookies: Arc::new(Jar::default()),
-            visited_urls: Vec::new(),
-            interaction_history: Vec::new(),
-            session_start: now,
-            last_activity: now,
-            request_count: 0,
-        }
-    }
-    
-    fn update_activity(&mut self, url: &str) {
-        self.last_activity = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        self.request_count += 1;
-        if !self.visited_urls.contains(&url.to_string()) {
-            self.visited_urls.push(url.to_string());
-        }
-    }
-    
-    fn get_session_duration(&self) -> u64 {
-        self.last_activity - self.session_start
-    }
-}
-
-// Global session storage
 // Advanced session storage
-type SessionStorage = Arc<Mutex<HashMap<String, Arc<Mutex<AdvancedSession>>>>>;
-
-fn get_session_id(req: &HttpRequest) -> String {
-    // Create session ID based on client IP and some randomization
-    let client_ip = req.connection_info().realip_remote_addr()
-        .unwrap_or("127.0.0.1").to_string();
-    
-    // In a real implementation, you'd use proper session management
-    format!("session_{}", general_purpose::STANDARD.encode(client_ip))
-}
-
-fn get_or_create_session(session_storage: &SessionStorage, session_id: &str) -> Arc<Mutex<AdvancedSession>> {
-    get_or_create_advanced_session(session_storage, session_id)
-}
-
-fn update_session(session_storage: &SessionStorage, session_id: &str, session: Arc<Mutex<AdvancedSession>>, url: &str) {
-    update_advanced_session(session_storage, session_id, &session, url);
-}
-
-fn get_realistic_user_agent(_url: &str) -> &'static str {
-    // This is now handled by BrowserFingerprint, but keeping for compatibility
-    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"
-}
-
-fn generate_google_specific_headers(session: &BrowserSession, url: &str, referer: Option<&str>) -> Vec<(String, String)> {
-    let mut headers = Vec::new();
-    let fp = &session.fingerprint;
-    
-    // Header order is critical - must match real Chrome exactly
-    headers.push(("Accept".to_string(), 
-        "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7".to_string()));
-    
-    headers.push(("Accept-Encoding".to_string(), "gzip, deflate, br".to_string()));
-    headers.push(("Accept-Language".to_string(), "en-US,en;q=0.9".to_string()));
-    
-    // Critical Chrome client hints - Google checks these extensively
-    headers.push(("Sec-Ch-Ua".to_string(), fp.sec_ch_ua.clone()));
-    headers.push(("Sec-Ch-Ua-Mobile".to_string(), fp.sec_ch_ua_mobile.clone()));
-    headers.push(("Sec-Ch-Ua-Platform".to_string(), fp.sec_ch_ua_platform.clone()));
-    
-    // Always include these for Google
-    headers.push(("Sec-Fetch-Dest".to_string(), "document".to_string()));
-    headers.push(("Sec-Fetch-Mode".to_string(), "navigate".to_string()));
-    headers.push(("Sec-Fetch-Site".to_string(), if referer.is_some() { "same-origin" } else { "none" }.to_string()));
-    headers.push(("Sec-Fetch-User".to_string(), "?1".to_string()));
-    
-    headers.push(("Upgrade-Insecure-Requests".to_string(), "1".to_string()));
-    headers.push(("User-Agent".to_string(), fp.user_agent.clone()));
-    
-    // Add referer if provided
-    if let Some(ref_url) = referer {
-        headers.push(("Referer".to_string(), ref_url.to_string()));
-    }
-    
-    // Google-specific client data that real Chrome sends
-    if url.contains("google.") {
-        let client_data = generate_google_client_data(&fp);
-        headers.push(("X-Client-Data".to_string(), client_data));
-        
-        // Additional Google-specific headers based on session history
-        if session.visited_urls.iter().any(|u| u.contains("google.")) {
-            headers.push(("X-Same-Domain".to_string(), "1".to_string()));
-        }
-        
-        // Simulate browser cache behavior
-        if session.request_count > 1 {
-            headers.push(("Cache-Control".to_string(), "max-age=0".to_string()));
-        }
-    }
-    
-    headers
-}
+type SessionStorage = Arc<session_map::SessionMap<Mutex<AdvancedSession>>>;
 
-fn generate_google_client_data(fp: &BrowserFingerprint) -> String {
-    // This generates a realistic X-Client-Data header that Chrome sends to Google
-    // The format is base64 encoded protobuf data
-    
-    // Simulate Chrome's client data with realistic values
-    let mut rng = thread_rng();
-    let chrome_version: u32 = fp.user_agent.split("Chrome/").nth(1)
-        .and_then(|s| s.split('.').next())
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(120);
-    
-    // Generate realistic encoded client data
-    // This is a simplified version - real Chrome client data is more complex
-    let client_data_raw = format!(
-        "{}:{}:{}:{}:{}",
-        chrome_version,
-        rng.gen_range(1000..9999),
-        fp.viewport_width,
-        fp.viewport_height,
-        rng.gen_range(100..999)
-    );
-    
-    general_purpose::STANDARD.encode(client_data_raw.as_bytes())
+fn get_session_id(req: &HttpRequest, config: &ProxyConfig) -> String {
+    // Forwarding headers only count from trusted proxies when falling back to the client address
+    let client_ip = forwarding::client_ip(req, &config.forwarding);
+    let tokens = req.app_data::<web::Data<SessionTokens>>().expect("session tokens are registered with the app");
+    tokens.session_id(req, config.session.ip_fallback, client_ip)
 }
 
-fn get_realistic_headers(url: &str, method: &str) -> Vec<(&'static str, String)> {
-    // This is now deprecated in favor of generate_google_specific_headers
-    // but keeping for backward compatibility
-    let mut headers = Vec::new();
-    
-    headers.push(("Accept", if method == "GET" {
-        "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"
-    } else {
-        "application/json, text/plain, */*"
-    }.to_string()));
-    
-    headers.push(("Accept-Language", "en-US,en;q=0.9".to_string()));
-    headers.push(("Accept-Encoding", "gzip, deflate, br".to_string()));
-    headers.push(("User-Agent", get_realistic_user_agent(url).to_string()));
-    
-    headers
+async fn get_public_ip(port: u16) -> String {
+    // Looked up once; the services are slow and the address rarely changes
+    static PUBLIC_IP: tokio::sync::OnceCell<Option<String>> = tokio::sync::OnceCell::const_new();
+    let ip = PUBLIC_IP.get_or_init(lookup_public_ip).await;
+    format!("{}:{}", ip.as_deref().unwrap_or("unknown"), port)
 }
 
-fn is_hop_by_hop_header(name: &str) -> bool {
-    matches!(name, 
-        "connection" | "proxy-connection" | "te" | "trailer" | 
-        "transfer-encoding" | "upgrade" | "keep-alive" | "proxy-authenticate" |
-        "proxy-authorization"
-    )
-}
-
-fn is_already_set_header(name: &str) -> bool {
-    matches!(name,
-        "host" | "content-length" | "accept" | "accept-language" | 
-        "accept-encoding" | "user-agent" | "sec-fetch-dest" | 
-        "sec-fetch-mode" | "sec-fetch-site" | "sec-fetch-user" | 
-        "upgrade-insecure-requests" | "dnt" | "cache-control" | 
-        "x-forwarded-for" | "x-real-ip" | "x-forwarded-proto" |
-        "sec-ch-ua" | "sec-ch-ua-mobile" | "sec-ch-ua-platform" |
-        "sec-ch-ua-arch" | "sec-ch-ua-bitness" | "sec-ch-ua-full-version-list" |
-        "sec-ch-ua-wow64" | "sec-ch-ua-model" | "x-client-data" |
-        "sec-ch-viewport-width" | "sec-ch-viewport-height" | "sec-ch-device-memory" |
-        "sec-ch-prefers-color-scheme" | "sec-ch-prefers-reduced-motion" | "referer"
-    )
-}
-
-// Create a persistent session for each client to maintain cookies and state
-struct Session {
-    client: Client,
-    cookies: Arc<Jar>,
-    visit_count: u32,
-    last_visit: std::time::Instant,
-}
-
-impl Session {
-    fn new() -> Self {
-        let cookies = Arc::new(Jar::default());
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .cookie_provider(cookies.clone())
-            .danger_accept_invalid_certs(false)
-            .tcp_keepalive(Duration::from_secs(60))
-            .pool_idle_timeout(Duration::from_secs(90))
-            .pool_max_idle_per_host(10)
-            .http1_only() // Force HTTP/1.1 to avoid HTTP/2 issues with bot detection
-            .build()
-            .unwrap();
-            
-        Self {
-            client,
-            cookies,
-            visit_count: 0,
-            last_visit: std::time::Instant::now(),
-        }
-    }
-    
-    fn update_visit(&mut self) {
-        self.visit_count += 1;
-        self.last_visit = std::time::Instant::now();
-    }
-}
-
-async fn prefetch_google_homepage(client: &Client) -> Result<(), reqwest::Error> {
-    // Some browsers prefetch the homepage before making search requests
-    let _response = client
-        .get("https://www.google.com/")
-        .header("User-Agent", get_realistic_user_agent("https://www.google.com/"))
-        .header("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8")
-        .header("Accept-Language", "en-US,en;q=0.9")
-        .header("sec-ch-ua", "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\"")
-        .header("sec-ch-ua-mobile", "?0")
-        .header("sec-ch-ua-platform", "\"Windows\"")
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await?;
-    
-    // Small delay after prefetch
-    tokio::time::sleep(Duration::from_millis(200)).await;
-    Ok(())
-}
-
-async fn simulate_google_browsing_behavior(session: &BrowserSession, target_url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create client using session's cookies
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .cookie_provider(session.cookies.clone())
-        .http1_only()
-        .build()?;
-    
-    println!("Simulating realistic Google browsing behavior");
-    
-    // Step 1: Visit Google homepage first if we haven't been to Google recently
-    if !session.visited_urls.iter().any(|u| u.contains("google.")) {
-        println!("First visit to Google - loading homepage");
-        
-        let homepage_headers = generate_google_specific_headers(session, "https://www.google.com/", None);
-        let mut homepage_request = client.get("https://www.google.com/");
-        
-        for (name, value) in homepage_headers {
-            homepage_request = homepage_request.header(&name, &value);
-        }
-        
-        let _ = homepage_request.send().await;
-        
-        // Human-like delay after loading homepage
-        tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(1500..3000))).await;
-        
-        // Load some typical resources that a real browser would load
-        let resources = [
-            "/favicon.ico",
-            "/images/branding/googleg/1x/googleg_standard_color_128dp.png",
-        ];
-        
-        for resource in &resources {
-            let resource_url = format!("https://www.google.com{}", resource);
-            let resource_headers = generate_google_specific_headers(session, &resource_url, Some("https://www.google.com/"));
-            let mut resource_request = client.get(&resource_url);
-            
-            for (name, value) in resource_headers {
-                resource_request = resource_request.header(&name, &value);
-            }
-            
-            let _ = resource_request.send().await;
-            tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(100..500))).await;
-        }
-    }
-    
-    // Step 2: If this is a search, simulate typing delay and suggestion fetching
-    if target_url.contains("/search?q=") {
-        // Extract search query
-        if let Some(query_start) = target_url.find("q=") {
-            let query_part = &target_url[query_start + 2..];
-            let query = query_part.split('&').next().unwrap_or("");
-            
-            if !query.is_empty() {
-                println!("Simulating search query typing for: {}", query);
-                
-                // Simulate Google search suggestions being fetched as user types
-                let decoded_query = urlencoding::decode(query).unwrap_or_default();
-                let query_chars: Vec<char> = decoded_query.chars().collect();
-                
-                // Simulate typing with suggestions (Google's complete/search endpoint)
-                for i in 1..=query_chars.len() {
-                    if i > 2 && i % 2 == 0 { // Don't fetch for every character, be realistic
-                        let partial_query: String = query_chars[..i].iter().collect();
-                        let suggestion_url = format!(
-                            "https://www.google.com/complete/search?q={}&client=gws-wiz&xssi=t&gs_ri=gws-wiz&hl=en&authuser=0",
-                            urlencoding::encode(&partial_query)
-                        );
-                        
-                        let suggestion_headers = generate_google_specific_headers(session, &suggestion_url, Some("https://www.google.com/"));
-                        let mut suggestion_request = client.get(&suggestion_url);
-                        
-                        for (name, value) in suggestion_headers {
-                            suggestion_request = suggestion_request.header(&name, &value);
-                        }
-                        
-                        let _ = suggestion_request.send().await;
-                        
-                        // Realistic typing speed delay
-                        tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(150..400))).await;
-                    }
-                }
-                
-                // Final pause before submitting search
-                tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(500..1500))).await;
-            }
-        }
-    }
-    
-    Ok(())
-}
-
-async fn simulate_browsing_behavior(client: &Client, target_url: &str) -> Result<(), reqwest::Error> {
-    // Simulate a more realistic browsing pattern for Google
-    if target_url.contains("google.com") {
-        // First, visit the homepage
-        let _homepage = client
-            .get("https://www.google.com/")
-            .header("User-Agent", get_realistic_user_agent("https://www.google.com/"))
-            .header("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8")
-            .header("Accept-Language", "en-US,en;q=0.9")
-            .header("sec-ch-ua", "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\", \"Google Chrome\";v=\"120\"")
-            .header("sec-ch-ua-mobile", "?0")
-            .header("sec-ch-ua-platform", "\"Windows\"")
-            .header("Sec-Fetch-Dest", "document")
-            .header("Sec-Fetch-Mode", "navigate")
-            .header("Sec-Fetch-Site", "none")
-            .header("Sec-Fetch-User", "?1")
-            .timeout(Duration::from_secs(10))
-            .send()
-            .await?;
-        
-        // Human-like delay
-        tokio::time::sleep(Duration::from_millis(800 + rand::thread_rng().gen_range(200..800))).await;
-        
-        // Simulate loading Google's static resources (JavaScript, CSS)
-        let resources = [
-            "https://www.google.com/xjs/_/js/",
-            "https://www.google.com/textinputassistant/",
-            "https://ssl.gstatic.com/ui/v1/",
-        ];
-        
-        for resource in &resources[0..1] { // Just load one to avoid too many requests
-            let _res = client
-                .get(*resource)
-                .header("User-Agent", get_realistic_user_agent("https://www.google.com/"))
-                .header("Referer", "https://www.google.com/")
-                .header("Accept", "*/*")
-                .header("Sec-Fetch-Dest", "script")
-                .header("Sec-Fetch-Mode", "no-cors")
-                .header("Sec-Fetch-Site", "same-origin")
-                .timeout(Duration::from_secs(5))
-                .send()
-                .await; // Ignore errors for resource loading
-            
-            tokio::time::sleep(Duration::from_millis(100 + rand::thread_rng().gen_range(50..200))).await;
-        }
-    }
-    
-    Ok(())
-}
-
-async fn get_public_ip() -> String {
+async fn lookup_public_ip() -> Option<String> {
     // Try to get the public IP address using external services
     let services = [
         "https://api.ipify.org",
@@ -471,17 +140,17 @@ async fn get_public_ip() -> String {
         .unwrap();
     
     for service in &services {
-        if let Ok(response) = client.get(*service).send().await {
-            if let Ok(ip) = response.text().await {
-                let ip = ip.trim();
-                if !ip.is_empty() && ip.parse::<std::net::IpAddr>().is_ok() {
-                    return format!("{}:8888", ip);
-                }
+        if let Ok(response) = client.get(*service).send().await
+            && let Ok(ip) = response.text().await
+        {
+            let ip = ip.trim();
+            if !ip.is_empty() && ip.parse::<std::net::IpAddr>().is_ok() {
+                return Some(ip.to_string());
             }
         }
     }
     
-    "unknown:8888".to_string() // Fallback if all services fail
+    None // Fallback if all services fail
 }
 
 #[derive(Deserialize)]
@@ -489,12 +158,37 @@ struct ProxyQuery {
     url: String,
 }
 
-// PQC-related structs for existing handlers
+/// Cookie export and import format: a JSON array (default) or a Netscape `cookies.txt` file
+#[derive(Deserialize)]
+struct CookieFormatQuery {
+    #[serde(default)]
+    format: CookieFormat,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum CookieFormat {
+    #[default]
+    Json,
+    Netscape,
+}
+
+// Schemas of the JSON-envelope endpoints (`/proxy-legacy`, `/pqc-proxy`). Requests pick
+// the response schema with `version`; requests without one get version 1.
+const ENVELOPE_VERSIONS: [u8; 2] = [1, 2];
+
+fn default_envelope_version() -> u8 {
+    1
+}
+
 #[derive(Deserialize)]
 struct ProxyRequest {
+    #[serde(default = "default_envelope_version")]
+    version: u8,
     url: String,
 }
 
+/// Version 1 response, with links in HTML made absolute
 #[derive(Serialize)]
 struct ProxyResponse {
     html: String,
@@ -504,10 +198,41 @@ struct ProxyResponse {
     pqc_public_keys: PqcPublicKeys,
 }
 
+/// Version 2 response, with links in HTML rewritten per the server's HTML setting
+#[derive(Serialize)]
+struct ProxyResponseV2 {
+    version: u8,
+    /// Final URL, after redirects
+    url: String,
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+    /// Whether `body` is encrypted with the PQC session's shared secret
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pqc_session: Option<PqcSessionInfo>,
+    pqc_public_keys: PqcPublicKeys,
+}
+
+#[derive(Serialize)]
+struct PqcSessionInfo {
+    session_id: String,
+    /// Kyber ciphertext to decapsulate for the shared secret, when this request created the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ciphertext: Option<String>,
+    /// Dilithium signature over the shared secret, when this request created the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct PqcProxyRequest {
+    #[serde(default = "default_envelope_version")]
+    version: u8,
     url: String,
-    pqc_session: Option<pqc::PqcSharedData>,
+    /// Session from `/pqc_handshake` or an earlier `/pqc-proxy` call whose secret encrypts the body
+    session_id: Option<String>,
+    /// Keys to establish a new session with, when no `session_id` is given
     peer_public_keys: Option<PqcPublicKeys>,
 }
 
@@ -520,13 +245,18 @@ struct PqcPublicKeys {
 
 #[derive(Serialize)]
 struct PqcResponse {
+    /// Refers to the established secret in later `/pqc-proxy` requests
+    session_id: String,
     session_data: pqc::PqcSharedData,
     public_keys: PqcPublicKeys,
 }
 
+// Keys loaded from the configured key file, set before the server starts
+static PQC_KEYS: OnceLock<PqcKeyPair> = OnceLock::new();
+
 // Global PQC instance (in production, you'd want proper state management)
 lazy_static::lazy_static! {
-    static ref PQC_INSTANCE: PqcCrypto = PqcCrypto::new();
+    static ref PQC_INSTANCE: PqcCrypto = PQC_KEYS.get().cloned().map_or_else(PqcCrypto::new, PqcCrypto::from_keys);
 }
 
 fn generate_session_id() -> String {
@@ -536,7 +266,13 @@ fn generate_session_id() -> String {
     format!("pqc_session_{}", session_id)
 }
 
-async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>, session_storage: web::Data<SessionStorage>) -> Result<HttpResponse> {
+#[allow(clippy::too_many_arguments)]
+async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>, session_storage: web::Data<SessionStorage>, config: web::Data<ProxyConfig>, domain_policy: web::Data<PolicyStore>, rate_limiter: web::Data<Arc<RateLimiter>>, authenticator: web::Data<Authenticator>, metrics: web::Data<Metrics>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Proxy, true) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+
     // Validate URL parameter
     if query.url.is_empty() {
         return Ok(HttpResponse::BadRequest().json(serde_json::json!({
@@ -544,31 +280,53 @@ async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>
         })));
     }
 
+    // Refuse denied and internal destinations before doing anything else
+    let target = match url::Url::parse(&query.url) {
+        Ok(target) => target,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid URL: {}", e)
+            })));
+        }
+    };
+    if let Err(rule) = domain_policy.check(&target) {
+        return Ok(denied_by_policy(&query.url, &rule));
+    }
+    if let Err(blocked) = config.ssrf.check_url(&target).await {
+        return Ok(forbidden_destination(&query.url, &blocked.0));
+    }
+
     // Get session ID and advanced session
-    let session_id = get_session_id(&req);
+    let client_ip = forwarding::client_ip(&req, &config.forwarding);
+    let session_id = get_session_id(&req, &config);
+
+    // Held until the response (including a streamed body) is finished
+    let target_host = target.host_str().map(str::to_ascii_lowercase).unwrap_or_default();
+    telemetry::record_target(&req, &session_id, &query.url, &target_host);
+    let permit = match rate_limiter.acquire(&client_ip.to_string(), &session_id, &target_host) {
+        Ok(permit) => permit,
+        Err(rejection) => return Ok(too_many_requests(&client_ip.to_string(), &rejection)),
+    };
+
     let session = get_or_create_advanced_session(&session_storage, &session_id);
     
-    println!("Proxying {} request to: {}", req.method(), query.url);
+    tracing::info!(url = %query.url, client = %client_ip, subject = %identity.subject, "proxying request");
 
     // For Google requests, use advanced anti-bot techniques
-    let is_google_search = query.url.contains("google.") && query.url.contains("/search");
-    let is_google_request = query.url.contains("google.");
+    let is_google_request = config.google_anti_bot && query.url.contains("google.");
     
     if is_google_request {
-        println!("🎯 Using advanced Google anti-bot techniques");
+        tracing::debug!("using Google anti-bot techniques");
         
         // Check if session needs cooling off
-        {
-            let session_guard = session.lock().unwrap();
-            if session_guard.needs_cooling_off() {
-                println!("❄️ Session needs cooling off - adding extra delay");
-                drop(session_guard);
-                tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(5000..15000))).await;
-            }
+        let needs_cooling_off = session.lock().unwrap().needs_cooling_off();
+        if needs_cooling_off {
+            tracing::info!("session needs cooling off, adding extra delay");
+            tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(5000..15000))).await;
         }
         
         // Simulate realistic pre-search behavior
-        let _ = simulate_advanced_browsing_behavior(&session, &query.url).await;
+        let _ = simulate_advanced_browsing_behavior(&session, &query.url, &config.timeouts).await;
         
         // Smart delay to avoid detection
         smart_delay(&session).await;
@@ -577,20 +335,32 @@ async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>
     // Determine if this should be a mobile request (randomly for variety)
     let is_mobile = rand::thread_rng().gen_bool(0.3);
 
+    // Either follow redirects here or hand them back to the client for rewriting
+    // (each followed hop is checked against the domain and SSRF policies again)
+    let ssrf_policy = Arc::new(config.ssrf.clone());
+    let redirect_policy = match config.redirect_mode {
+        RedirectMode::Follow => guarded_redirect_policy(config.max_redirects, ssrf_policy.clone(), domain_policy.clone().into_inner()),
+        RedirectMode::Passthrough => reqwest::redirect::Policy::none(),
+    };
+
     // Create client with session's cookie jar and advanced settings
     let client = {
         let session_guard = session.lock().unwrap();
         Client::builder()
-            .timeout(Duration::from_secs(45))
+            .timeout(Duration::from_secs(config.timeouts.proxy_secs))
             .cookie_provider(session_guard.cookies.clone())
+            .redirect(redirect_policy)
+            // Names are re-checked on the addresses actually connected to, defeating DNS rebinding
+            .dns_resolver(Arc::new(ssrf::GuardedResolver::new(ssrf_policy)))
             .danger_accept_invalid_certs(false)
-            .tcp_keepalive(Duration::from_secs(60))
-            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(config.timeouts.tcp_keepalive_secs))
+            .pool_idle_timeout(Duration::from_secs(config.timeouts.pool_idle_secs))
             .pool_max_idle_per_host(10)
             .http1_only() // Force HTTP/1.1 to avoid HTTP/2 fingerprinting
-            .gzip(true)
-            .brotli(true)
-            .deflate(true)
+            // Bodies are decoded in the handler, only when they need rewriting
+            .gzip(false)
+            .brotli(false)
+            .deflate(false)
             .build()
             .unwrap()
     };
@@ -612,24 +382,59 @@ async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>
         generate_realistic_headers_v2(&session_guard, &query.url, is_mobile)
     };
     
+    // The client's own headers (content type, auth, conditionals, ranges, ...) pass through
+    // according to the forwarding policy
+    let mut forwarded_headers = headers::forwarded_request_headers(req.headers(), &config.header_policy, config.translate_cookies);
+    // A credential meant for us must never reach the upstream
+    if identity.source == Some(auth::CredentialSource::Authorization) {
+        forwarded_headers.retain(|(name, _)| name != "authorization");
+    }
+    // ... and neither must our session token
+    forwarded_headers.retain_mut(|(name, value)| match name.as_str() {
+        session_token::HEADER => false,
+        "cookie" => session_token::strip_cookie(value).map(|rest| *value = rest).is_some(),
+        _ => true,
+    });
+    
+    // Byte ranges apply to the identity representation, so range requests ask for it
+    // uncompressed and the client gets exactly the bytes it asked for
+    let is_range_request = req.headers().contains_key("range");
+    
     // Add headers in the exact order they appear in real browsers
     for (name, value) in headers_to_use {
+        // Explicitly allowed client headers replace the generated ones
+        if forwarded_headers.iter().any(|(forwarded, _)| forwarded.eq_ignore_ascii_case(&name)) {
+            continue;
+        }
+        if is_range_request && name.eq_ignore_ascii_case("accept-encoding") {
+            request_builder = request_builder.header(&name, "identity");
+            continue;
+        }
         request_builder = request_builder.header(&name, &value);
     }
+    
+    for (name, value) in forwarded_headers {
+        request_builder = request_builder.header(name, value);
+    }
 
-    // Get client IP for forwarding with advanced masking
-    let client_ip = req.connection_info().realip_remote_addr()
-        .map(|addr| addr.split(':').next().unwrap_or("127.0.0.1"))
-        .unwrap_or("127.0.0.1")
-        .to_string();
-
-    // Generate more realistic forwarded IP
-    let forwarded_ip = generate_realistic_forwarded_ip(&client_ip);
+    // Forwarding headers according to the configured mode (none at all when anonymous)
+    for (name, value) in forwarding::upstream_headers(&req, &config.forwarding) {
+        request_builder = request_builder.header(name, value);
+    }
 
-    request_builder = request_builder
-        .header("X-Forwarded-For", &forwarded_ip)
-        .header("X-Real-IP", &forwarded_ip)
-        .header("X-Forwarded-Proto", if query.url.starts_with("https") { "https" } else { "http" });
+    // Map the browser's namespaced cookies for this site back to their upstream names
+    if config.translate_cookies
+        && let Some(cookie_header) = req.headers().get("cookie").and_then(|v| v.to_str().ok())
+    {
+        let browser_cookies = cookies::upstream_cookies(cookie_header, &target);
+        if !browser_cookies.is_empty() {
+            // An explicit Cookie header replaces the jar's, so merge the two
+            let jar_cookies = session.lock().unwrap().cookies.cookies(&target);
+            let jar_cookies = jar_cookies.as_ref().and_then(|v| v.to_str().ok());
+            let merged = cookies::merge_cookie_header(jar_cookies, &browser_cookies);
+            request_builder = request_builder.header("Cookie", merged);
+        }
+    }
 
     // Add request body if present
     if !body.is_empty() {
@@ -647,102 +452,212 @@ async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>
     let max_retries = if is_google_request { 3 } else { 1 };
     
     loop {
+        let started = Instant::now();
         let response_result = request_builder.try_clone().unwrap().send().await;
+        metrics.observe_upstream(metrics::Upstream::Proxy, started.elapsed());
         
         match response_result {
             Ok(response) => {
                 let status = response.status();
                 let headers = response.headers().clone();
+                let final_url = response.url().clone();
+                telemetry::record_upstream_status(&req, status.as_u16());
+                
+                tracing::debug!(upstream_status = status.as_u16(), url = %query.url, "upstream responded");
+                
+                let content_type = headers.get("content-type")
+                    .and_then(|ct| ct.to_str().ok())
+                    .unwrap_or("")
+                    .to_ascii_lowercase();
+                let is_html = content_type.contains("text/html");
                 
-                println!("Response status: {} for {}", status, query.url);
+                // Partial content must reach the client byte-for-byte, so it is never decoded or rewritten
+                let is_partial = is_range_request || status.as_u16() == 206;
+                // Nor is a response without a body, whose headers describe a representation we never see
+                let has_body = req.method() != actix_web::http::Method::HEAD
+                    && !matches!(status.as_u16(), 204 | 304);
+                let rewrite_mode = if is_partial || !has_body {
+                    None
+                } else if is_html {
+                    config.rewrite_html
+                } else if content_type.contains("text/css") {
+                    config.rewrite_css
+                } else {
+                    None
+                };
+                
+                let upstream_encoding = encoding::Encoding::from_header(
+                    headers.get("content-encoding").and_then(|v| v.to_str().ok())
+                );
+                let client_accept_encoding = req.headers().get("accept-encoding").and_then(|v| v.to_str().ok());
+                let needs_decoding = !is_partial
+                    && has_body
+                    && upstream_encoding.is_some_and(|enc| !encoding::accepts(client_accept_encoding, enc));
+                let secure_client = forwarding::is_tls(&req);
                 
-                // Get response body
-                let body_bytes = match response.bytes().await {
+                // Anything we don't need to look inside is streamed straight through, which keeps
+                // large downloads and media seeking out of memory
+                if has_body && rewrite_mode.is_none() && !needs_decoding && !is_google_request {
+                    update_advanced_session(&session, &query.url, &config.session);
+                    log_upstream_status(status);
+                    
+                    let mut response_builder = HttpResponse::build(
+                        actix_web::http::StatusCode::from_u16(status.as_u16()).unwrap()
+                    );
+                    forward_response_headers(&mut response_builder, &headers, &final_url, &config, secure_client, false);
+                    
+                    let content_length = headers.get("content-length")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u64>().ok());
+                    tracing::debug!(content_length, "streaming response body");
+                    
+                    // The stream carries the permit so the concurrency slot is held until the body is sent
+                    let stream_metrics = metrics.clone();
+                    let stream = response.bytes_stream().map(move |chunk| {
+                        let _ = &permit;
+                        if let Ok(bytes) = &chunk {
+                            stream_metrics.bytes_in(bytes.len());
+                            stream_metrics.bytes_out(bytes.len());
+                        }
+                        chunk
+                    });
+                    return Ok(match content_length {
+                        Some(len) => response_builder.body(actix_web::body::SizedStream::new(len, stream)),
+                        None => response_builder.streaming(stream),
+                    });
+                }
+                
+                // Get response body, still in the upstream's content encoding
+                let raw_body = match response.bytes().await {
                     Ok(bytes) => bytes,
                     Err(e) => {
-                        println!("Failed to read response body: {}", e);
+                        tracing::warn!(error = %e, "failed to read response body");
                         return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
                             "error": format!("Failed to read response body: {}", e)
                         })));
                     }
                 };
+                metrics.bytes_in(raw_body.len());
 
-                let body_str = String::from_utf8_lossy(&body_bytes);
+                // Only decode when we need to look inside the body or the client can't take the upstream coding
+                let decoded_body = match upstream_encoding {
+                    Some(enc) if !is_partial && has_body && (rewrite_mode.is_some() || is_google_request || needs_decoding) => {
+                        match encoding::decode(&raw_body, enc) {
+                            Ok(decoded) => Some(decoded),
+                            Err(e) => {
+                                tracing::warn!(encoding = ?enc, error = %e, "failed to decode response body");
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                
+                let body_str = String::from_utf8_lossy(decoded_body.as_deref().unwrap_or(&raw_body));
 
                 // Check for anti-bot responses
                 if is_google_request && handle_anti_bot_response(&body_str, &session) {
                     if retry_count < max_retries {
                         retry_count += 1;
-                        println!("🔄 Retry attempt {} for anti-bot response", retry_count);
+                        tracing::info!(attempt = retry_count, "retrying after anti-bot response");
                         
                         // Longer delay before retry
                         tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(3000..8000))).await;
                         continue;
                     } else {
-                        println!("❌ Max retries reached for anti-bot response");
+                        tracing::warn!("max retries reached for anti-bot response");
                     }
                 }
 
                 // Update session with this activity
-                update_advanced_session(&session_storage, &session_id, &session, &query.url);
+                update_advanced_session(&session, &query.url, &config.session);
                 
                 // Log response details
-                if status.as_u16() == 429 || status.as_u16() == 403 {
-                    println!("⚠️  Potential bot detection: status {}", status);
-                } else if status.is_success() {
-                    println!("✅ Request successful: status {}", status);
-                } else {
-                    println!("⚠️  Unexpected status: {}", status);
-                }
+                log_upstream_status(status);
                 
                 // Check for Google's specific responses
-                if query.url.contains("google.com") {
-                    if let Some(content_type) = headers.get("content-type") {
-                        if let Ok(ct) = content_type.to_str() {
-                            if ct.contains("text/html") {
-                                if body_str.contains("<h3") {
-                                    println!("🎉 Found search result headers (h3 tags)!");
-                                } else if body_str.contains("javascript") || body_str.contains("click here") {
-                                    println!("🤖 Likely bot detection page (contains JavaScript requirements)");
-                                } else {
-                                    println!("❓ Unknown HTML response type");
-                                }
+                if query.url.contains("google.com") && is_html {
+                    if body_str.contains("<h3") {
+                        tracing::debug!("found search result headers");
+                    } else if body_str.contains("javascript") || body_str.contains("click here") {
+                        tracing::debug!("likely bot detection page");
+                    } else {
+                        tracing::debug!("unrecognised HTML response");
+                    }
+                }
+                
+                // Point links in HTML pages and stylesheets back at the origin or through the proxy
+                let rewritten = match (rewrite_mode, decoded_body.as_deref()) {
+                    (Some(mode), Some(decoded)) if is_html => {
+                        let mut rewriter = html_rewriter::HtmlRewriter::new(final_url.clone(), mode);
+                        let mut rewritten = rewriter.write(decoded);
+                        rewritten.extend(rewriter.finish());
+                        Some(rewritten)
+                    }
+                    (Some(mode), Some(decoded)) => std::str::from_utf8(decoded)
+                        .ok()
+                        .map(|css| css_rewriter::rewrite_css(css, &final_url, mode).into_bytes()),
+                    _ if needs_decoding => decoded_body.clone(),
+                    _ => None,
+                };
+                
+                // Bodies we touched are re-encoded for the client; everything else passes through as sent
+                let body_transformed = rewritten.is_some();
+                let (body_bytes, client_encoding) = match rewritten {
+                    Some(body) => {
+                        let target = if config.recompress && encoding::is_compressible(&content_type) {
+                            encoding::negotiate(client_accept_encoding)
+                        } else {
+                            encoding::Encoding::Identity
+                        };
+                        match encoding::encode(&body, target) {
+                            Ok(encoded) => (web::Bytes::from(encoded), target),
+                            Err(e) => {
+                                tracing::warn!(encoding = ?target, error = %e, "failed to re-encode response body");
+                                (web::Bytes::from(body), encoding::Encoding::Identity)
                             }
                         }
                     }
-                }
+                    None => (raw_body, encoding::Encoding::Identity),
+                };
                 
                 // Create response builder with the same status
                 let mut response_builder = HttpResponse::build(
                     actix_web::http::StatusCode::from_u16(status.as_u16()).unwrap()
                 );
+                forward_response_headers(&mut response_builder, &headers, &final_url, &config, secure_client, body_transformed);
 
-                // Forward response headers (excluding hop-by-hop headers)
-                for (header_name, header_value) in &headers {
-                    let name = header_name.as_str().to_lowercase();
-                    
-                    // Skip hop-by-hop headers and headers that actix-web manages
-                    if !matches!(name.as_str(),
-                        "connection" | "proxy-connection" | "te" | "trailer" | 
-                        "transfer-encoding" | "upgrade" | "content-encoding"
-                    ) {
-                        if let Ok(value) = header_value.to_str() {
-                            response_builder.insert_header((header_name.as_str(), value));
-                        }
-                    }
+                if let Some(coding) = client_encoding.header_value() {
+                    response_builder.insert_header(("Content-Encoding", coding));
+                    response_builder.append_header(("Vary", "Accept-Encoding"));
                 }
 
-                println!("Response body length: {} bytes", body_bytes.len());
+                // A HEAD answer keeps the length of the body a GET would have returned
+                let head_length = headers.get("content-length")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .filter(|_| req.method() == actix_web::http::Method::HEAD);
+                if let Some(len) = head_length {
+                    let empty = futures_util::stream::empty::<Result<web::Bytes, std::io::Error>>();
+                    return Ok(response_builder.body(actix_web::body::SizedStream::new(len, empty)));
+                }
+
+                tracing::debug!(bytes = body_bytes.len(), "sending response body");
+                metrics.bytes_out(body_bytes.len());
                 return Ok(response_builder.body(body_bytes));
             }
             Err(e) => {
+                metrics.upstream_error(&e);
+                if let Some(reason) = ssrf::blocked_reason(&e) {
+                    return Ok(forbidden_destination(&query.url, &reason));
+                }
                 if retry_count < max_retries && is_google_request {
                     retry_count += 1;
-                    println!("🔄 Network retry attempt {} due to error: {}", retry_count, e);
+                    tracing::info!(attempt = retry_count, error = %e, "retrying after network error");
                     tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(2000..5000))).await;
                     continue;
                 } else {
-                    println!("Failed to proxy request: {}", e);
+                    tracing::warn!(error = %e, "failed to proxy request");
                     return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
                         "error": format!("Failed to proxy request: {}", e)
                     })));
@@ -752,242 +667,453 @@ async fn proxy(req: HttpRequest, body: web::Bytes, query: web::Query<ProxyQuery>
     }
 }
 
-async fn proxy_handler(req: web::Json<ProxyRequest>) -> Result<HttpResponse> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-        .redirect(reqwest::redirect::Policy::limited(10))
-        .build()
-        .unwrap();
+/// Follow up to `max_redirects` hops, refusing any hop the domain or SSRF policy rejects
+fn guarded_redirect_policy(max_redirects: usize, ssrf_policy: Arc<ssrf::SsrfPolicy>, domain_policy: Arc<PolicyStore>) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error("too many redirects");
+        }
+        if let Err(rule) = domain_policy.check(attempt.url()) {
+            let reason = format!("redirect to {} denied by policy rule {}", attempt.url(), rule);
+            return attempt.error(ssrf::Blocked(reason));
+        }
+        match ssrf_policy.check_redirect(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(blocked) => attempt.error(blocked),
+        }
+    })
+}
 
-    println!("Fetching URL: {}", req.url);
+/// Authenticate the request for `scope`, or build the error response.
+///
+/// `/proxy` behaves like a forward proxy and challenges with `407` and `Proxy-Authenticate`;
+/// the other endpoints answer `401` with `WWW-Authenticate`. Valid credentials lacking the
+/// scope get `403`.
+fn authorize(req: &HttpRequest, authenticator: &Authenticator, scope: Scope, proxy_challenge: bool) -> std::result::Result<auth::Identity, HttpResponse> {
+    let identity = authenticator
+        .authorize(req, scope, &PQC_INSTANCE)
+        .map_err(|error| auth_error_response(req, error, proxy_challenge))?;
+    if identity.source.is_some() {
+        telemetry::record_subject(req, &identity.subject);
+    }
+    Ok(identity)
+}
 
-    match client.get(&req.url)
-        .header("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8")
-        .header("Accept-Language", "en-US,en;q=0.5")
-        .header("DNT", "1")
-        .header("Connection", "keep-alive")
-        .header("Upgrade-Insecure-Requests", "1")
-        .send()
-        .await {
-        Ok(response) => {
-            let status = response.status().as_u16();
-            let headers = response.headers().clone();
-            
-            println!("Response status: {}", status);
-            println!("Content-Type: {:?}", headers.get("content-type"));
-            
-            match response.text().await {
-                Ok(mut html) => {
-                    // Fix relative URLs to absolute URLs
-                    let base_url = &req.url;
-                    if let Ok(parsed_url) = url::Url::parse(base_url) {
-                        let origin = format!("{}://{}", parsed_url.scheme(), parsed_url.host_str().unwrap_or(""));
-                        
-                        // Replace relative URLs with absolute URLs
-                        html = html.replace("href=\"/", &format!("href=\"{}/", origin));
-                        html = html.replace("src=\"/", &format!("src=\"{}/", origin));
-                        html = html.replace("action=\"/", &format!("action=\"{}/", origin));
-                        
-                        // Fix CSS url() references
-                        html = html.replace("url(/_next/", &format!("url({}//_next/", origin));
-                        html = html.replace("url(/", &format!("url({}/", origin));
-                        
-                        // Also handle protocol-relative URLs
-                        html = html.replace("href=\"//", "href=\"https://");
-                        html = html.replace("src=\"//", "src=\"https://");
+fn auth_error_response(req: &HttpRequest, error: auth::AuthError, proxy_challenge: bool) -> HttpResponse {
+    let client = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let (challenge, body) = match error {
+        auth::AuthError::Forbidden(scope) => {
+            tracing::info!(?scope, "rejecting request: missing scope");
+            audit::record("auth_forbidden", &[("client", &client), ("path", req.path()), ("scope", &format!("{:?}", scope).to_ascii_lowercase())]);
+            return HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Insufficient scope",
+                "required_scope": scope
+            }));
+        }
+        auth::AuthError::Missing => (
+            format!("Basic realm=\"{0}\", Bearer realm=\"{0}\"", auth::REALM),
+            serde_json::json!({ "error": "Authentication required" }),
+        ),
+        auth::AuthError::Invalid(reason) => {
+            tracing::info!(%reason, "rejecting request: invalid credentials");
+            audit::record("auth_failed", &[("client", &client), ("path", req.path()), ("reason", &reason)]);
+            (
+                format!("Basic realm=\"{0}\", Bearer realm=\"{0}\", error=\"invalid_token\"", auth::REALM),
+                serde_json::json!({ "error": "Invalid credentials", "reason": reason }),
+            )
+        }
+    };
+
+    let mut response = if proxy_challenge {
+        HttpResponse::build(actix_web::http::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+    } else {
+        HttpResponse::Unauthorized()
+    };
+    let header = if proxy_challenge { "Proxy-Authenticate" } else { "WWW-Authenticate" };
+    response.insert_header((header, challenge)).json(body)
+}
+
+fn too_many_requests(client: &str, rejection: &limits::Rejection) -> HttpResponse {
+    tracing::info!(%client, limit = rejection.scope, reason = rejection.reason, "rate limited");
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", rejection.retry_after_secs().to_string()))
+        .json(serde_json::json!({
+            "error": "Too many requests",
+            "limit": rejection.scope,
+            "reason": rejection.reason,
+            "retry_after": rejection.retry_after_secs()
+        }))
+}
+
+fn denied_by_policy(url: &str, rule: &policy::Rule) -> HttpResponse {
+    tracing::info!(%url, %rule, "destination denied by policy");
+    audit::record("policy_denied", &[("url", url), ("rule", &rule.to_string())]);
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Destination denied by policy",
+        "rule": rule.to_string()
+    }))
+}
+
+fn forbidden_destination(url: &str, reason: &str) -> HttpResponse {
+    tracing::warn!(%url, %reason, "refusing blocked destination");
+    audit::record("destination_blocked", &[("url", url), ("reason", reason)]);
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Destination not allowed",
+        "reason": reason
+    }))
+}
+
+fn log_upstream_status(status: reqwest::StatusCode) {
+    if status.as_u16() == 429 || status.as_u16() == 403 {
+        tracing::warn!(upstream_status = status.as_u16(), "potential bot detection");
+    } else if status.is_success() {
+        tracing::debug!(upstream_status = status.as_u16(), "upstream request successful");
+    } else {
+        tracing::info!(upstream_status = status.as_u16(), "unexpected upstream status");
+    }
+}
+
+/// Copy upstream response headers onto the client response, dropping hop-by-hop headers and
+/// rewriting redirects and cookies so the client stays on the proxy
+fn forward_response_headers(
+    response_builder: &mut actix_web::HttpResponseBuilder,
+    headers: &reqwest::header::HeaderMap,
+    final_url: &url::Url,
+    config: &ProxyConfig,
+    secure_client: bool,
+    body_transformed: bool,
+) {
+    for (header_name, header_value) in headers {
+        let name = header_name.as_str().to_lowercase();
+        
+        // Skip hop-by-hop headers and headers that actix-web manages
+        if matches!(name.as_str(),
+            "connection" | "proxy-connection" | "te" | "trailer" | 
+            "transfer-encoding" | "upgrade"
+        ) {
+            continue;
+        }
+        
+        // The upstream coding and length no longer apply once the body has been transformed
+        if body_transformed && matches!(name.as_str(), "content-encoding" | "content-length") {
+            continue;
+        }
+
+        if let Ok(value) = header_value.to_str() {
+            // Scope upstream cookies to this site's namespace on the proxy
+            if name == "set-cookie" && config.translate_cookies {
+                match cookies::rewrite_set_cookie(value, final_url, secure_client) {
+                    Some(rewritten) => {
+                        response_builder.append_header((header_name.as_str(), rewritten));
                     }
-                    
-                    println!("HTML content length: {} chars", html.len());
-                    
-                    // Get the server's public IP address
-                    let server_ip = get_public_ip().await;
-                    
-                    // Generate PQC session ID and get public keys
-                    let pqc_session_id = generate_session_id();
-                    let (kyber_pk, dilithium_pk, sphincs_pk) = PQC_INSTANCE.get_public_keys();
-                    let pqc_public_keys = PqcPublicKeys {
-                        kyber_pk,
-                        dilithium_pk,
-                        sphincs_pk,
-                    };
-                    
-                    let proxy_response = ProxyResponse {
-                        html,
-                        status,
-                        server_ip,
-                        pqc_session_id,
-                        pqc_public_keys,
-                    };
-                    Ok(HttpResponse::Ok().json(proxy_response))
+                    None => tracing::debug!(url = %final_url, "dropped Set-Cookie with foreign domain"),
+                }
+                continue;
+            }
+
+            // Keep redirects pointed at the proxy instead of the origin
+            match redirect::rewrite_header(&name, value, final_url) {
+                Some(rewritten) => {
+                    tracing::debug!(header = %name, location = %rewritten, "rewrote redirect header");
+                    response_builder.append_header((header_name.as_str(), rewritten));
                 }
-                Err(e) => {
-                    println!("Failed to read response body: {}", e);
-                    Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                        "error": format!("Failed to read response body: {}", e)
-                    })))
+                None => {
+                    response_builder.append_header((header_name.as_str(), value));
                 }
             }
         }
-        Err(e) => {
-            println!("Failed to fetch URL: {}", e);
-            Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": format!("Failed to fetch the URL: {}", e)
-            })))
-        }
     }
 }
 
-async fn pqc_proxy_handler(req: web::Json<PqcProxyRequest>) -> Result<HttpResponse> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-        .redirect(reqwest::redirect::Policy::limited(10))
-        .build()
-        .unwrap();
+/// A page fetched for the envelope endpoints
+struct EnvelopePage {
+    url: url::Url,
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+}
 
-    println!("PQC Proxy: Fetching URL: {}", req.url);
+/// Refuse schema versions the envelope endpoints don't know
+fn check_envelope_version(version: u8) -> std::result::Result<(), HttpResponse> {
+    if ENVELOPE_VERSIONS.contains(&version) {
+        return Ok(());
+    }
+    Err(HttpResponse::BadRequest().json(serde_json::json!({
+        "error": format!("Unsupported schema version {}", version),
+        "supported_versions": ENVELOPE_VERSIONS
+    })))
+}
 
-    // If peer public keys are provided, establish secure session
-    let mut encryption_key = None;
-    if let Some(peer_keys) = &req.peer_public_keys {
-        println!("Establishing PQC secure session...");
-        match PQC_INSTANCE.kyber_encapsulate(&peer_keys.kyber_pk) {
-            Ok((shared_secret, _)) => {
-                println!("✓ Kyber key encapsulation successful");
-                encryption_key = Some(shared_secret);
-            }
-            Err(e) => {
-                println!("⚠ PQC key encapsulation failed: {}", e);
-            }
-        }
+/// Fetch `url` for an envelope endpoint with the shared client, after the same policy, SSRF and
+/// rate-limit checks as `/proxy`, rewriting links in HTML with `rewrite`
+#[allow(clippy::too_many_arguments)]
+async fn fetch_envelope_page(
+    http_req: &HttpRequest,
+    url: &str,
+    rewrite: Option<UrlMode>,
+    config: &ProxyConfig,
+    domain_policy: &PolicyStore,
+    rate_limiter: &Arc<RateLimiter>,
+    client: &Client,
+    metrics: &Metrics,
+) -> std::result::Result<EnvelopePage, HttpResponse> {
+    let target = url::Url::parse(url).map_err(|e| {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Invalid URL: {}", e)
+        }))
+    })?;
+    if let Err(rule) = domain_policy.check(&target) {
+        return Err(denied_by_policy(url, &rule));
     }
+    if let Err(blocked) = config.ssrf.check_url(&target).await {
+        return Err(forbidden_destination(url, &blocked.0));
+    }
+
+    let client_ip = forwarding::client_ip(http_req, &config.forwarding).to_string();
+    let session_id = get_session_id(http_req, config);
+    let host = target.host_str().unwrap_or_default().to_ascii_lowercase();
+    telemetry::record_target(http_req, &session_id, url, &host);
+    let _permit = rate_limiter
+        .acquire(&client_ip, &session_id, &host)
+        .map_err(|rejection| too_many_requests(&client_ip, &rejection))?;
+
+    tracing::info!(%url, "fetching for envelope");
 
-    match client.get(&req.url)
+    let started = Instant::now();
+    let response = client.get(target)
         .header("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8")
         .header("Accept-Language", "en-US,en;q=0.5")
         .header("DNT", "1")
-        .header("Connection", "keep-alive")
         .header("Upgrade-Insecure-Requests", "1")
         .send()
-        .await {
-        Ok(response) => {
-            let status = response.status().as_u16();
-            let headers = response.headers().clone();
-            
-            println!("Response status: {}", status);
-            println!("Content-Type: {:?}", headers.get("content-type"));
-            
-            match response.text().await {
-                Ok(mut html) => {
-                    // Fix relative URLs to absolute URLs (same as before)
-                    let base_url = &req.url;
-                    if let Ok(parsed_url) = url::Url::parse(base_url) {
-                        let origin = format!("{}://{}", parsed_url.scheme(), parsed_url.host_str().unwrap_or(""));
-                        
-                        html = html.replace("href=\"/", &format!("href=\"{}/", origin));
-                        html = html.replace("src=\"/", &format!("src=\"{}/", origin));
-                        html = html.replace("action=\"/", &format!("action=\"{}/", origin));
-                        html = html.replace("url(/_next/", &format!("url({}//_next/", origin));
-                        html = html.replace("url(/", &format!("url({}/", origin));
-                        html = html.replace("href=\"//", "href=\"https://");
-                        html = html.replace("src=\"//", "src=\"https://");
-                    }
-                    
-                    // Apply PQC encryption if secure session established
-                    let processed_html = if let Some(key) = encryption_key {
-                        println!("🔒 Applying PQC encryption to HTML content");
-                        match PQC_INSTANCE.symmetric_encrypt(html.as_bytes(), &key) {
-                            Ok(encrypted) => {
-                                println!("✓ HTML content encrypted with PQC");
-                                encrypted
-                            }
-                            Err(e) => {
-                                println!("⚠ PQC encryption failed: {}", e);
-                                html // Fallback to unencrypted
-                            }
-                        }
-                    } else {
-                        html
-                    };
-                    
-                    println!("Processed content length: {} chars", processed_html.len());
-                    
-                    let server_ip = get_public_ip().await;
-                    let pqc_session_id = generate_session_id();
-                    let (kyber_pk, dilithium_pk, sphincs_pk) = PQC_INSTANCE.get_public_keys();
-                    
-                    // Create digital signature of the content hash for integrity
-                    let content_hash = PQC_INSTANCE.hash_data(processed_html.as_bytes());
-                    let content_signature = match PQC_INSTANCE.dilithium_sign(content_hash.as_bytes()) {
-                        Ok(sig) => sig,
-                        Err(e) => {
-                            println!("⚠ Failed to sign content: {}", e);
-                            String::new()
-                        }
-                    };
-                    
-                    let pqc_public_keys = PqcPublicKeys {
-                        kyber_pk,
-                        dilithium_pk,
-                        sphincs_pk,
-                    };
-                    
-                    let proxy_response = ProxyResponse {
-                        html: processed_html,
-                        status,
-                        server_ip,
-                        pqc_session_id,
-                        pqc_public_keys,
-                    };
-                    
-                    // Add PQC signature to response headers
-                    Ok(HttpResponse::Ok()
-                        .insert_header(("X-PQC-Content-Hash", content_hash))
-                        .insert_header(("X-PQC-Content-Signature", content_signature))
-                        .insert_header(("X-PQC-Enabled", "true"))
-                        .json(proxy_response))
-                }
-                Err(e) => {
-                    println!("Failed to read response body: {}", e);
-                    Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                        "error": format!("Failed to read response body: {}", e)
-                    })))
-                }
+        .await;
+    metrics.observe_upstream(metrics::Upstream::Envelope, started.elapsed());
+    let response = response
+        .map_err(|e| {
+            metrics.upstream_error(&e);
+            if let Some(reason) = ssrf::blocked_reason(&e) {
+                return forbidden_destination(url, &reason);
+            }
+            tracing::warn!(error = %e, "failed to fetch URL");
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Failed to fetch the URL: {}", e)
+            }))
+        })?;
+
+    let status = response.status().as_u16();
+    let final_url = response.url().clone();
+    telemetry::record_upstream_status(http_req, status);
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    tracing::debug!(upstream_status = status, content_type = ?content_type, "upstream responded");
+
+    let body = response.text().await.map_err(|e| {
+        tracing::warn!(error = %e, "failed to read response body");
+        HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to read response body: {}", e)
+        }))
+    })?;
+    metrics.bytes_in(body.len());
+
+    let is_html = content_type.as_deref().is_some_and(|ct| ct.to_ascii_lowercase().contains("html"));
+    let body = match rewrite {
+        Some(mode) if is_html => html_rewriter::rewrite_html(&body, &final_url, mode),
+        _ => body,
+    };
+    tracing::debug!(chars = body.len(), "fetched envelope content");
+
+    Ok(EnvelopePage { url: final_url, status, content_type, body })
+}
+
+fn server_public_keys() -> PqcPublicKeys {
+    let (kyber_pk, dilithium_pk, sphincs_pk) = PQC_INSTANCE.get_public_keys();
+    PqcPublicKeys { kyber_pk, dilithium_pk, sphincs_pk }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn proxy_handler(http_req: HttpRequest, req: web::Json<ProxyRequest>, config: web::Data<ProxyConfig>, domain_policy: web::Data<PolicyStore>, rate_limiter: web::Data<Arc<RateLimiter>>, client: web::Data<Client>, authenticator: web::Data<Authenticator>, metrics: web::Data<Metrics>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&http_req, &authenticator, Scope::Proxy, false) {
+        return Ok(response);
+    }
+    if let Err(response) = check_envelope_version(req.version) {
+        return Ok(response);
+    }
+
+    // Version 1 always made links absolute; later versions follow the server's HTML setting
+    let rewrite = if req.version == 1 { Some(UrlMode::Absolute) } else { config.rewrite_html };
+    let page = match fetch_envelope_page(&http_req, &req.url, rewrite, &config, &domain_policy, &rate_limiter, &client, &metrics).await {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+
+    metrics.bytes_out(page.body.len());
+    if req.version == 1 {
+        return Ok(HttpResponse::Ok().json(ProxyResponse {
+            html: page.body,
+            status: page.status,
+            server_ip: get_public_ip(http_req.app_config().local_addr().port()).await,
+            pqc_session_id: generate_session_id(),
+            pqc_public_keys: server_public_keys(),
+        }));
+    }
+    Ok(HttpResponse::Ok().json(ProxyResponseV2 {
+        version: req.version,
+        url: page.url.to_string(),
+        status: page.status,
+        content_type: page.content_type,
+        body: page.body,
+        encrypted: false,
+        pqc_session: None,
+        pqc_public_keys: server_public_keys(),
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn pqc_proxy_handler(http_req: HttpRequest, req: web::Json<PqcProxyRequest>, config: web::Data<ProxyConfig>, domain_policy: web::Data<PolicyStore>, rate_limiter: web::Data<Arc<RateLimiter>>, client: web::Data<Client>, pqc_sessions: web::Data<pqc::PqcSessionStore>, authenticator: web::Data<Authenticator>, metrics: web::Data<Metrics>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&http_req, &authenticator, Scope::Pqc, false) {
+        return Ok(response);
+    }
+    if let Err(response) = check_envelope_version(req.version) {
+        return Ok(response);
+    }
+
+    // The body is encrypted with an existing session's secret, or with a new one for the peer's key
+    let mut session: Option<(String, String, Option<pqc::PqcSharedData>)> = None;
+    if let Some(session_id) = &req.session_id {
+        match pqc_sessions.shared_secret(session_id) {
+            Some(secret) => session = Some((session_id.clone(), secret, None)),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Unknown or expired PQC session"
+                })));
             }
         }
+    } else if let Some(peer_keys) = &req.peer_public_keys {
+        let result = PQC_INSTANCE.create_secure_session(&peer_keys.kyber_pk);
+        metrics.pqc(PqcOperation::Encapsulate, result.is_ok());
+        let client = http_req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+        if let Err(e) = &result {
+            audit::record("pqc_handshake_failed", &[("client", &client), ("error", &e.to_string())]);
+        }
+        match result {
+            Ok(shared) => {
+                let session_id = generate_session_id();
+                tracing::info!(session = %session_id, "established PQC session");
+                audit::record("pqc_handshake", &[("client", &client), ("session", &session_id)]);
+                pqc_sessions.insert(&session_id, shared.shared_secret.clone());
+                session = Some((session_id, shared.shared_secret.clone(), Some(shared)));
+            }
+            // Version 1 carried on unencrypted
+            Err(e) if req.version == 1 => tracing::warn!(error = %e, "PQC key encapsulation failed, sending plaintext"),
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("PQC key encapsulation failed: {}", e)
+                })));
+            }
+        }
+    }
+
+    let rewrite = if req.version == 1 { Some(UrlMode::Absolute) } else { config.rewrite_html };
+    let page = match fetch_envelope_page(&http_req, &req.url, rewrite, &config, &domain_policy, &rate_limiter, &client, &metrics).await {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+
+    // Apply PQC encryption if secure session established
+    let mut encrypted = false;
+    let mut body = page.body;
+    if let Some((_, secret, _)) = &session {
+        let result = PQC_INSTANCE.symmetric_encrypt(body.as_bytes(), secret);
+        metrics.pqc(PqcOperation::Encrypt, result.is_ok());
+        match result {
+            Ok(ciphertext) => {
+                body = ciphertext;
+                encrypted = true;
+            }
+            Err(e) if req.version == 1 => tracing::warn!(error = %e, "PQC encryption failed, sending plaintext"),
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": format!("PQC encryption failed: {}", e)
+                })));
+            }
+        }
+    }
+
+    // Create digital signature of the content hash for integrity
+    let content_hash = PQC_INSTANCE.hash_data(body.as_bytes());
+    let content_signature = PQC_INSTANCE.dilithium_sign(content_hash.as_bytes());
+    metrics.pqc(PqcOperation::Sign, content_signature.is_ok());
+    let content_signature = match content_signature {
+        Ok(sig) => sig,
         Err(e) => {
-            println!("Failed to fetch URL: {}", e);
-            Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": format!("Failed to fetch the URL: {}", e)
-            })))
+            tracing::warn!(error = %e, "failed to sign content");
+            String::new()
         }
+    };
+
+    metrics.bytes_out(body.len());
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-PQC-Content-Hash", content_hash))
+        .insert_header(("X-PQC-Content-Signature", content_signature))
+        .insert_header(("X-PQC-Enabled", "true"));
+
+    if req.version == 1 {
+        return Ok(response.json(ProxyResponse {
+            html: body,
+            status: page.status,
+            server_ip: get_public_ip(http_req.app_config().local_addr().port()).await,
+            pqc_session_id: session.map_or_else(generate_session_id, |(session_id, _, _)| session_id),
+            pqc_public_keys: server_public_keys(),
+        }));
     }
+    Ok(response.json(ProxyResponseV2 {
+        version: req.version,
+        url: page.url.to_string(),
+        status: page.status,
+        content_type: page.content_type,
+        body,
+        encrypted,
+        pqc_session: session.map(|(session_id, _, shared)| PqcSessionInfo {
+            session_id,
+            ciphertext: shared.as_ref().map(|s| s.ciphertext.clone()),
+            signature: shared.map(|s| s.signature),
+        }),
+        pqc_public_keys: server_public_keys(),
+    }))
 }
 
-async fn pqc_handshake(req: web::Json<PqcPublicKeys>) -> Result<HttpResponse> {
-    println!("🤝 PQC Handshake initiated");
-    
+async fn pqc_handshake(http_req: HttpRequest, req: web::Json<PqcPublicKeys>, pqc_sessions: web::Data<pqc::PqcSessionStore>, authenticator: web::Data<Authenticator>, metrics: web::Data<Metrics>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&http_req, &authenticator, Scope::Pqc, false) {
+        return Ok(response);
+    }
+
     // Create secure session with the provided public key
-    match PQC_INSTANCE.create_secure_session(&req.kyber_pk) {
+    let result = PQC_INSTANCE.create_secure_session(&req.kyber_pk);
+    metrics.pqc(PqcOperation::Handshake, result.is_ok());
+    let client = http_req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    match result {
         Ok(session_data) => {
-            let (kyber_pk, dilithium_pk, sphincs_pk) = PQC_INSTANCE.get_public_keys();
-            let public_keys = PqcPublicKeys {
-                kyber_pk,
-                dilithium_pk,
-                sphincs_pk,
-            };
-            
+            let session_id = generate_session_id();
+            pqc_sessions.insert(&session_id, session_data.shared_secret.clone());
+            telemetry::record_session(&http_req, &session_id);
+
             let response = PqcResponse {
+                session_id,
                 session_data,
-                public_keys,
+                public_keys: server_public_keys(),
             };
             
-            println!("✓ PQC Handshake completed successfully");
+            tracing::info!(session = %response.session_id, "PQC handshake completed");
+            audit::record("pqc_handshake", &[("client", &client), ("session", &response.session_id)]);
             Ok(HttpResponse::Ok().json(response))
         }
         Err(e) => {
-            println!("⚠ PQC Handshake failed: {}", e);
+            tracing::warn!(error = %e, "PQC handshake failed");
+            audit::record("pqc_handshake_failed", &[("client", &client), ("error", &e.to_string())]);
             Ok(HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": format!("PQC handshake failed: {}", e)
             })))
@@ -995,6 +1121,243 @@ async fn pqc_handshake(req: web::Json<PqcPublicKeys>) -> Result<HttpResponse> {
     }
 }
 
+#[derive(Deserialize)]
+struct TokenRequest {
+    scopes: Option<Vec<Scope>>,
+    ttl_secs: Option<u64>,
+}
+
+/// Exchange an API key for a short-lived token signed with the server's Dilithium key
+async fn issue_token(req: HttpRequest, body: Option<web::Json<TokenRequest>>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authenticator.authenticate(&req, &PQC_INSTANCE) {
+        Ok(identity) if identity.is_api_key => identity,
+        Ok(_) => return Ok(auth_error_response(&req, auth::AuthError::Missing, false)),
+        Err(error) => return Ok(auth_error_response(&req, error, false)),
+    };
+
+    let (scopes, ttl_secs) = body.map_or((None, None), |b| (b.0.scopes, b.0.ttl_secs));
+    match authenticator.issue_token(&identity, scopes, ttl_secs, &PQC_INSTANCE) {
+        Ok((token, expires_at)) => {
+            tracing::info!(subject = %identity.subject, expires_at, "issued token");
+            audit::record("token_issued", &[("subject", &identity.subject), ("expires_at", &expires_at.to_string())]);
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "token": token,
+                "token_type": "Bearer",
+                "expires_at": expires_at
+            })))
+        }
+        Err(auth::AuthError::Invalid(reason)) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to issue token: {}", reason)
+        }))),
+        Err(error) => Ok(auth_error_response(&req, error, false)),
+    }
+}
+
+/// Prometheus text exposition of the server's counters; needs the admin scope when auth is required
+async fn metrics_handler(req: HttpRequest, metrics: web::Data<Metrics>, session_storage: web::Data<SessionStorage>, rate_limiter: web::Data<Arc<RateLimiter>>, config: web::Data<ProxyConfig>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    if config.auth.required
+        && let Err(response) = authorize(&req, &authenticator, Scope::Admin, false)
+    {
+        return Ok(response);
+    }
+    let active_sessions = session_storage.len();
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render(active_sessions, &rate_limiter.stats())))
+}
+
+/// Liveness: the process is up and serving requests
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: PQC keys work, the session stores are usable and every listener is bound
+async fn readyz(health: web::Data<Health>, session_storage: web::Data<SessionStorage>, pqc_sessions: web::Data<pqc::PqcSessionStore>) -> HttpResponse {
+    let (_, dilithium_pk, _) = PQC_INSTANCE.get_public_keys();
+    let pqc_keys = PQC_INSTANCE
+        .dilithium_sign(b"readyz")
+        .and_then(|signed| PQC_INSTANCE.dilithium_verify(&signed, &dilithium_pk))
+        .is_ok_and(|message| message == b"readyz");
+    let session_store = session_storage.is_usable() && pqc_sessions.is_usable();
+
+    let readiness = health.readiness(pqc_keys, session_store);
+    if readiness.checks.passed() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        tracing::warn!(checks = ?readiness.checks, "not ready");
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+/// Start a new browsing session; its token comes back in the session cookie and the
+/// `X-Proxy-Session` header, and the previous session is left to expire
+async fn new_session(req: HttpRequest, tokens: web::Data<SessionTokens>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &authenticator, Scope::Proxy, false) {
+        return Ok(response);
+    }
+    let session_id = tokens.start_new(&req);
+    telemetry::record_session(&req, &session_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "session_id": session_id })))
+}
+
+/// Admin: every browsing session with its counters, most recently active first
+async fn admin_list_sessions(req: HttpRequest, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &authenticator, Scope::Admin, false) {
+        return Ok(response);
+    }
+    let sessions = session_storage.values();
+    let mut summaries: Vec<SessionSummary> = sessions.iter().filter_map(|session| session.lock().ok().map(|s| s.summary())).collect();
+    summaries.sort_by_key(|summary| summary.idle_secs);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "count": summaries.len(),
+        "sessions": summaries
+    })))
+}
+
+/// Admin: one session's counters, history and cookie names
+async fn admin_get_session(req: HttpRequest, path: web::Path<String>, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &authenticator, Scope::Admin, false) {
+        return Ok(response);
+    }
+    let session = session_storage.get(path.as_str());
+    match session {
+        Some(session) => Ok(HttpResponse::Ok().json(session.lock().unwrap().detail())),
+        None => Ok(session_not_found()),
+    }
+}
+
+/// Admin: forget a session's cookies, keeping the session itself
+async fn admin_clear_session_cookies(req: HttpRequest, path: web::Path<String>, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Admin, false) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+    let Some(session) = session_storage.get(path.as_str()) else {
+        return Ok(session_not_found());
+    };
+    session.lock().unwrap().cookies.lock().unwrap().clear();
+
+    tracing::info!(session = %path, subject = %identity.subject, "admin cleared session cookies");
+    audit::record("admin_session_cookies_cleared", &[("subject", &identity.subject), ("session", &path)]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "session_id": path.as_str(), "cookies_cleared": true })))
+}
+
+/// Admin: a session's cookies, values included, as JSON or a Netscape cookies.txt file
+async fn admin_export_session_cookies(req: HttpRequest, path: web::Path<String>, query: web::Query<CookieFormatQuery>, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Admin, false) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+    let Some(session) = session_storage.get(path.as_str()) else {
+        return Ok(session_not_found());
+    };
+    let cookies = session_store::export(&session.lock().unwrap().cookies.lock().unwrap());
+
+    tracing::info!(session = %path, subject = %identity.subject, cookies = cookies.len(), "admin exported session cookies");
+    audit::record("admin_session_cookies_exported", &[("subject", &identity.subject), ("session", &path), ("cookies", &cookies.len().to_string())]);
+    Ok(match query.format {
+        CookieFormat::Json => HttpResponse::Ok().json(cookies),
+        CookieFormat::Netscape => HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(session_store::to_netscape(&cookies)),
+    })
+}
+
+/// Admin: load cookies exported earlier into a session. `PUT` replaces the session's cookies,
+/// `POST` adds to them.
+async fn admin_import_session_cookies(req: HttpRequest, body: web::Bytes, path: web::Path<String>, query: web::Query<CookieFormatQuery>, session_storage: web::Data<SessionStorage>, config: web::Data<ProxyConfig>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Admin, false) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+    let Some(session) = session_storage.get(path.as_str()) else {
+        return Ok(session_not_found());
+    };
+    let parsed = match query.format {
+        CookieFormat::Json => serde_json::from_slice(&body).map_err(|e| e.to_string()),
+        CookieFormat::Netscape => std::str::from_utf8(&body).map_err(|e| e.to_string()).and_then(session_store::from_netscape),
+    };
+    let cookies: Vec<session_store::StoredCookie> = match parsed {
+        Ok(cookies) => cookies,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": format!("Invalid cookies: {}", e) }))),
+    };
+
+    let replace = req.method() == actix_web::http::Method::PUT;
+    let jar = session.lock().unwrap().cookies.clone();
+    let mut store = jar.lock().unwrap();
+    let mut staged = if replace { reqwest_cookie_store::CookieStore::default() } else { store.clone() };
+    let imported = match session_store::import(&mut staged, &cookies) {
+        Ok(imported) => imported,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": format!("Invalid cookies: {}", e) }))),
+    };
+    if staged.iter_unexpired().count() > config.session.max_cookies {
+        let error = format!("Session would hold more than {} cookies", config.session.max_cookies);
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": error })));
+    }
+    *store = staged;
+    drop(store);
+
+    tracing::info!(session = %path, subject = %identity.subject, imported, replace, "admin imported session cookies");
+    audit::record("admin_session_cookies_imported", &[("subject", &identity.subject), ("session", &path), ("cookies", &imported.to_string())]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "session_id": path.as_str(), "imported": imported, "replaced": replace })))
+}
+
+/// Admin: drop one session
+async fn admin_delete_session(req: HttpRequest, path: web::Path<String>, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Admin, false) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+    if session_storage.remove(path.as_str()).is_none() {
+        return Ok(session_not_found());
+    }
+
+    tracing::info!(session = %path, subject = %identity.subject, "admin deleted session");
+    audit::record("admin_session_deleted", &[("subject", &identity.subject), ("session", &path)]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "deleted": 1 })))
+}
+
+/// Admin: drop every session
+async fn admin_flush_sessions(req: HttpRequest, session_storage: web::Data<SessionStorage>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    let identity = match authorize(&req, &authenticator, Scope::Admin, false) {
+        Ok(identity) => identity,
+        Err(response) => return Ok(response),
+    };
+    let deleted = session_storage.clear();
+
+    tracing::info!(deleted, subject = %identity.subject, "admin flushed sessions");
+    audit::record("admin_sessions_flushed", &[("subject", &identity.subject), ("deleted", &deleted.to_string())]);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "deleted": deleted })))
+}
+
+/// Admin: configured rate limits and session caps next to their current usage
+async fn admin_limits(req: HttpRequest, session_storage: web::Data<SessionStorage>, pqc_sessions: web::Data<pqc::PqcSessionStore>, rate_limiter: web::Data<Arc<RateLimiter>>, config: web::Data<ProxyConfig>, authenticator: web::Data<Authenticator>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &authenticator, Scope::Admin, false) {
+        return Ok(response);
+    }
+    let usage: HashMap<_, _> = rate_limiter.stats().into_iter().collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "rate_limits": {
+            "client": config.limits.client,
+            "session": config.limits.session,
+            "host": config.limits.host
+        },
+        "rate_limit_usage": usage,
+        "sessions": {
+            "browsing": session_storage.len(),
+            "max_browsing": config.session.max_sessions,
+            "pqc": pqc_sessions.live_count(),
+            "max_pqc": config.session.max_pqc_sessions,
+            "ttl_secs": config.session.ttl_secs,
+            "max_visited_urls": config.session.max_visited_urls,
+            "max_history": config.session.max_history,
+            "max_cookies": config.session.max_cookies
+        }
+    })))
+}
+
+fn session_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({ "error": "Session not found" }))
+}
+
 async fn pqc_info() -> Result<HttpResponse> {
     let (kyber_pk, dilithium_pk, sphincs_pk) = PQC_INSTANCE.get_public_keys();
     
@@ -1023,7 +1386,7 @@ use std::time::Instant;
 #[derive(Debug, Clone)]
 struct AdvancedSession {
     session_id: String,
-    cookies: Arc<reqwest::cookie::Jar>,
+    cookies: Arc<reqwest_cookie_store::CookieStoreMutex>,
     fingerprint: BrowserFingerprint,
     visited_urls: Vec<String>,
     interaction_history: Vec<String>,
@@ -1046,7 +1409,7 @@ impl AdvancedSession {
         
         AdvancedSession {
             session_id,
-            cookies: Arc::new(reqwest::cookie::Jar::default()),
+            cookies: Arc::default(),
             fingerprint,
             visited_urls: Vec::new(),
             interaction_history: Vec::new(),
@@ -1088,89 +1451,205 @@ impl AdvancedSession {
     fn needs_cooling_off(&self) -> bool {
         self.captcha_attempts > 2 || self.is_suspicious()
     }
+
+    fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            session_id: self.session_id.clone(),
+            started_at: self.session_start,
+            idle_secs: self.last_activity.elapsed().as_secs(),
+            request_count: self.request_count,
+            success_count: self.success_count,
+            success_rate: self.get_success_rate(),
+            visited_url_count: self.visited_urls.len(),
+            captcha_attempts: self.captcha_attempts,
+            suspicious: self.is_suspicious(),
+        }
+    }
+
+    fn detail(&self) -> SessionDetail {
+        let mut cookies: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for cookie in session_store::export(&self.cookies.lock().unwrap()) {
+            cookies.entry(cookie.domain).or_default().insert(cookie.name);
+        }
+
+        SessionDetail {
+            summary: self.summary(),
+            user_agent: self.fingerprint.user_agent.clone(),
+            languages: self.preferred_languages.clone(),
+            visited_urls: self.visited_urls.clone(),
+            interaction_history: self.interaction_history.clone(),
+            cookies,
+        }
+    }
 }
 
-// Helper function to generate realistic forwarded IPs
-fn generate_realistic_forwarded_ip(original_ip: &str) -> String {
-    // Generate IPs from common residential/business ranges
-    let ranges = [
-        // Common residential ranges
-        ("192.168.", 1, 255, 1, 254),
-        ("10.", 0, 255, 0, 255),
-        ("172.", 16, 31, 0, 255),
-        // Common business ranges  
-        ("203.", 0, 255, 0, 255),
-        ("74.", 125, 127, 0, 255),
-        ("98.", 138, 140, 0, 255),
-    ];
-    
-    if rand::thread_rng().gen_bool(0.8) {
-        // 80% chance to use a realistic IP range
-        let range = ranges[rand::thread_rng().gen_range(0..ranges.len())];
-        let (prefix, start1, end1, start2, end2) = range;
-        
-        if prefix.ends_with('.') {
-            // Two more octets needed
-            format!("{}{}.{}.{}", 
-                prefix,
-                rand::thread_rng().gen_range(start1..=end1),
-                rand::thread_rng().gen_range(start2..=end2),
-                rand::thread_rng().gen_range(1..=254)
-            )
-        } else {
-            original_ip.to_string()
+/// A browsing session as listed by the admin API
+#[derive(Serialize)]
+struct SessionSummary {
+    session_id: String,
+    started_at: u64,
+    idle_secs: u64,
+    request_count: u32,
+    success_count: u32,
+    success_rate: f32,
+    visited_url_count: usize,
+    captcha_attempts: u32,
+    suspicious: bool,
+}
+
+/// One browsing session in full; cookie values are left out
+#[derive(Serialize)]
+struct SessionDetail {
+    #[serde(flatten)]
+    summary: SessionSummary,
+    user_agent: String,
+    languages: Vec<String>,
+    visited_urls: Vec<String>,
+    interaction_history: Vec<String>,
+    /// Names of the cookies held for each domain
+    cookies: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// A browsing session as kept in the session store file
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    session_id: String,
+    fingerprint: BrowserFingerprint,
+    visited_urls: Vec<String>,
+    interaction_history: Vec<String>,
+    session_start: u64,
+    idle_secs: u64,
+    request_count: u32,
+    success_count: u32,
+    google_tokens: Vec<String>,
+    captcha_attempts: u32,
+    preferred_languages: Vec<String>,
+    screen_resolution: String,
+    timezone: String,
+    connection_downlink: String,
+    cookies: Vec<session_store::StoredCookie>,
+}
+
+impl From<&AdvancedSession> for SavedSession {
+    fn from(session: &AdvancedSession) -> Self {
+        SavedSession {
+            session_id: session.session_id.clone(),
+            fingerprint: session.fingerprint.clone(),
+            visited_urls: session.visited_urls.clone(),
+            interaction_history: session.interaction_history.clone(),
+            session_start: session.session_start,
+            idle_secs: session.last_activity.elapsed().as_secs(),
+            request_count: session.request_count,
+            success_count: session.success_count,
+            google_tokens: session.google_tokens.clone(),
+            captcha_attempts: session.captcha_attempts,
+            preferred_languages: session.preferred_languages.clone(),
+            screen_resolution: session.screen_resolution.clone(),
+            timezone: session.timezone.clone(),
+            connection_downlink: session.connection_downlink.clone(),
+            cookies: session_store::export(&session.cookies.lock().unwrap()),
         }
-    } else {
-        // 20% chance to slightly modify the original IP
-        let ip_parts: Vec<&str> = original_ip.split('.').collect();
-        if ip_parts.len() == 4 {
-            if let (Ok(a), Ok(b), Ok(c), Ok(d)) = (
-                ip_parts[0].parse::<u8>(),
-                ip_parts[1].parse::<u8>(),
-                ip_parts[2].parse::<u8>(),
-                ip_parts[3].parse::<u8>(),
-            ) {
-                let new_d = d.wrapping_add(rand::thread_rng().gen_range(0..5));
-                format!("{}.{}.{}.{}", a, b, c, new_d)
-            } else {
-                original_ip.to_string()
+    }
+}
+
+impl SavedSession {
+    fn restore(self) -> Result<AdvancedSession, String> {
+        let mut cookies = reqwest_cookie_store::CookieStore::default();
+        session_store::import(&mut cookies, &self.cookies)?;
+        let now = Instant::now();
+        Ok(AdvancedSession {
+            session_id: self.session_id,
+            cookies: Arc::new(reqwest_cookie_store::CookieStoreMutex::new(cookies)),
+            fingerprint: self.fingerprint,
+            visited_urls: self.visited_urls,
+            interaction_history: self.interaction_history,
+            session_start: self.session_start,
+            last_activity: now.checked_sub(Duration::from_secs(self.idle_secs)).unwrap_or(now),
+            request_count: self.request_count,
+            success_count: self.success_count,
+            google_tokens: self.google_tokens,
+            captcha_attempts: self.captcha_attempts,
+            preferred_languages: self.preferred_languages,
+            screen_resolution: self.screen_resolution,
+            timezone: self.timezone,
+            connection_downlink: self.connection_downlink,
+        })
+    }
+}
+
+/// Write every browsing session, cookies included, to the session store file
+fn save_sessions(session_storage: &SessionStorage, path: &std::path::Path) -> Result<usize, String> {
+    let sessions = session_storage.values();
+    let saved: Vec<SavedSession> = sessions.iter().map(|session| SavedSession::from(&*session.lock().unwrap())).collect();
+    session_store::save(path, &saved)?;
+    Ok(saved.len())
+}
+
+/// Read sessions back from the session store file, leaving out ones idle for longer than `ttl_secs`
+fn load_sessions(session_storage: &SessionStorage, path: &std::path::Path, ttl_secs: u64) -> Result<usize, String> {
+    let saved: Vec<SavedSession> = session_store::load(path)?;
+    let mut restored = 0;
+    for session in saved.into_iter().filter(|session| session.idle_secs < ttl_secs) {
+        let (session_id, idle) = (session.session_id.clone(), Duration::from_secs(session.idle_secs));
+        match session.restore() {
+            Ok(session) => {
+                session_storage.restore(session_id, Mutex::new(session), idle);
+                restored += 1;
             }
-        } else {
-            original_ip.to_string()
+            Err(e) => tracing::warn!(session = %session_id, error = %e, "could not restore session"),
         }
     }
+    Ok(restored)
 }
 
 // Advanced session management functions
+// Idle sessions are dropped by `reap_sessions`, not here, to keep the request path short
 fn get_or_create_advanced_session(session_storage: &SessionStorage, session_id: &str) -> Arc<Mutex<AdvancedSession>> {
-    let mut storage = session_storage.lock().unwrap();
-    
-    // Clean up old sessions periodically
-    let now = Instant::now();
-    storage.retain(|_, session_arc| {
-        if let Ok(session) = session_arc.lock() {
-            now.duration_since(session.last_activity).as_secs() < 3600 // Keep for 1 hour
-        } else {
-            false
+    session_storage.get_or_insert_with(session_id, || Mutex::new(AdvancedSession::new(session_id.to_string())))
+}
+
+/// Drop sessions idle for longer than `session.ttl_secs`, every `session.reap_secs`
+async fn reap_sessions(session_storage: SessionStorage, settings: SessionSettings) {
+    let ttl = Duration::from_secs(settings.ttl_secs);
+    let mut ticker = tokio::time::interval(Duration::from_secs(settings.reap_secs));
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let reaped = session_storage.remove_idle(ttl);
+        if reaped > 0 {
+            tracing::debug!(reaped, remaining = session_storage.len(), "dropped idle sessions");
         }
-    });
-    
-    storage.entry(session_id.to_string())
-        .or_insert_with(|| Arc::new(Mutex::new(AdvancedSession::new(session_id.to_string()))))
-        .clone()
+    }
 }
 
-fn update_advanced_session(session_storage: &SessionStorage, session_id: &str, session: &Arc<Mutex<AdvancedSession>>, url: &str) {
+/// Longest URL kept in a session's history; longer ones are cut short
+const MAX_RECORDED_URL_LEN: usize = 2048;
+/// Google `FPB` tokens kept per session
+const MAX_GOOGLE_TOKENS: usize = 8;
+
+fn update_advanced_session(session: &Arc<Mutex<AdvancedSession>>, url: &str, settings: &SessionSettings) {
+    let mut end = url.len().min(MAX_RECORDED_URL_LEN);
+    while !url.is_char_boundary(end) {
+        end -= 1;
+    }
+    let url = &url[..end];
     let mut session_guard = session.lock().unwrap();
     session_guard.visited_urls.push(url.to_string());
     session_guard.interaction_history.push(format!("visited: {}", url));
     
     // Keep only recent history to prevent memory bloat
-    if session_guard.visited_urls.len() > 50 {
-        session_guard.visited_urls.drain(0..10);
+    // (trimmed in chunks of a fifth so this doesn't run on every request)
+    if session_guard.visited_urls.len() > settings.max_visited_urls {
+        let excess = session_guard.visited_urls.len() - settings.max_visited_urls + settings.max_visited_urls / 5;
+        session_guard.visited_urls.drain(0..excess);
     }
-    if session_guard.interaction_history.len() > 100 {
-        session_guard.interaction_history.drain(0..20);
+    if session_guard.interaction_history.len() > settings.max_history {
+        let excess = session_guard.interaction_history.len() - settings.max_history + settings.max_history / 5;
+        session_guard.interaction_history.drain(0..excess);
+    }
+    let dropped = session_store::trim(&mut session_guard.cookies.lock().unwrap(), settings.max_cookies);
+    if dropped > 0 {
+        tracing::debug!(dropped, max = settings.max_cookies, "session cookie limit reached, dropped cookies");
     }
 }
 
@@ -1194,7 +1673,7 @@ async fn smart_delay(session: &Arc<Mutex<AdvancedSession>>) {
         rand::thread_rng().gen_range(800..2500)
     };
     
-    println!("⏳ Smart delay: {}ms", base_delay);
+    tracing::debug!(delay_ms = base_delay, "smart delay");
     tokio::time::sleep(Duration::from_millis(base_delay)).await;
 }
 
@@ -1204,24 +1683,24 @@ fn handle_anti_bot_response(body: &str, session: &Arc<Mutex<AdvancedSession>>) -
     
     if body.contains("captcha") || body.contains("CAPTCHA") {
         session_guard.captcha_attempts += 1;
-        println!("🤖 CAPTCHA detected - attempt #{}", session_guard.captcha_attempts);
+        tracing::warn!(attempt = session_guard.captcha_attempts, "CAPTCHA detected");
         return true;
     }
     
     if body.contains("unusual traffic") || body.contains("automated") {
-        println!("🚫 Automated traffic detection");
+        tracing::warn!("automated traffic detected");
         return true;
     }
     
     if body.contains("Please click") && body.contains("redirected") {
-        println!("🔄 Redirect challenge detected");
+        tracing::info!("redirect challenge detected");
         return true;
     }
     
     // Check for successful search results
     if body.contains("<h3") || body.contains("search-result") {
         session_guard.add_success();
-        println!("✅ Successful search results detected");
+        tracing::debug!("search results detected");
         return false;
     }
     
@@ -1287,11 +1766,6 @@ fn generate_realistic_headers_v2(session: &AdvancedSession, url: &str, is_mobile
         
         // Add cache control for Google
         headers.insert("Cache-Control".to_string(), "max-age=0".to_string());
-        
-        // Add Google's client data header
-        use base64::{Engine as _, engine::general_purpose};
-        let client_data = general_purpose::STANDARD.encode(format!("session={}", &session.session_id[0..8]));
-        headers.insert("X-Client-Data".to_string(), client_data);
     }
     
     // Add referer based on session history
@@ -1306,15 +1780,15 @@ fn generate_realistic_headers_v2(session: &AdvancedSession, url: &str, is_mobile
 }
 
 // Simulate advanced browsing behavior
-async fn simulate_advanced_browsing_behavior(session: &Arc<Mutex<AdvancedSession>>, target_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+async fn simulate_advanced_browsing_behavior(session: &Arc<Mutex<AdvancedSession>>, target_url: &str, timeouts: &Timeouts) -> Result<(), Box<dyn std::error::Error>> {
     let client = {
         let session_guard = session.lock().unwrap();
         Client::builder()
-            .timeout(Duration::from_secs(30))
+            .timeout(Duration::from_secs(timeouts.prefetch_secs))
             .cookie_provider(session_guard.cookies.clone())
             .danger_accept_invalid_certs(false)
-            .tcp_keepalive(Duration::from_secs(60))
-            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(timeouts.tcp_keepalive_secs))
+            .pool_idle_timeout(Duration::from_secs(timeouts.pool_idle_secs))
             .pool_max_idle_per_host(10)
             .http1_only()
             .build()
@@ -1323,77 +1797,79 @@ async fn simulate_advanced_browsing_behavior(session: &Arc<Mutex<AdvancedSession
 
     if target_url.contains("google.com") {
         // 1. Visit Google homepage first if not already visited
-        {
-            let session_guard = session.lock().unwrap();
-            if !session_guard.visited_urls.iter().any(|u| u.contains("google.com")) {
-                drop(session_guard);
+        let visited_google = session.lock().unwrap().visited_urls.iter().any(|u| u.contains("google.com"));
+        if !visited_google {
+            tracing::debug!("simulating Google homepage visit");
+            let headers = {
+                let session_guard = session.lock().unwrap();
+                generate_realistic_headers_v2(&session_guard, "https://www.google.com", false)
+            };
+            
+            let mut request = client.get("https://www.google.com");
+            for (name, value) in headers {
+                request = request.header(&name, &value);
+            }
+            
+            if let Ok(response) = request.send().await {
+                let succeeded = response.status().is_success();
                 
-                println!("🌐 Simulating Google homepage visit...");
-                let headers = {
-                    let session_guard = session.lock().unwrap();
-                    generate_realistic_headers_v2(&session_guard, "https://www.google.com", false)
-                };
+                // Extract any tokens or cookies for later use
+                let body = response.text().await.unwrap_or_default();
                 
-                let mut request = client.get("https://www.google.com");
-                for (name, value) in headers {
-                    request = request.header(&name, &value);
+                let mut session_guard = session.lock().unwrap();
+                session_guard.visited_urls.push("https://www.google.com".to_string());
+                session_guard.update_activity();
+                
+                if succeeded {
+                    session_guard.add_success();
+                    tracing::debug!("Google homepage visit successful");
                 }
                 
-                if let Ok(response) = request.send().await {
-                    let mut session_guard = session.lock().unwrap();
-                    session_guard.visited_urls.push("https://www.google.com".to_string());
-                    session_guard.update_activity();
-                    
-                    if response.status().is_success() {
-                        session_guard.add_success();
-                        println!("✅ Google homepage visit successful");
-                    }
-                    
-                    // Extract any tokens or cookies for later use
-                    let body = response.text().await.unwrap_or_default();
-                    if let Some(start) = body.find("\"FPB\":\"") {
-                        if let Some(end) = body[start+7..].find("\"") {
-                            let token = &body[start+7..start+7+end];
-                            session_guard.google_tokens.push(token.to_string());
-                        }
+                if let Some(start) = body.find("\"FPB\":\"")
+                    && let Some(end) = body[start+7..].find("\"")
+                {
+                    let token = &body[start+7..start+7+end];
+                    session_guard.google_tokens.push(token.to_string());
+                    if session_guard.google_tokens.len() > MAX_GOOGLE_TOKENS {
+                        session_guard.google_tokens.remove(0);
                     }
                 }
-                
-                // Human-like delay
-                tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(800..2000))).await;
             }
+            
+            // Human-like delay
+            tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(800..2000))).await;
         }
 
         // 2. Simulate search suggestions request
-        if target_url.contains("/search?q=") {
-            if let Some(query_start) = target_url.find("q=") {
-                let query_part = &target_url[query_start+2..];
-                let query = query_part.split('&').next().unwrap_or("");
-                let decoded_query = urlencoding::decode(query).unwrap_or_default();
+        if target_url.contains("/search?q=")
+            && let Some(query_start) = target_url.find("q=")
+        {
+            let query_part = &target_url[query_start+2..];
+            let query = query_part.split('&').next().unwrap_or("");
+            let decoded_query = urlencoding::decode(query).unwrap_or_default();
+            
+            // Simulate typing the query gradually with suggestions
+            for i in 1..=decoded_query.len().min(8) {
+                let partial_query = &decoded_query[0..i];
+                let suggest_url = format!("https://suggestqueries.google.com/complete/search?client=chrome&q={}", urlencoding::encode(partial_query));
                 
-                // Simulate typing the query gradually with suggestions
-                for i in 1..=decoded_query.len().min(8) {
-                    let partial_query = &decoded_query[0..i];
-                    let suggest_url = format!("https://suggestqueries.google.com/complete/search?client=chrome&q={}", urlencoding::encode(partial_query));
-                    
-                    let headers = {
-                        let session_guard = session.lock().unwrap();
-                        generate_realistic_headers_v2(&session_guard, &suggest_url, false)
-                    };
-                    
-                    let mut request = client.get(&suggest_url);
-                    for (name, value) in headers {
-                        request = request.header(&name, &value);
-                    }
-                    
-                    let _ = request.send().await;
-                    
-                    // Typing speed simulation
-                    tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(100..400))).await;
+                let headers = {
+                    let session_guard = session.lock().unwrap();
+                    generate_realistic_headers_v2(&session_guard, &suggest_url, false)
+                };
+                
+                let mut request = client.get(&suggest_url);
+                for (name, value) in headers {
+                    request = request.header(&name, &value);
                 }
                 
-                println!("⌨️ Simulated typing behavior for query: {}", decoded_query);
+                let _ = request.send().await;
+                
+                // Typing speed simulation
+                tokio::time::sleep(Duration::from_millis(rand::thread_rng().gen_range(100..400))).await;
             }
+            
+            tracing::debug!(query = %decoded_query, "simulated typing");
         }
     }
 
@@ -1402,25 +1878,369 @@ async fn simulate_advanced_browsing_behavior(session: &Arc<Mutex<AdvancedSession
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    println!("Server starting...");
-    
+    let cli = Cli::parse();
+    let proxy_config = match ProxyConfig::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.check_config {
+        println!("✅ Configuration is valid");
+        return Ok(());
+    }
+    if let Some(path) = &cli.verify_audit_log {
+        std::process::exit(verify_audit_log(path, cli.expect_head.as_deref(), &proxy_config));
+    }
+
+    if let Err(e) = telemetry::init(&proxy_config.logging) {
+        eprintln!("❌ Failed to initialise logging: {}", e);
+        std::process::exit(1);
+    }
+    tracing::info!(redirect_mode = ?proxy_config.redirect_mode, "server starting");
+
+    let mut key_event = "pqc_keys_generated";
+    if let Some(path) = &proxy_config.pqc_key_file {
+        if path.exists() {
+            key_event = "pqc_keys_loaded";
+        }
+        let keys = PqcKeyPair::load_or_create(path)
+            .map_err(|e| std::io::Error::other(format!("Failed to load PQC keys: {}", e)))?;
+        tracing::info!(path = %path.display(), "loaded PQC keys");
+        let _ = PQC_KEYS.set(keys);
+    }
+    audit::init(&proxy_config.audit, &PQC_INSTANCE)
+        .map_err(|e| std::io::Error::other(format!("Failed to open audit log: {}", e)))?;
+    audit::record("server_started", &[("version", env!("CARGO_PKG_VERSION"))]);
+    let (_, dilithium_pk, _) = PQC_INSTANCE.get_public_keys();
+    audit::record(key_event, &[("dilithium_fingerprint", &PQC_INSTANCE.hash_data(dilithium_pk.as_bytes()))]);
+
     // Initialize session storage
-    let session_storage: SessionStorage = Arc::new(Mutex::new(HashMap::new()));
+    let session_storage: SessionStorage = Arc::new(session_map::SessionMap::new(proxy_config.session.max_sessions));
+    tokio::spawn(reap_sessions(session_storage.clone(), proxy_config.session.clone()));
+    if let Some(path) = &proxy_config.session.store_file {
+        if path.exists() {
+            match load_sessions(&session_storage, path, proxy_config.session.ttl_secs) {
+                Ok(restored) => tracing::info!(path = %path.display(), restored, "restored browsing sessions"),
+                Err(e) => tracing::warn!(error = %e, "could not restore browsing sessions"),
+            }
+        }
+
+        let (storage, path) = (session_storage.clone(), path.clone());
+        let interval = Duration::from_secs(proxy_config.session.save_secs);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = save_sessions(&storage, &path) {
+                    tracing::warn!(error = %e, "could not save browsing sessions");
+                }
+            }
+        });
+    }
+
+    let domain_policy = PolicyStore::open(proxy_config.policy_file.clone())
+        .map(Arc::new)
+        .map_err(|e| std::io::Error::other(format!("Failed to load domain policy: {}", e)))?;
+    if let Some(path) = &proxy_config.policy_file {
+        tracing::info!(path = %path.display(), rules = domain_policy.current().len(), "loaded domain policy");
+    }
+    let rate_limiter = Arc::new(RateLimiter::new(&proxy_config.limits));
+    let authenticator = actix_web::web::Data::new(Authenticator::new(proxy_config.auth.clone()));
+    if proxy_config.auth.required {
+        tracing::info!(api_keys = proxy_config.auth.api_keys.len(), "authentication required");
+    }
+    domain_policy.clone().watch(Duration::from_secs(proxy_config.policy_reload_secs.max(1)));
+
+    // One client for the JSON-envelope endpoints, guarded like `/proxy`
+    let ssrf_policy = Arc::new(proxy_config.ssrf.clone());
+    let envelope_client = Client::builder()
+        .timeout(Duration::from_secs(proxy_config.timeouts.legacy_proxy_secs))
+        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
+        .redirect(guarded_redirect_policy(proxy_config.max_redirects, ssrf_policy.clone(), domain_policy.clone()))
+        .dns_resolver(Arc::new(ssrf::GuardedResolver::new(ssrf_policy)))
+        .tcp_keepalive(Duration::from_secs(proxy_config.timeouts.tcp_keepalive_secs))
+        .pool_idle_timeout(Duration::from_secs(proxy_config.timeouts.pool_idle_secs))
+        .build()
+        .map_err(|e| std::io::Error::other(format!("Failed to build HTTP client: {}", e)))?;
+    let envelope_client = actix_web::web::Data::new(envelope_client);
+    let pqc_sessions = actix_web::web::Data::new(pqc::PqcSessionStore::new(Duration::from_secs(proxy_config.session.ttl_secs), proxy_config.session.max_pqc_sessions));
+    let shutdown_settings = proxy_config.shutdown.clone();
+    if let Some(path) = &shutdown_settings.pqc_sessions_file
+        && path.exists()
+    {
+        match pqc_sessions.load(path) {
+            Ok(restored) => tracing::info!(path = %path.display(), restored, "restored PQC sessions"),
+            Err(e) => tracing::warn!(error = %e, "could not restore PQC sessions"),
+        }
+    }
+    let metrics = actix_web::web::Data::new(Metrics::new());
+    let access_log = access_log::AccessLog::open(&proxy_config.access_log)
+        .map(actix_web::web::Data::new)
+        .map_err(|e| std::io::Error::other(format!("Failed to open access log: {}", e)))?;
+    if let Some(path) = &proxy_config.access_log.path {
+        tracing::info!(path = %path.display(), "writing access log");
+    }
+    let health = actix_web::web::Data::new(Health::new());
+    let server_health = health.clone();
+    // Keyed on the Dilithium secret key, so tokens outlive restarts when the keys are kept in a file
+    let session_tokens = actix_web::web::Data::new(SessionTokens::new(PQC_INSTANCE.key_pair.dilithium_secret_key.as_bytes()));
+    // Kept outside the server so they can be flushed once it has stopped
+    let in_flight = actix_web::web::Data::new(shutdown::InFlight::default());
+    let draining = in_flight.clone();
+    let (final_metrics, final_access_log, final_pqc_sessions) = (metrics.clone(), access_log.clone(), pqc_sessions.clone());
+    let (final_sessions, session_store_file) = (session_storage.clone(), proxy_config.session.store_file.clone());
     
+    let listeners = proxy_config.listeners.clone();
+    let tls = proxy_config.tls.clone();
+    let workers = proxy_config.workers;
+    let cert_store = if tls.enabled() {
+        let store = tls::CertStore::open(&tls)
+            .map(Arc::new)
+            .map_err(|e| std::io::Error::other(format!("Failed to load TLS certificates: {}", e)))?;
+        store.clone().watch(Duration::from_secs(tls.reload_secs.max(1)));
+        Some(store)
+    } else {
+        None
+    };
+
     // Create and start HTTP server
-    actix_web::HttpServer::new(move || {
+    let mut server = actix_web::HttpServer::new(move || {
         actix_web::App::new()
+            .wrap(actix_web::middleware::from_fn(metrics::track))
+            .wrap(actix_web::middleware::from_fn(shutdown::track))
+            .wrap(actix_web::middleware::from_fn(telemetry::request_span))
+            .app_data(in_flight.clone())
             .app_data(actix_web::web::Data::new(session_storage.clone()))
-            .route("/proxy", actix_web::web::get().to(proxy))
-            .route("/proxy", actix_web::web::post().to(proxy))
+            .app_data(actix_web::web::Data::new(proxy_config.clone()))
+            .app_data(actix_web::web::Data::from(domain_policy.clone()))
+            .app_data(actix_web::web::Data::new(rate_limiter.clone()))
+            .app_data(authenticator.clone())
+            .app_data(envelope_client.clone())
+            .app_data(pqc_sessions.clone())
+            .app_data(metrics.clone())
+            .app_data(server_health.clone())
+            .app_data(access_log.clone())
+            .app_data(session_tokens.clone())
+            .service(
+                actix_web::web::resource("/proxy")
+                    .wrap(actix_web::middleware::from_fn(session_token::attach))
+                    .wrap(actix_web::middleware::from_fn(access_log::record))
+                    .route(actix_web::web::get().to(proxy))
+                    .route(actix_web::web::post().to(proxy))
+                    .route(actix_web::web::put().to(proxy))
+                    .route(actix_web::web::delete().to(proxy))
+                    .route(actix_web::web::patch().to(proxy))
+                    .route(actix_web::web::head().to(proxy))
+                    .route(actix_web::web::method(actix_web::http::Method::OPTIONS).to(proxy)),
+            )
             .route("/pqc_info", actix_web::web::get().to(pqc_info))
             .route("/pqc-info", actix_web::web::get().to(pqc_info))  // Extension compatibility
-            .route("/pqc_handshake", actix_web::web::post().to(pqc_handshake))
+            .service(
+                actix_web::web::resource("/proxy-legacy")
+                    .wrap(actix_web::middleware::from_fn(session_token::attach))
+                    .wrap(actix_web::middleware::from_fn(access_log::record))
+                    .route(actix_web::web::post().to(proxy_handler)),
+            )
+            .service(
+                actix_web::web::resource("/pqc-proxy")
+                    .wrap(actix_web::middleware::from_fn(session_token::attach))
+                    .wrap(actix_web::middleware::from_fn(access_log::record))
+                    .route(actix_web::web::post().to(pqc_proxy_handler)),
+            )
+            .service(
+                // Documented spelling and the one the extension uses
+                actix_web::web::resource(["/pqc-handshake", "/pqc_handshake"])
+                    .wrap(actix_web::middleware::from_fn(access_log::record))
+                    .route(actix_web::web::post().to(pqc_handshake)),
+            )
+            .route("/auth/token", actix_web::web::post().to(issue_token))
+            .service(
+                actix_web::web::resource("/session")
+                    .wrap(actix_web::middleware::from_fn(session_token::attach))
+                    .route(actix_web::web::post().to(new_session)),
+            )
+            .route("/metrics", actix_web::web::get().to(metrics_handler))
+            .service(
+                actix_web::web::resource("/admin/sessions")
+                    .route(actix_web::web::get().to(admin_list_sessions))
+                    .route(actix_web::web::delete().to(admin_flush_sessions)),
+            )
+            .service(
+                actix_web::web::resource("/admin/sessions/{id}")
+                    .route(actix_web::web::get().to(admin_get_session))
+                    .route(actix_web::web::delete().to(admin_delete_session)),
+            )
+            .service(
+                actix_web::web::resource("/admin/sessions/{id}/cookies")
+                    .route(actix_web::web::get().to(admin_export_session_cookies))
+                    .route(actix_web::web::put().to(admin_import_session_cookies))
+                    .route(actix_web::web::post().to(admin_import_session_cookies))
+                    .route(actix_web::web::delete().to(admin_clear_session_cookies)),
+            )
+            .route("/admin/limits", actix_web::web::get().to(admin_limits))
+            .route("/healthz", actix_web::web::get().to(healthz))
+            .route("/readyz", actix_web::web::get().to(readyz))
             .route("/", actix_web::web::get().to(|| async {
                 actix_web::HttpResponse::Ok().body("VPN Server with PQC - Proxy available at /proxy")
             }))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    });
+    if workers > 0 {
+        server = server.workers(workers);
+    }
+    for listener in &listeners {
+        server = server.bind(listener)?;
+        tracing::info!(address = %listener, "listening on HTTP");
+    }
+    if let Some(store) = &cert_store {
+        let server_config = store.server_config().map_err(std::io::Error::other)?;
+        for listener in &tls.listeners {
+            server = server.bind_rustls_0_23(listener, server_config.clone())?;
+            tracing::info!(address = %listener, "listening on HTTPS");
+        }
+    }
+    health.set_listeners(server.addrs());
+    let server = server.disable_signals().run();
+    let mut handles = vec![server.handle()];
+
+    let serve = if tls.redirect_listeners.is_empty() {
+        futures_util::future::Either::Left(server)
+    } else {
+        // Plain-HTTP listeners that only send clients over to the first HTTPS listener
+        let https_port = tls.listeners[0].to_socket_addrs()?.next().map_or(443, |addr| addr.port());
+        let public_host = tls.public_host.as_deref().and_then(tls::parse_authority);
+        let mut redirect_server = actix_web::HttpServer::new(move || {
+            actix_web::App::new()
+                .wrap(actix_web::middleware::from_fn(telemetry::request_span))
+                .app_data(actix_web::web::Data::new(HttpsRedirect { public_host: public_host.clone(), port: https_port }))
+                .default_service(actix_web::web::to(redirect_to_https))
+        })
+        .workers(1);
+        for listener in &tls.redirect_listeners {
+            redirect_server = redirect_server.bind(listener)?;
+            tracing::info!(address = %listener, "redirecting HTTP to HTTPS");
+        }
+        let redirect_server = redirect_server.disable_signals().run();
+        handles.push(redirect_server.handle());
+        futures_util::future::Either::Right(async move { futures_util::future::try_join(server, redirect_server).await.map(|_| ()) })
+    };
+    actix_web::rt::spawn(shutdown::stop_on_signal(handles, draining, Duration::from_secs(shutdown_settings.timeout_secs)));
+    let result = serve.await;
+
+    // Nothing is being served any more; persist what should outlive the process
+    tracing::info!("server stopped");
+    if let Some(path) = &shutdown_settings.pqc_sessions_file {
+        match final_pqc_sessions.save(path) {
+            Ok(saved) => tracing::info!(path = %path.display(), saved, "saved PQC sessions"),
+            Err(e) => tracing::warn!(error = %e, "could not save PQC sessions"),
+        }
+    }
+    if let Some(path) = &session_store_file {
+        match save_sessions(&final_sessions, path) {
+            Ok(saved) => tracing::info!(path = %path.display(), saved, "saved browsing sessions"),
+            Err(e) => tracing::warn!(error = %e, "could not save browsing sessions"),
+        }
+    }
+    audit::record("server_stopped", &[]);
+    audit::checkpoint();
+    final_access_log.flush();
+    final_metrics.log_totals();
+    result
+}
+
+/// `--verify-audit-log`: check the log against the server's Dilithium key and return the exit code
+fn verify_audit_log(path: &std::path::Path, expect_head: Option<&str>, config: &ProxyConfig) -> i32 {
+    let Some(key_file) = config.pqc_key_file.as_ref().filter(|p| p.is_file()) else {
+        eprintln!("❌ Verifying an audit log needs the server's PQC key file (pqc.key_file or --pqc-key-file)");
+        return 1;
+    };
+    match PqcKeyPair::load_or_create(key_file) {
+        Ok(keys) => {
+            let _ = PQC_KEYS.set(keys);
+        }
+        Err(e) => {
+            eprintln!("❌ Failed to load PQC keys: {}", e);
+            return 1;
+        }
+    }
+
+    let (_, dilithium_pk, _) = PQC_INSTANCE.get_public_keys();
+    match audit::verify(path, &PQC_INSTANCE, &dilithium_pk, expect_head) {
+        Ok(report) => {
+            println!("Audit log chain intact: {} entries, {} signed checkpoints", report.entries, report.checkpoints);
+            if let Some((seq, hash)) = &report.head {
+                println!("   head: entry {} ({})", seq, hash);
+            }
+            let mut failed = !report.is_complete();
+            if report.checkpoints == 0 {
+                eprintln!("❌ The log has no signed checkpoint");
+            }
+            if report.unsigned_tail > 0 {
+                eprintln!("❌ The last {} entries are not covered by a checkpoint; the log may have been cut short", report.unsigned_tail);
+            }
+            if report.torn_tail > 0 {
+                eprintln!("❌ The log ends in a partly written entry ({} bytes), left out of the check", report.torn_tail);
+            }
+            if let Some(hash) = expect_head {
+                match report.expected_head {
+                    Some(seq) => println!("   expected head found at entry {}", seq),
+                    None => {
+                        eprintln!("❌ No entry has the expected head {}; the log was truncated or is not the one logged", hash);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                return 1;
+            }
+            println!("✅ Audit log verified");
+            0
+        }
+        Err(e) => {
+            eprintln!("❌ Audit log verification failed: {}", e);
+            1
+        }
+    }
+}
+
+/// Where the plain-HTTP redirect listeners send clients
+struct HttpsRedirect {
+    /// `tls.public_host`, split into host and optional port
+    public_host: Option<(String, Option<u16>)>,
+    /// Port of the first HTTPS listener
+    port: u16,
+}
+
+async fn redirect_to_https(req: HttpRequest, target: web::Data<HttpsRedirect>) -> HttpResponse {
+    // Only the configured host or the request's own Host header; never X-Forwarded-Host or
+    // Forwarded, which any client can set
+    let authority = match &target.public_host {
+        Some((host, port)) => Some((host.clone(), port.unwrap_or(target.port))),
+        None => req
+            .headers()
+            .get(actix_web::http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(tls::parse_authority)
+            .map(|(host, _)| (host, target.port)),
+    };
+    let Some((host, port)) = authority else {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Missing or invalid Host header" }));
+    };
+    let authority = match port {
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+
+    // 308 keeps the method and body of non-GET requests
+    let mut response = if matches!(*req.method(), actix_web::http::Method::GET | actix_web::http::Method::HEAD) {
+        HttpResponse::MovedPermanently()
+    } else {
+        HttpResponse::PermanentRedirect()
+    };
+    response
+        .insert_header(("Location", format!("https://{}{}", authority, path)))
+        .finish()
 }
@@ -0,0 +1,373 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::limits::ScopeStats;
+use crate::telemetry::RequestInfo;
+
+/// Upper bounds of the upstream latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 13] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Distinct destination hosts tracked before the rest are counted as `other`
+const MAX_HOSTS: usize = 500;
+
+/// Which part of the server made an upstream request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    /// `/proxy`
+    Proxy,
+    /// `/proxy-legacy` and `/pqc-proxy`
+    Envelope,
+}
+
+impl Upstream {
+    fn label(self) -> &'static str {
+        match self {
+            Upstream::Proxy => "proxy",
+            Upstream::Envelope => "envelope",
+        }
+    }
+}
+
+/// Why an upstream request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamError {
+    Timeout,
+    Dns,
+    Tls,
+    Connect,
+    Redirect,
+    /// Refused by the SSRF or domain policy while connecting or redirecting
+    Blocked,
+    Other,
+}
+
+impl UpstreamError {
+    pub fn classify(error: &reqwest::Error) -> Self {
+        if crate::ssrf::blocked_reason(error).is_some() {
+            return UpstreamError::Blocked;
+        }
+        if error.is_timeout() {
+            return UpstreamError::Timeout;
+        }
+        if error.is_redirect() {
+            return UpstreamError::Redirect;
+        }
+
+        // reqwest doesn't expose DNS and TLS failures as kinds, only in its source chain
+        let mut chain = String::new();
+        let mut source: Option<&dyn std::error::Error> = Some(error);
+        while let Some(err) = source {
+            let _ = write!(chain, "{} ", err);
+            source = err.source();
+        }
+        let chain = chain.to_ascii_lowercase();
+        if chain.contains("dns error") || chain.contains("failed to lookup address") {
+            UpstreamError::Dns
+        } else if ["certificate", "tls", "handshake"].iter().any(|word| chain.contains(word)) {
+            UpstreamError::Tls
+        } else if error.is_connect() {
+            UpstreamError::Connect
+        } else {
+            UpstreamError::Other
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            UpstreamError::Timeout => "timeout",
+            UpstreamError::Dns => "dns",
+            UpstreamError::Tls => "tls",
+            UpstreamError::Connect => "connect",
+            UpstreamError::Redirect => "redirect",
+            UpstreamError::Blocked => "blocked",
+            UpstreamError::Other => "other",
+        }
+    }
+}
+
+/// A PQC operation counted by the metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PqcOperation {
+    Handshake,
+    Encapsulate,
+    Encrypt,
+    Sign,
+}
+
+impl PqcOperation {
+    fn label(self) -> &'static str {
+        match self {
+            PqcOperation::Handshake => "handshake",
+            PqcOperation::Encapsulate => "encapsulate",
+            PqcOperation::Encrypt => "encrypt",
+            PqcOperation::Sign => "sign",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+struct RequestCounts {
+    /// Keyed by method, status class and destination host
+    counts: HashMap<(String, &'static str, String), u64>,
+    /// Hosts with their own label; any beyond `MAX_HOSTS` are counted as `other`
+    hosts: HashSet<String>,
+}
+
+impl RequestCounts {
+    /// The label for `host`, giving it its own while there is room
+    fn host_label<'a>(&mut self, host: &'a str) -> &'a str {
+        if self.hosts.contains(host) {
+            return host;
+        }
+        if self.hosts.len() < MAX_HOSTS {
+            self.hosts.insert(host.to_string());
+            return host;
+        }
+        "other"
+    }
+}
+
+/// Counters shared by all workers, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<RequestCounts>,
+    latency: Mutex<HashMap<Upstream, Histogram>>,
+    upstream_errors: Mutex<HashMap<UpstreamError, u64>>,
+    /// Keyed by operation and whether it succeeded
+    pqc: Mutex<HashMap<(PqcOperation, bool), u64>>,
+    bytes_from_upstream: AtomicU64,
+    bytes_to_clients: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record_request(&self, method: &str, status: u16, host: Option<&str>) {
+        let class = match status {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        };
+        let mut requests = self.requests.lock().unwrap();
+        let host = match host {
+            None => "",
+            Some(host) => requests.host_label(host),
+        };
+        *requests.counts.entry((method.to_string(), class, host.to_string())).or_insert(0) += 1;
+    }
+
+    /// Time from sending an upstream request to receiving its response headers
+    pub fn observe_upstream(&self, upstream: Upstream, elapsed: Duration) {
+        self.latency.lock().unwrap().entry(upstream).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    pub fn upstream_error(&self, error: &reqwest::Error) {
+        *self.upstream_errors.lock().unwrap().entry(UpstreamError::classify(error)).or_insert(0) += 1;
+    }
+
+    pub fn pqc(&self, operation: PqcOperation, succeeded: bool) {
+        *self.pqc.lock().unwrap().entry((operation, succeeded)).or_insert(0) += 1;
+    }
+
+    /// Response body bytes read from upstream servers
+    pub fn bytes_in(&self, bytes: usize) {
+        self.bytes_from_upstream.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Response body bytes sent to clients
+    pub fn bytes_out(&self, bytes: usize) {
+        self.bytes_to_clients.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Log the lifetime totals, so they aren't lost with the process
+    pub fn log_totals(&self) {
+        let requests: u64 = self.requests.lock().unwrap().counts.values().sum();
+        let upstream_errors: u64 = self.upstream_errors.lock().unwrap().values().sum();
+        tracing::info!(
+            requests,
+            upstream_errors,
+            bytes_in = self.bytes_from_upstream.load(Ordering::Relaxed),
+            bytes_out = self.bytes_to_clients.load(Ordering::Relaxed),
+            "final metrics"
+        );
+    }
+
+    /// The exposition text, including gauges sampled by the caller
+    pub fn render(&self, active_sessions: usize, limits: &[(&'static str, ScopeStats)]) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "vpn_requests_total", "counter", "Requests handled, by method, status class and destination host");
+        let mut requests: Vec<_> = self.requests.lock().unwrap().counts.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        requests.sort();
+        for ((method, class, host), count) in requests {
+            let _ = writeln!(
+                out,
+                "vpn_requests_total{{method=\"{}\",status=\"{}\",host=\"{}\"}} {}",
+                escape(&method), class, escape(&host), count
+            );
+        }
+
+        header(&mut out, "vpn_upstream_latency_seconds", "histogram", "Time until upstream response headers arrive");
+        let latency = self.latency.lock().unwrap();
+        for upstream in [Upstream::Proxy, Upstream::Envelope] {
+            let Some(histogram) = latency.get(&upstream) else {
+                continue;
+            };
+            let label = upstream.label();
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(out, "vpn_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}", label, bound, count);
+            }
+            let _ = writeln!(out, "vpn_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}", label, histogram.count);
+            let _ = writeln!(out, "vpn_upstream_latency_seconds_sum{{upstream=\"{}\"}} {}", label, histogram.sum);
+            let _ = writeln!(out, "vpn_upstream_latency_seconds_count{{upstream=\"{}\"}} {}", label, histogram.count);
+        }
+        drop(latency);
+
+        header(&mut out, "vpn_upstream_errors_total", "counter", "Failed upstream requests, by kind");
+        let errors = self.upstream_errors.lock().unwrap();
+        for kind in [
+            UpstreamError::Timeout,
+            UpstreamError::Dns,
+            UpstreamError::Tls,
+            UpstreamError::Connect,
+            UpstreamError::Redirect,
+            UpstreamError::Blocked,
+            UpstreamError::Other,
+        ] {
+            let count = errors.get(&kind).copied().unwrap_or(0);
+            let _ = writeln!(out, "vpn_upstream_errors_total{{kind=\"{}\"}} {}", kind.label(), count);
+        }
+        drop(errors);
+
+        header(&mut out, "vpn_bytes_total", "counter", "Response body bytes read from upstreams (in) and sent to clients (out)");
+        let _ = writeln!(out, "vpn_bytes_total{{direction=\"in\"}} {}", self.bytes_from_upstream.load(Ordering::Relaxed));
+        let _ = writeln!(out, "vpn_bytes_total{{direction=\"out\"}} {}", self.bytes_to_clients.load(Ordering::Relaxed));
+
+        header(&mut out, "vpn_pqc_operations_total", "counter", "PQC handshakes, key encapsulations, encryptions and signatures, by result");
+        let pqc = self.pqc.lock().unwrap();
+        for operation in [PqcOperation::Handshake, PqcOperation::Encapsulate, PqcOperation::Encrypt, PqcOperation::Sign] {
+            for (succeeded, result) in [(true, "success"), (false, "failure")] {
+                let count = pqc.get(&(operation, succeeded)).copied().unwrap_or(0);
+                let _ = writeln!(out, "vpn_pqc_operations_total{{operation=\"{}\",result=\"{}\"}} {}", operation.label(), result, count);
+            }
+        }
+        drop(pqc);
+
+        header(&mut out, "vpn_active_sessions", "gauge", "Browsing sessions currently held");
+        let _ = writeln!(out, "vpn_active_sessions {}", active_sessions);
+
+        header(&mut out, "vpn_rate_limit_decisions_total", "counter", "Rate limiter decisions, by scope and outcome");
+        for (scope, stats) in limits {
+            for (outcome, count) in [
+                ("allowed", stats.allowed),
+                ("rate_limited", stats.rate_limited),
+                ("concurrency_limited", stats.concurrency_limited),
+            ] {
+                let _ = writeln!(out, "vpn_rate_limit_decisions_total{{scope=\"{}\",outcome=\"{}\"}} {}", scope, outcome, count);
+            }
+        }
+        header(&mut out, "vpn_rate_limit_in_flight", "gauge", "Requests holding a rate limiter slot, by scope");
+        for (scope, stats) in limits {
+            let _ = writeln!(out, "vpn_rate_limit_in_flight{{scope=\"{}\"}} {}", scope, stats.in_flight);
+        }
+        header(&mut out, "vpn_rate_limit_tracked_keys", "gauge", "Clients, sessions or hosts with limiter state, by scope");
+        for (scope, stats) in limits {
+            let _ = writeln!(out, "vpn_rate_limit_tracked_keys{{scope=\"{}\"}} {}", scope, stats.tracked_keys);
+        }
+
+        out
+    }
+}
+
+/// Count every request by method, status class and the destination host its handler recorded
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let method = req.method().to_string();
+    let response = next.call(req).await?;
+
+    if let Some(metrics) = metrics {
+        let host = RequestInfo::of(response.request()).target_host;
+        metrics.record_request(&method, response.status().as_u16(), host.as_deref());
+    }
+    Ok(response)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_lines(metrics: &Metrics) -> Vec<String> {
+        metrics.render(0, &[]).lines().filter(|line| line.starts_with("vpn_requests_total{")).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn hosts_past_the_cap_are_counted_as_other() {
+        let metrics = Metrics::new();
+        for n in 0..MAX_HOSTS {
+            // Several series per host must not use up the cap sooner
+            metrics.record_request("GET", 200, Some(&format!("h{}.test", n)));
+            metrics.record_request("POST", 500, Some(&format!("h{}.test", n)));
+        }
+        metrics.record_request("GET", 200, Some("late.test"));
+        metrics.record_request("GET", 404, Some("later.test"));
+        metrics.record_request("GET", 404, Some("h0.test"));
+        metrics.record_request("GET", 200, None);
+
+        let lines = request_lines(&metrics);
+        assert!(lines.contains(&r#"vpn_requests_total{method="POST",status="5xx",host="h499.test"} 1"#.to_string()));
+        assert!(lines.contains(&r#"vpn_requests_total{method="GET",status="4xx",host="h0.test"} 1"#.to_string()));
+        assert!(lines.contains(&r#"vpn_requests_total{method="GET",status="2xx",host="other"} 1"#.to_string()));
+        assert!(lines.contains(&r#"vpn_requests_total{method="GET",status="4xx",host="other"} 1"#.to_string()));
+        assert!(lines.contains(&r#"vpn_requests_total{method="GET",status="2xx",host=""} 1"#.to_string()));
+        assert!(!lines.iter().any(|line| line.contains("late")));
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let metrics = Metrics::new();
+        metrics.record_request("GET", 302, Some("a\"b"));
+        assert_eq!(request_lines(&metrics), vec![r#"vpn_requests_total{method="GET",status="3xx",host="a\"b"} 1"#]);
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+/// What happens to a request matching a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Exact(String),
+    /// `*.example.com`: any subdomain of `example.com`, but not `example.com` itself
+    Subdomains(String),
+}
+
+/// One line of the policy file.
+///
+/// The pattern is `[scheme://]host[:port][/path-prefix]`, where `host` is an exact name,
+/// `*.domain` or `*`:
+///
+/// ```text
+/// deny  *.internal.example.com
+/// allow https://docs.example.com/public/
+/// deny  http://*:8080
+/// allow *
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: Action,
+    scheme: Option<String>,
+    host: HostPattern,
+    port: Option<u16>,
+    path_prefix: Option<String>,
+    /// Line in the policy file, for log messages
+    pub line: usize,
+    pattern: String,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        write!(f, "line {}: {} {}", self.line, action, self.pattern)
+    }
+}
+
+impl Rule {
+    fn parse(line: usize, text: &str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let action = match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            other => return Err(format!("line {}: expected 'allow' or 'deny', got '{}'", line, other.unwrap_or(""))),
+        };
+        let pattern = words.next().ok_or_else(|| format!("line {}: missing pattern", line))?;
+        if words.next().is_some() {
+            return Err(format!("line {}: unexpected text after pattern", line));
+        }
+
+        let (scheme, rest) = match pattern.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+            None => (None, pattern),
+        };
+        if let Some(scheme) = &scheme
+            && !matches!(scheme.as_str(), "http" | "https")
+        {
+            return Err(format!("line {}: unsupported scheme '{}'", line, scheme));
+        }
+
+        let (authority, path_prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(rest[i..].to_string())),
+            None => (rest, None),
+        };
+        // IPv6 literals are bracketed, so only a colon after the closing bracket starts a port
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, rest)) => (host, rest.strip_prefix(':')),
+                None => return Err(format!("line {}: unterminated '['", line)),
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = port
+            .map(|p| p.parse::<u16>().map_err(|_| format!("line {}: invalid port '{}'", line, p)))
+            .transpose()?;
+
+        let host = host.to_ascii_lowercase();
+        let host = match host.as_str() {
+            "" => return Err(format!("line {}: missing host", line)),
+            "*" => HostPattern::Any,
+            _ => match host.strip_prefix("*.") {
+                Some(domain) if !domain.is_empty() && !domain.contains('*') => HostPattern::Subdomains(domain.to_string()),
+                _ if host.contains('*') => return Err(format!("line {}: wildcards are only allowed as a leading '*.'", line)),
+                _ => HostPattern::Exact(host),
+            },
+        };
+
+        Ok(Rule { action, scheme, host, port, path_prefix, line, pattern: pattern.to_string() })
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        if self.scheme.as_deref().is_some_and(|s| s != url.scheme()) {
+            return false;
+        }
+        if self.port.is_some() && self.port != url.port_or_known_default() {
+            return false;
+        }
+        if self.path_prefix.as_deref().is_some_and(|p| !url.path().starts_with(p)) {
+            return false;
+        }
+
+        let host = url.host_str().unwrap_or("").trim_start_matches('[').trim_end_matches(']');
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Subdomains(domain) => host.strip_suffix(domain.as_str()).is_some_and(|p| p.ends_with('.')),
+        }
+    }
+}
+
+/// Ordered allow/deny rules for proxied destinations; the first matching rule wins and
+/// requests matching no rule are allowed
+#[derive(Debug, Clone, Default)]
+pub struct DomainPolicy {
+    rules: Vec<Rule>,
+}
+
+impl DomainPolicy {
+    /// Parse a policy file: one rule per line, `#` starts a comment
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let rules = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split('#').next().unwrap_or("").trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| Rule::parse(number, line))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The first rule matching `url`, if any
+    pub fn matching_rule(&self, url: &Url) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(url))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// The active policy, reloaded from its file when that changes
+pub struct PolicyStore {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<DomainPolicy>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl PolicyStore {
+    /// Load the policy at `path`, or start with an empty (allow everything) policy
+    pub fn open(path: Option<PathBuf>) -> Result<Self, String> {
+        let (policy, modified) = match &path {
+            Some(path) => (DomainPolicy::load(path)?, modified_time(path)),
+            None => (DomainPolicy::default(), None),
+        };
+        Ok(Self {
+            path,
+            current: RwLock::new(Arc::new(policy)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub fn current(&self) -> Arc<DomainPolicy> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Check `url` against the active policy, returning the denying rule if it is refused
+    pub fn check(&self, url: &Url) -> Result<(), Rule> {
+        match self.current().matching_rule(url) {
+            Some(rule) if rule.action == Action::Deny => Err(rule.clone()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-read the policy file. A file that fails to parse leaves the previous policy active.
+    pub fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        *self.modified.lock().unwrap() = modified_time(path);
+        match DomainPolicy::load(path) {
+            Ok(policy) => {
+                tracing::info!(path = %path.display(), rules = policy.len(), "reloaded domain policy");
+                crate::audit::record("policy_reloaded", &[("path", &path.display().to_string()), ("rules", &policy.len().to_string())]);
+                *self.current.write().unwrap() = Arc::new(policy);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "keeping previous domain policy");
+                crate::audit::record("policy_reload_failed", &[("path", &path.display().to_string()), ("error", &e.to_string())]);
+            }
+        }
+    }
+
+    /// Reload the policy whenever its file changes (checked every `interval`) or on SIGHUP
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        if self.path.is_none() {
+            return;
+        }
+
+        #[cfg(unix)]
+        {
+            let store = self.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                    return;
+                };
+                while hangup.recv().await.is_some() {
+                    store.reload();
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let path = self.path.as_deref().unwrap();
+                if modified_time(path) != *self.modified.lock().unwrap() {
+                    self.reload();
+                }
+            }
+        });
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(policy: &DomainPolicy, url: &str) -> Option<(Action, usize)> {
+        policy.matching_rule(&Url::parse(url).unwrap()).map(|rule| (rule.action, rule.line))
+    }
+
+    struct TempPolicy(PathBuf);
+
+    impl TempPolicy {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("vpn-server-policy-{}-{}.txt", name, std::process::id()));
+            fs::write(&path, text).unwrap();
+            TempPolicy(path)
+        }
+    }
+
+    impl Drop for TempPolicy {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn exact_hosts_match_only_themselves() {
+        let policy = DomainPolicy::parse("deny example.com").unwrap();
+        assert_eq!(verdict(&policy, "https://example.com/"), Some((Action::Deny, 1)));
+        assert_eq!(verdict(&policy, "http://EXAMPLE.com./x"), Some((Action::Deny, 1)));
+        assert_eq!(verdict(&policy, "https://www.example.com/"), None);
+        assert_eq!(verdict(&policy, "https://notexample.com/"), None);
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_but_not_the_domain() {
+        let policy = DomainPolicy::parse("deny *.example.com").unwrap();
+        assert_eq!(verdict(&policy, "https://a.example.com/"), Some((Action::Deny, 1)));
+        assert_eq!(verdict(&policy, "https://a.b.example.com/"), Some((Action::Deny, 1)));
+        assert_eq!(verdict(&policy, "https://example.com/"), None);
+        assert_eq!(verdict(&policy, "https://badexample.com/"), None);
+    }
+
+    #[test]
+    fn scheme_port_and_path_narrow_a_rule() {
+        let policy = DomainPolicy::parse("deny http://*:8080\nallow https://docs.example.com/public/\ndeny [::1]").unwrap();
+        assert_eq!(verdict(&policy, "http://a.test:8080/"), Some((Action::Deny, 1)));
+        assert_eq!(verdict(&policy, "https://a.test:8080/"), None);
+        assert_eq!(verdict(&policy, "https://docs.example.com/public/a"), Some((Action::Allow, 2)));
+        assert_eq!(verdict(&policy, "https://docs.example.com/private"), None);
+        assert_eq!(verdict(&policy, "http://docs.example.com/public/a"), None);
+        assert_eq!(verdict(&policy, "http://[::1]:9000/"), Some((Action::Deny, 3)));
+    }
+
+    #[test]
+    fn earlier_deny_wins_over_a_later_allow() {
+        let policy = DomainPolicy::parse("# comment\ndeny *.ads.example.com\nallow *.example.com  # trailing\ndeny *").unwrap();
+        assert_eq!(verdict(&policy, "https://x.ads.example.com/"), Some((Action::Deny, 2)));
+        assert_eq!(verdict(&policy, "https://www.example.com/"), Some((Action::Allow, 3)));
+        assert_eq!(verdict(&policy, "https://other.test/"), Some((Action::Deny, 4)));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected_with_their_line() {
+        for (text, error) in [
+            ("block example.com", "line 1: expected 'allow' or 'deny'"),
+            ("allow\n", "line 1: missing pattern"),
+            ("\nallow a.test b.test", "line 2: unexpected text"),
+            ("deny ftp://a.test", "line 1: unsupported scheme"),
+            ("deny a.*.test", "line 1: wildcards"),
+            ("deny a.test:http", "line 1: invalid port"),
+        ] {
+            let e = DomainPolicy::parse(text).unwrap_err();
+            assert!(e.starts_with(error), "{:?}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn reload_keeps_the_previous_policy_when_the_file_is_broken() {
+        let file = TempPolicy::new("reload", "deny a.test\n");
+        let store = PolicyStore::open(Some(file.0.clone())).unwrap();
+        let a = Url::parse("https://a.test/").unwrap();
+        let b = Url::parse("https://b.test/").unwrap();
+        assert!(store.check(&a).is_err());
+
+        fs::write(&file.0, "deny b.test\n").unwrap();
+        store.reload();
+        assert!(store.check(&a).is_ok());
+        assert_eq!(store.check(&b).unwrap_err().line, 1);
+
+        fs::write(&file.0, "deny b.test\nrefuse a.test\n").unwrap();
+        store.reload();
+        assert!(store.check(&a).is_ok());
+        assert!(store.check(&b).is_err());
+        assert_eq!(store.current().len(), 1);
+    }
+}
@@ -5,6 +5,9 @@ use pqcrypto_traits::kem::{PublicKey as KemPublicKey, SecretKey as KemSecretKey,
 use pqcrypto_traits::sign::{PublicKey as SignPublicKey, SecretKey as SignSecretKey, SignedMessage};
 use serde::{Deserialize, Serialize};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PqcKeyPair {
@@ -16,6 +19,40 @@ pub struct PqcKeyPair {
     pub sphincs_secret_key: String,
 }
 
+impl PqcKeyPair {
+    /// Load a key pair saved as JSON, or generate one and save it (readable by the owner only)
+    pub fn load_or_create(path: &std::path::Path) -> Result<Self, String> {
+        if path.exists() {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            return serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e));
+        }
+
+        let key_pair = PqcCrypto::new().key_pair;
+        let json = serde_json::to_string_pretty(&key_pair).map_err(|e| e.to_string())?;
+        write_private(path, json.as_bytes(), true)?;
+        Ok(key_pair)
+    }
+}
+
+/// Write a file readable by the owner only, either creating it fresh or replacing it
+pub(crate) fn write_private(path: &std::path::Path, contents: &[u8], create_new: bool) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true);
+    if create_new {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    // The mode only applies when the file is created; tighten one that already existed
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    std::io::Write::write_all(&mut file, contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PqcSharedData {
     pub ciphertext: String,
@@ -64,19 +101,6 @@ impl PqcCrypto {
         ))
     }
 
-    /// Perform Kyber key decapsulation
-    pub fn kyber_decapsulate(&self, ciphertext: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let sk_bytes = BASE64.decode(&self.key_pair.kyber_secret_key)?;
-        let sk = kyber768::SecretKey::from_bytes(&sk_bytes)?;
-        
-        let ct_bytes = BASE64.decode(ciphertext)?;
-        let ct = kyber768::Ciphertext::from_bytes(&ct_bytes)?;
-        
-        let shared_secret = kyber768::decapsulate(&ct, &sk);
-        
-        Ok(BASE64.encode(shared_secret.as_bytes()))
-    }
-
     /// Create a Dilithium signature (replaces traditional digital signatures)
     pub fn dilithium_sign(&self, message: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
         let sk_bytes = BASE64.decode(&self.key_pair.dilithium_secret_key)?;
@@ -100,29 +124,6 @@ impl PqcCrypto {
         Ok(verified_message)
     }
 
-    /// Create a SPHINCS+ signature (alternative signature scheme)
-    pub fn sphincs_sign(&self, message: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
-        let sk_bytes = BASE64.decode(&self.key_pair.sphincs_secret_key)?;
-        let sk = sphincssha2128ssimple::SecretKey::from_bytes(&sk_bytes)?;
-        
-        let signed_message = sphincssha2128ssimple::sign(message, &sk);
-        
-        Ok(BASE64.encode(signed_message.as_bytes()))
-    }
-
-    /// Verify a SPHINCS+ signature
-    pub fn sphincs_verify(&self, signed_message_b64: &str, public_key: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let pk_bytes = BASE64.decode(public_key)?;
-        let pk = sphincssha2128ssimple::PublicKey::from_bytes(&pk_bytes)?;
-        
-        let signed_bytes = BASE64.decode(signed_message_b64)?;
-        let signed_msg = sphincssha2128ssimple::SignedMessage::from_bytes(&signed_bytes)?;
-        
-        let verified_message = sphincssha2128ssimple::open(&signed_msg, &pk)?;
-        
-        Ok(verified_message)
-    }
-
     /// Symmetric encryption using shared secret (replaces AES)
     /// This is a simple XOR-based encryption for demonstration
     /// In production, you'd want to use a proper AEAD cipher with the shared secret as key
@@ -137,19 +138,6 @@ impl PqcCrypto {
         Ok(BASE64.encode(&encrypted))
     }
 
-    /// Symmetric decryption using shared secret
-    pub fn symmetric_decrypt(&self, encrypted_data: &str, shared_secret: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let key = BASE64.decode(shared_secret)?;
-        let encrypted = BASE64.decode(encrypted_data)?;
-        let mut decrypted = Vec::new();
-        
-        for (i, &byte) in encrypted.iter().enumerate() {
-            decrypted.push(byte ^ key[i % key.len()]);
-        }
-        
-        Ok(decrypted)
-    }
-
     /// Generate a hash using SHA-3 (quantum-resistant alternative to SHA-256/384)
     pub fn hash_data(&self, data: &[u8]) -> String {
         use sha3::{Digest, Sha3_256};
@@ -183,6 +171,61 @@ impl PqcCrypto {
         })
     }
 
+}
+
+/// The peer's half of a handshake and the alternative SPHINCS+ scheme. The server itself only
+/// initiates handshakes and signs with Dilithium, so nothing in this binary calls these.
+#[allow(dead_code)]
+impl PqcCrypto {
+    /// Perform Kyber key decapsulation
+    pub fn kyber_decapsulate(&self, ciphertext: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let sk_bytes = BASE64.decode(&self.key_pair.kyber_secret_key)?;
+        let sk = kyber768::SecretKey::from_bytes(&sk_bytes)?;
+        
+        let ct_bytes = BASE64.decode(ciphertext)?;
+        let ct = kyber768::Ciphertext::from_bytes(&ct_bytes)?;
+        
+        let shared_secret = kyber768::decapsulate(&ct, &sk);
+        
+        Ok(BASE64.encode(shared_secret.as_bytes()))
+    }
+
+    /// Create a SPHINCS+ signature (alternative signature scheme)
+    pub fn sphincs_sign(&self, message: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let sk_bytes = BASE64.decode(&self.key_pair.sphincs_secret_key)?;
+        let sk = sphincssha2128ssimple::SecretKey::from_bytes(&sk_bytes)?;
+        
+        let signed_message = sphincssha2128ssimple::sign(message, &sk);
+        
+        Ok(BASE64.encode(signed_message.as_bytes()))
+    }
+
+    /// Verify a SPHINCS+ signature
+    pub fn sphincs_verify(&self, signed_message_b64: &str, public_key: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let pk_bytes = BASE64.decode(public_key)?;
+        let pk = sphincssha2128ssimple::PublicKey::from_bytes(&pk_bytes)?;
+        
+        let signed_bytes = BASE64.decode(signed_message_b64)?;
+        let signed_msg = sphincssha2128ssimple::SignedMessage::from_bytes(&signed_bytes)?;
+        
+        let verified_message = sphincssha2128ssimple::open(&signed_msg, &pk)?;
+        
+        Ok(verified_message)
+    }
+
+    /// Symmetric decryption using shared secret
+    pub fn symmetric_decrypt(&self, encrypted_data: &str, shared_secret: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let key = BASE64.decode(shared_secret)?;
+        let encrypted = BASE64.decode(encrypted_data)?;
+        let mut decrypted = Vec::new();
+        
+        for (i, &byte) in encrypted.iter().enumerate() {
+            decrypted.push(byte ^ key[i % key.len()]);
+        }
+        
+        Ok(decrypted)
+    }
+
     /// Verify and establish secure session
     pub fn verify_secure_session(&self, session_data: &PqcSharedData, peer_dilithium_pk: &str) -> Result<String, Box<dyn std::error::Error>> {
         // 1. Decapsulate to get shared secret
@@ -205,3 +248,184 @@ impl Default for PqcCrypto {
         Self::new()
     }
 }
+
+/// Shared secrets established by handshakes, so later requests can refer to them by session id
+pub struct PqcSessionStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+struct StoredSession {
+    shared_secret: String,
+    last_used: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    shared_secret: String,
+    idle_secs: u64,
+}
+
+impl PqcSessionStore {
+    /// Sessions unused for `ttl` are forgotten, and past `capacity` the least recently used are
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { sessions: Mutex::new(HashMap::new()), ttl, capacity: capacity.max(1) }
+    }
+
+    /// Remember `shared_secret` under `session_id`, dropping expired sessions and, when full,
+    /// the least recently used
+    pub fn insert(&self, session_id: &str, shared_secret: String) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| now.duration_since(session.last_used) < self.ttl);
+        if !sessions.contains_key(session_id) {
+            evict_least_recently_used(&mut sessions, self.capacity - 1);
+        }
+        sessions.insert(session_id.to_string(), StoredSession { shared_secret, last_used: now });
+    }
+
+    /// The shared secret of a live session, keeping it alive
+    pub fn shared_secret(&self, session_id: &str) -> Option<String> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(session_id)?;
+        if now.duration_since(session.last_used) >= self.ttl {
+            sessions.remove(session_id);
+            return None;
+        }
+        session.last_used = now;
+        Some(session.shared_secret.clone())
+    }
+
+    /// Sessions that have not expired yet
+    pub fn live_count(&self) -> usize {
+        let now = Instant::now();
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().filter(|session| now.duration_since(session.last_used) < self.ttl).count()
+    }
+
+    /// Whether the store can still be locked, i.e. no thread panicked while holding it
+    pub fn is_usable(&self) -> bool {
+        self.sessions.lock().is_ok()
+    }
+
+    /// Save live sessions (readable by the owner only) so they survive a restart
+    pub fn save(&self, path: &std::path::Path) -> Result<usize, String> {
+        let now = Instant::now();
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let saved: HashMap<&String, SavedSession> = sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_used) < self.ttl)
+            .map(|(id, session)| {
+                let idle_secs = now.duration_since(session.last_used).as_secs();
+                (id, SavedSession { shared_secret: session.shared_secret.clone(), idle_secs })
+            })
+            .collect();
+        let json = serde_json::to_vec(&saved).map_err(|e| e.to_string())?;
+        let count = saved.len();
+        drop(saved);
+        drop(sessions);
+        write_private(path, &json, false)?;
+        Ok(count)
+    }
+
+    /// Restore sessions written by `save`, keeping how long each had been idle
+    pub fn load(&self, path: &std::path::Path) -> Result<usize, String> {
+        let text = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let saved: HashMap<String, SavedSession> =
+            serde_json::from_slice(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        for (id, session) in saved {
+            let idle = Duration::from_secs(session.idle_secs);
+            if idle >= self.ttl {
+                continue;
+            }
+            let last_used = now.checked_sub(idle).unwrap_or(now);
+            sessions.insert(id, StoredSession { shared_secret: session.shared_secret, last_used });
+        }
+        evict_least_recently_used(&mut sessions, self.capacity);
+        Ok(sessions.len())
+    }
+}
+
+/// Drop the least recently used sessions until at most `keep` are left
+fn evict_least_recently_used(sessions: &mut HashMap<String, StoredSession>, keep: usize) {
+    let Some(excess) = sessions.len().checked_sub(keep).filter(|&n| n > 0) else {
+        return;
+    };
+    let mut by_use: Vec<(Instant, String)> = sessions.iter().map(|(id, session)| (session.last_used, id.clone())).collect();
+    by_use.sort_unstable();
+    for (_, id) in by_use.into_iter().take(excess) {
+        sessions.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_recovers_the_shared_secret_of_a_handshake() {
+        let (server, peer) = (PqcCrypto::new(), PqcCrypto::new());
+        let session = server.create_secure_session(&peer.key_pair.kyber_public_key).unwrap();
+        let shared_secret = peer.verify_secure_session(&session, &server.key_pair.dilithium_public_key).unwrap();
+        assert_eq!(shared_secret, session.shared_secret);
+
+        let encrypted = server.symmetric_encrypt(b"hello", &shared_secret).unwrap();
+        assert_eq!(peer.symmetric_decrypt(&encrypted, &shared_secret).unwrap(), b"hello");
+        assert!(peer.verify_secure_session(&session, &peer.key_pair.dilithium_public_key).is_err());
+    }
+
+    #[test]
+    fn session_store_evicts_the_least_recently_used_past_capacity() {
+        let store = PqcSessionStore::new(Duration::from_secs(60), 2);
+        store.insert("a", "1".to_string());
+        std::thread::sleep(Duration::from_millis(2));
+        store.insert("b", "2".to_string());
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(store.shared_secret("a").is_some());
+        store.insert("c", "3".to_string());
+        assert_eq!(store.live_count(), 2);
+        assert!(store.shared_secret("b").is_none());
+        assert_eq!(store.shared_secret("a").as_deref(), Some("1"));
+
+        // Replacing a session's secret doesn't evict another one
+        store.insert("a", "4".to_string());
+        assert_eq!((store.live_count(), store.shared_secret("c").as_deref()), (2, Some("3")));
+    }
+
+    #[test]
+    fn session_store_expires_and_caps_loaded_sessions() {
+        let path = std::env::temp_dir().join(format!("vpn-server-pqc-sessions-{}.json", std::process::id()));
+        let saved = HashMap::from([
+            ("fresh", SavedSession { shared_secret: "1".to_string(), idle_secs: 5 }),
+            ("older", SavedSession { shared_secret: "2".to_string(), idle_secs: 30 }),
+            ("oldest", SavedSession { shared_secret: "3".to_string(), idle_secs: 50 }),
+            ("expired", SavedSession { shared_secret: "4".to_string(), idle_secs: 90 }),
+        ]);
+        std::fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let store = PqcSessionStore::new(Duration::from_secs(60), 2);
+        let loaded = store.load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, Ok(2));
+        assert!(store.shared_secret("fresh").is_some() && store.shared_secret("older").is_some());
+        assert!(store.shared_secret("oldest").is_none() && store.shared_secret("expired").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_private_tightens_an_existing_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("vpn-server-private-{}.json", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"new", false).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((mode, contents.as_str()), (0o600, "new"));
+    }
+}
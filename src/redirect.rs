@@ -0,0 +1,78 @@
+use url::Url;
+
+/// Path of the query-parameter proxy endpoint that rewritten URLs point back to
+pub const PROXY_PATH: &str = "/proxy";
+
+/// How resolved URLs are emitted by the rewriters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlMode {
+    /// Absolute URLs on the origin server
+    Absolute,
+    /// Absolute URLs routed back through `/proxy?url=`
+    Proxied,
+}
+
+impl UrlMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "absolute" => Some(UrlMode::Absolute),
+            "proxy" | "proxied" => Some(UrlMode::Proxied),
+            _ => None,
+        }
+    }
+}
+
+/// Build the proxy-relative URL that fetches `target` through `/proxy`
+pub fn proxy_url(target: &Url) -> String {
+    format!("{}?url={}", PROXY_PATH, urlencoding::encode(target.as_str()))
+}
+
+/// Resolve a (possibly relative) reference against `base` and emit it in the given mode.
+/// Fragments, empty values and non-HTTP schemes (`data:`, `javascript:`, ...) are left alone.
+pub fn resolve(base: &Url, value: &str, mode: UrlMode) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() || value.starts_with('#') {
+        return None;
+    }
+
+    let target = base.join(value).ok()?;
+    if !matches!(target.scheme(), "http" | "https") {
+        return None;
+    }
+
+    Some(match mode {
+        UrlMode::Absolute => target.to_string(),
+        UrlMode::Proxied => proxy_url(&target),
+    })
+}
+
+/// Rewrite a redirect-style response header so the client stays on the proxy.
+/// Returns `None` when the header is not one we rewrite or its value can't be resolved.
+pub fn rewrite_header(name: &str, value: &str, base: &Url) -> Option<String> {
+    match name {
+        "location" | "content-location" => rewrite_location(value, base),
+        "refresh" => rewrite_refresh(value, base, UrlMode::Proxied),
+        _ => None,
+    }
+}
+
+/// Resolve a (possibly relative) location against the upstream URL and route it through the proxy
+pub fn rewrite_location(value: &str, base: &Url) -> Option<String> {
+    resolve(base, value, UrlMode::Proxied)
+}
+
+/// Rewrite a `<delay>; url=<target>` refresh value, as found in the `Refresh` header
+/// and `<meta http-equiv="refresh">`, keeping the delay intact
+pub fn rewrite_refresh(value: &str, base: &Url, mode: UrlMode) -> Option<String> {
+    let (delay, rest) = value.split_once([';', ','])?;
+    let rest = rest.trim_start();
+
+    // The "url=" prefix is optional and case-insensitive
+    let target = match rest.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("url=") => &rest[4..],
+        _ => rest,
+    };
+    let target = target.trim().trim_matches(|c| c == '"' || c == '\'');
+
+    resolve(base, target, mode).map(|resolved| format!("{}; url={}", delay.trim(), resolved))
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 16;
+
+/// Sessions by id, spread over separately locked shards so requests for different sessions
+/// rarely wait on each other. Holds at most `capacity` sessions; past that the least recently
+/// used are evicted.
+pub struct SessionMap<T> {
+    shards: Vec<Mutex<HashMap<String, Entry<T>>>>,
+    hasher: RandomState,
+    len: AtomicUsize,
+    capacity: usize,
+    /// Held while evicting, so concurrent inserts over capacity don't all scan the map
+    evicting: Mutex<()>,
+}
+
+struct Entry<T> {
+    value: Arc<T>,
+    last_used: Instant,
+}
+
+impl<T> SessionMap<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            len: AtomicUsize::new(0),
+            capacity,
+            evicting: Mutex::new(()),
+        }
+    }
+
+    fn shard(&self, id: &str) -> &Mutex<HashMap<String, Entry<T>>> {
+        &self.shards[self.hasher.hash_one(id) as usize % SHARDS]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Whether every shard can still be locked
+    pub fn is_usable(&self) -> bool {
+        self.shards.iter().all(|shard| shard.lock().is_ok())
+    }
+
+    /// A session without marking it as used
+    pub fn get(&self, id: &str) -> Option<Arc<T>> {
+        self.shard(id).lock().unwrap().get(id).map(|entry| entry.value.clone())
+    }
+
+    /// The session under `id`, created with `create` if there is none, marked as just used
+    pub fn get_or_insert_with(&self, id: &str, create: impl FnOnce() -> T) -> Arc<T> {
+        let now = Instant::now();
+        let mut shard = self.shard(id).lock().unwrap();
+        if let Some(entry) = shard.get_mut(id) {
+            entry.last_used = now;
+            return entry.value.clone();
+        }
+        let value = Arc::new(create());
+        shard.insert(id.to_string(), Entry { value: value.clone(), last_used: now });
+        drop(shard);
+        self.added();
+        value
+    }
+
+    /// Put back a session that was last used `idle` ago, replacing any under the same id
+    pub fn restore(&self, id: String, value: T, idle: Duration) {
+        let now = Instant::now();
+        let last_used = now.checked_sub(idle).unwrap_or(now);
+        let replaced = self.shard(&id).lock().unwrap().insert(id, Entry { value: Arc::new(value), last_used });
+        if replaced.is_none() {
+            self.added();
+        }
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Arc<T>> {
+        let removed = self.shard(id).lock().unwrap().remove(id)?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(removed.value)
+    }
+
+    /// Drop every session, returning how many there were
+    pub fn clear(&self) -> usize {
+        self.remove_where(|_| true)
+    }
+
+    /// Every session, in no particular order
+    pub fn values(&self) -> Vec<Arc<T>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().values().map(|entry| entry.value.clone()).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Drop sessions unused for `ttl`, returning how many
+    pub fn remove_idle(&self, ttl: Duration) -> usize {
+        let now = Instant::now();
+        self.remove_where(|entry| now.duration_since(entry.last_used) >= ttl)
+    }
+
+    fn remove_where(&self, mut doomed: impl FnMut(&Entry<T>) -> bool) -> usize {
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, entry| !doomed(entry));
+            removed += before - shard.len();
+        }
+        self.len.fetch_sub(removed, Ordering::Relaxed);
+        removed
+    }
+
+    fn added(&self) {
+        if self.len.fetch_add(1, Ordering::Relaxed) + 1 > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Evict the least recently used sessions down to capacity, and a twentieth below it so
+    /// the whole map isn't scanned on every new session
+    fn evict(&self) {
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return;
+        };
+        let len = self.len();
+        if len <= self.capacity {
+            return;
+        }
+        let excess = len - self.capacity + self.capacity / 20;
+        let mut last_used: Vec<Instant> =
+            self.shards.iter().flat_map(|shard| shard.lock().unwrap().values().map(|entry| entry.last_used).collect::<Vec<_>>()).collect();
+        if last_used.is_empty() {
+            return;
+        }
+        let nth = excess.min(last_used.len()) - 1;
+        let cutoff = *last_used.select_nth_unstable(nth).1;
+
+        let mut budget = excess;
+        let evicted = self.remove_where(|entry| {
+            let evict = budget > 0 && entry.last_used <= cutoff;
+            budget -= usize::from(evict);
+            evict
+        });
+        tracing::info!(evicted, capacity = self.capacity, "session capacity reached, evicted least recently used sessions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_are_evicted_past_capacity() {
+        let map = SessionMap::new(40);
+        for n in 0..40 {
+            map.restore(format!("s{}", n), n, Duration::from_secs(100 - n as u64));
+        }
+        assert_eq!(map.len(), 40);
+        // Used just now, so no longer among the oldest
+        map.get_or_insert_with("s0", || unreachable!());
+
+        map.get_or_insert_with("new", || 40);
+        // One over capacity, plus a twentieth of it
+        assert_eq!(map.len(), 38);
+        assert!(["s1", "s2", "s3"].iter().all(|id| map.get(id).is_none()));
+        assert!(["s0", "s4", "s39", "new"].iter().all(|id| map.get(id).is_some()));
+        assert_eq!(map.values().len(), 38);
+    }
+
+    #[test]
+    fn len_follows_restore_remove_and_remove_idle() {
+        let map = SessionMap::new(10);
+        map.restore("a".to_string(), 1, Duration::ZERO);
+        map.restore("b".to_string(), 2, Duration::ZERO);
+        map.restore("a".to_string(), 3, Duration::ZERO);
+        assert_eq!((map.len(), *map.get("a").unwrap()), (2, 3));
+
+        assert_eq!(map.remove("a").as_deref(), Some(&3));
+        assert!(map.remove("a").is_none());
+        assert_eq!(map.len(), 1);
+
+        map.restore("idle".to_string(), 4, Duration::from_secs(120));
+        map.get_or_insert_with("b", || unreachable!());
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove_idle(Duration::from_secs(60)), 1);
+        assert_eq!(map.len(), 1);
+        assert!(map.get("idle").is_none());
+
+        assert_eq!(map.clear(), 1);
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn capacity_of_one_keeps_the_most_recent_session() {
+        let map = SessionMap::new(1);
+        map.get_or_insert_with("a", || 1);
+        map.get_or_insert_with("b", || 2);
+        assert_eq!(map.len(), 1);
+        assert!(map.get("a").is_none());
+        assert_eq!(*map.get_or_insert_with("b", || unreachable!()), 2);
+
+        // A restored session idle for longer than the live one is the one evicted
+        map.restore("a".to_string(), 1, Duration::from_secs(10));
+        assert_eq!(map.len(), 1);
+        assert!(map.get("a").is_none() && map.get("b").is_some());
+    }
+}
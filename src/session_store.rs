@@ -0,0 +1,291 @@
+use cookie_store::{CookieDomain, CookieExpiration};
+use reqwest_cookie_store::{CookieStore, RawCookie};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use time::OffsetDateTime;
+use url::Url;
+
+/// A cookie as it is exported, imported and persisted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredCookie {
+    pub name: String,
+    pub value: String,
+    /// The host the cookie was set by, or with `host_only` off, the domain whose subdomains
+    /// also receive it
+    pub domain: String,
+    #[serde(default)]
+    pub host_only: bool,
+    #[serde(default = "root_path")]
+    pub path: String,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub http_only: bool,
+    /// Unix time the cookie expires at; absent for cookies that last as long as the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+fn root_path() -> String {
+    "/".to_string()
+}
+
+/// Every unexpired cookie in `store`, including ones without an expiry
+pub fn export(store: &CookieStore) -> Vec<StoredCookie> {
+    store
+        .iter_unexpired()
+        .filter_map(|cookie| {
+            let (domain, host_only) = match &cookie.domain {
+                CookieDomain::HostOnly(host) => (host.clone(), true),
+                CookieDomain::Suffix(domain) => (domain.clone(), false),
+                CookieDomain::NotPresent | CookieDomain::Empty => return None,
+            };
+            Some(StoredCookie {
+                name: cookie.name().to_string(),
+                value: cookie.value().to_string(),
+                domain,
+                host_only,
+                path: cookie.path.to_string(),
+                secure: cookie.secure().unwrap_or(false),
+                http_only: cookie.http_only().unwrap_or(false),
+                expires: match &cookie.expires {
+                    CookieExpiration::AtUtc(at) => Some(at.unix_timestamp()),
+                    CookieExpiration::SessionEnd => None,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Add `cookies` to `store`, replacing same-named ones. Either all are added or, on the first
+/// cookie that can't be, none are. Already expired cookies are skipped. Returns how many were added.
+pub fn import(store: &mut CookieStore, cookies: &[StoredCookie]) -> Result<usize, String> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut staged = store.clone();
+    let mut added = 0;
+    for (n, cookie) in cookies.iter().enumerate() {
+        if cookie.expires.is_some_and(|at| at <= now) {
+            continue;
+        }
+        let at = |problem: String| format!("cookie {} ({}): {}", n + 1, cookie.name, problem);
+        let domain = cookie.domain.trim().trim_start_matches('.').to_ascii_lowercase();
+        let scheme = if cookie.secure { "https" } else { "http" };
+        let url = Url::parse(&format!("{}://{}{}", scheme, domain, cookie.path))
+            .map_err(|e| at(format!("invalid domain or path: {}", e)))?;
+
+        let mut raw = RawCookie::build(cookie.name.clone(), cookie.value.clone())
+            .path(cookie.path.clone())
+            .secure(cookie.secure)
+            .http_only(cookie.http_only);
+        if !cookie.host_only {
+            raw = raw.domain(domain.clone());
+        }
+        if let Some(expires) = cookie.expires {
+            let expires = OffsetDateTime::from_unix_timestamp(expires).map_err(|e| at(format!("invalid expiry: {}", e)))?;
+            raw = raw.expires(expires);
+        }
+        staged.insert_raw(&raw.finish(), &url).map_err(|e| at(e.to_string()))?;
+        added += 1;
+    }
+    *store = staged;
+    Ok(added)
+}
+
+/// Drop cookies until at most `max` remain: expired ones first, then those expiring soonest.
+/// Cookies without an expiry go last, as they tend to hold logins. Returns how many were dropped.
+pub fn trim(store: &mut CookieStore, max: usize) -> usize {
+    let mut cookies: Vec<_> = store
+        .iter_any()
+        .map(|cookie| {
+            let expires = match &cookie.expires {
+                CookieExpiration::AtUtc(at) if !cookie.is_expired() => Some(*at),
+                CookieExpiration::AtUtc(_) => Some(OffsetDateTime::UNIX_EPOCH),
+                CookieExpiration::SessionEnd => None,
+            };
+            (expires.is_none(), expires, String::from(&cookie.domain), cookie.path.to_string(), cookie.name().to_string())
+        })
+        .collect();
+    if cookies.len() <= max {
+        return 0;
+    }
+    cookies.sort_unstable();
+    let excess = cookies.len() - max;
+    for (_, _, domain, path, name) in cookies.into_iter().take(excess) {
+        store.remove(&domain, &path, &name);
+    }
+    excess
+}
+
+/// Render cookies in the Netscape `cookies.txt` format read by curl, wget and browser extensions
+pub fn to_netscape(cookies: &[StoredCookie]) -> String {
+    let mut out = String::from("# Netscape HTTP Cookie File\n");
+    for cookie in cookies {
+        let domain = if cookie.host_only { cookie.domain.clone() } else { format!(".{}", cookie.domain) };
+        let flag = |set: bool| if set { "TRUE" } else { "FALSE" };
+        out.push_str(&format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            if cookie.http_only { "#HttpOnly_" } else { "" },
+            domain,
+            flag(!cookie.host_only),
+            cookie.path,
+            flag(cookie.secure),
+            cookie.expires.unwrap_or(0),
+            cookie.name,
+            cookie.value
+        ));
+    }
+    out
+}
+
+/// Parse a Netscape `cookies.txt` file, skipping comments and blank lines
+pub fn from_netscape(text: &str) -> Result<Vec<StoredCookie>, String> {
+    let mut cookies = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+            Some(rest) => (rest, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+            return Err(format!("line {}: expected 7 tab-separated fields, found {}", n + 1, fields.len()));
+        };
+        let flag = |field: &str| match field.to_ascii_uppercase().as_str() {
+            "TRUE" => Ok(true),
+            "FALSE" => Ok(false),
+            other => Err(format!("line {}: expected TRUE or FALSE, found {:?}", n + 1, other)),
+        };
+        let expires: i64 = expires.trim().parse().map_err(|_| format!("line {}: invalid expiry {:?}", n + 1, expires))?;
+        cookies.push(StoredCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: domain.trim_start_matches('.').to_string(),
+            host_only: !flag(subdomains)?,
+            path: path.to_string(),
+            secure: flag(secure)?,
+            http_only,
+            expires: (expires != 0).then_some(expires),
+        });
+    }
+    Ok(cookies)
+}
+
+/// Write `sessions` to `path` (readable by the owner only), replacing it only once fully written
+pub fn save<T: Serialize>(path: &Path, sessions: &[T]) -> Result<(), String> {
+    let json = serde_json::to_vec(sessions).map_err(|e| e.to_string())?;
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".tmp");
+    let staging = Path::new(&staging);
+    crate::pqc::write_private(staging, &json, false)?;
+    std::fs::rename(staging, path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Read sessions written by `save`
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, String> {
+    let json = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookie(name: &str, domain: &str, host_only: bool, expires: Option<i64>) -> StoredCookie {
+        StoredCookie {
+            name: name.to_string(),
+            value: format!("{}-value", name),
+            domain: domain.to_string(),
+            host_only,
+            path: "/".to_string(),
+            secure: false,
+            http_only: false,
+            expires,
+        }
+    }
+
+    fn in_secs(secs: i64) -> Option<i64> {
+        Some(OffsetDateTime::now_utc().unix_timestamp() + secs)
+    }
+
+    /// Every field of `cookies`, sorted by name, for comparing lists
+    fn fields(mut cookies: Vec<StoredCookie>) -> Vec<serde_json::Value> {
+        cookies.sort_by(|a, b| a.name.cmp(&b.name));
+        cookies.iter().map(|cookie| serde_json::to_value(cookie).unwrap()).collect()
+    }
+
+    fn names(store: &CookieStore) -> Vec<String> {
+        let mut names: Vec<_> = store.iter_any().map(|cookie| cookie.name().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn netscape_round_trip_keeps_http_only_and_host_only_cookies() {
+        let cookies = vec![
+            StoredCookie { http_only: true, secure: true, path: "/account".to_string(), ..cookie("login", "example.com", false, in_secs(3600)) },
+            cookie("host", "www.example.com", true, None),
+        ];
+        let text = to_netscape(&cookies);
+        assert!(text.contains("#HttpOnly_.example.com\tTRUE\t/account\tTRUE\t"), "{}", text);
+        assert!(text.contains("\nwww.example.com\tFALSE\t/\tFALSE\t0\thost\thost-value\n"), "{}", text);
+        assert_eq!(fields(from_netscape(&text).unwrap()), fields(cookies.clone()));
+
+        // ... and through a cookie jar
+        let mut store = CookieStore::default();
+        assert_eq!(import(&mut store, &from_netscape(&text).unwrap()), Ok(2));
+        assert_eq!(fields(export(&store)), fields(cookies));
+        let host_only = store.get_request_values(&Url::parse("http://sub.www.example.com/").unwrap()).count();
+        assert_eq!(host_only, 0);
+    }
+
+    #[test]
+    fn bad_line_rejects_the_whole_file() {
+        let text = "# Netscape HTTP Cookie File\n\
+                    .example.com\tTRUE\t/\tFALSE\t0\tgood\t1\n\
+                    .example.com\tTRUE\t/\tFALSE\tsoon\tbad\t2\n";
+        assert_eq!(from_netscape(text).unwrap_err(), "line 3: invalid expiry \"soon\"");
+        assert_eq!(from_netscape("example.com\tTRUE\t/\tFALSE\t0\tshort\n").unwrap_err(), "line 1: expected 7 tab-separated fields, found 6");
+        assert!(from_netscape("example.com\tYES\t/\tFALSE\t0\tflag\t1\n").unwrap_err().contains("expected TRUE or FALSE"));
+    }
+
+    #[test]
+    fn bad_cookie_rejects_the_whole_import() {
+        let mut store = CookieStore::default();
+        import(&mut store, &[cookie("kept", "example.com", false, None)]).unwrap();
+
+        let cookies = [cookie("kept", "example.com", false, in_secs(60)), cookie("new", "example.com", false, None), cookie("bad", "exa mple.com", false, None)];
+        let error = import(&mut store, &cookies).unwrap_err();
+        assert!(error.starts_with("cookie 3 (bad): invalid domain or path"), "{}", error);
+        assert_eq!(names(&store), ["kept"]);
+        assert_eq!(export(&store)[0].expires, None);
+
+        // Already expired cookies are skipped rather than rejected
+        assert_eq!(import(&mut store, &[cookie("old", "example.com", false, in_secs(-60)), cookies[1].clone()]), Ok(1));
+        assert_eq!(names(&store), ["kept", "new"]);
+    }
+
+    #[test]
+    fn trim_drops_expired_then_soonest_expiring_and_session_cookies_last() {
+        let mut store = CookieStore::default();
+        let cookies = [
+            cookie("session", "example.com", false, None),
+            cookie("later", "example.com", false, in_secs(7200)),
+            cookie("soon", "example.com", false, in_secs(600)),
+            cookie("expiring", "example.com", false, in_secs(1)),
+        ];
+        import(&mut store, &cookies).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        assert_eq!((store.iter_any().count(), store.iter_unexpired().count()), (4, 3));
+        assert_eq!(trim(&mut store, 4), 0);
+
+        assert_eq!(trim(&mut store, 3), 1);
+        assert_eq!(names(&store), ["later", "session", "soon"]);
+        assert_eq!(trim(&mut store, 2), 1);
+        assert_eq!(names(&store), ["later", "session"]);
+        assert_eq!(trim(&mut store, 1), 1);
+        assert_eq!(names(&store), ["session"]);
+    }
+}
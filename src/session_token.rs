@@ -0,0 +1,197 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, SET_COOKIE};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use sha3::{Digest, Sha3_256};
+use std::net::IpAddr;
+
+/// Request and response header carrying the session token, for clients without cookies
+pub const HEADER: &str = "x-proxy-session";
+/// Cookie carrying the session token
+pub const COOKIE: &str = "proxy_session";
+
+/// Issues and checks the opaque tokens that identify browsing sessions.
+///
+/// A token is `<id>.<tag>`: 16 random bytes and a SHA3-256 tag keyed on a server secret, both
+/// base64url. A Dilithium signature, as used for auth tokens, would not fit in a cookie.
+pub struct SessionTokens {
+    key: [u8; 32],
+}
+
+/// A token issued while handling the request, for `attach` to hand to the client
+#[derive(Clone)]
+struct Issued(String);
+
+impl SessionTokens {
+    /// Tags are keyed on a hash of `secret`, so tokens stay valid across restarts as long as it does
+    pub fn new(secret: &[u8]) -> Self {
+        let key = Sha3_256::new().chain_update(b"vpn-server session token\0").chain_update(secret).finalize();
+        Self { key: key.into() }
+    }
+
+    fn tag(&self, id: &str) -> [u8; 32] {
+        Sha3_256::new().chain_update(self.key).chain_update(id.as_bytes()).finalize().into()
+    }
+
+    fn issue(&self) -> (String, String) {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = general_purpose::URL_SAFE_NO_PAD.encode(id);
+        let token = format!("{}.{}", id, general_purpose::URL_SAFE_NO_PAD.encode(self.tag(&id)));
+        (session_key(&id), token)
+    }
+
+    /// The session a genuine token stands for
+    fn verify(&self, token: &str) -> Option<String> {
+        let (id, tag) = token.trim().split_once('.')?;
+        let tag: [u8; 32] = general_purpose::URL_SAFE_NO_PAD.decode(tag).ok()?.try_into().ok()?;
+        let expected = self.tag(id);
+        let matches = tag.iter().zip(&expected).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0;
+        matches.then(|| session_key(id))
+    }
+
+    /// The session a request belongs to: a valid token from the `X-Proxy-Session` header or the
+    /// session cookie, else the client address when `ip_fallback` is set, else a new session
+    /// whose token `attach` returns with the response
+    pub fn session_id(&self, req: &HttpRequest, ip_fallback: bool, client_ip: IpAddr) -> String {
+        let header = req.headers().get(HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let cookie = req.cookie(COOKIE).map(|cookie| cookie.value().to_string());
+        let presented: Vec<String> = header.into_iter().chain(cookie).collect();
+        if let Some(id) = presented.iter().find_map(|token| self.verify(token)) {
+            return id;
+        }
+        if !presented.is_empty() {
+            tracing::debug!("ignoring invalid session token");
+        }
+
+        if ip_fallback {
+            return format!("session_{}", general_purpose::STANDARD.encode(client_ip.to_string()));
+        }
+        self.start_new(req)
+    }
+
+    /// Begin a new session for this request, regardless of any token it carries
+    pub fn start_new(&self, req: &HttpRequest) -> String {
+        let (id, token) = self.issue();
+        req.extensions_mut().insert(Issued(token));
+        id
+    }
+}
+
+fn session_key(id: &str) -> String {
+    format!("session_{}", id)
+}
+
+/// Hand a token issued during the request to the client, in both the session cookie and the
+/// `X-Proxy-Session` header
+pub async fn attach(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut response = next.call(req).await?;
+    let issued = response.request().extensions().get::<Issued>().cloned();
+    if let Some(Issued(token)) = issued {
+        let secure = if crate::forwarding::is_tls(response.request()) { "; Secure" } else { "" };
+        let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax{}", COOKIE, token, secure);
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            headers.append(SET_COOKIE, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            headers.insert(HeaderName::from_static(HEADER), value);
+        }
+    }
+    Ok(response)
+}
+
+/// Remove the session cookie from a `Cookie` header bound for an upstream; `None` when
+/// nothing else is left
+pub fn strip_cookie(header: &[u8]) -> Option<Vec<u8>> {
+    let header = String::from_utf8_lossy(header);
+    let rest: Vec<&str> = header
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && pair.split_once('=').is_none_or(|(name, _)| name.trim() != COOKIE))
+        .collect();
+    (!rest.is_empty()).then(|| rest.join("; ").into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn issued_token_verifies() {
+        let tokens = SessionTokens::new(b"secret");
+        let (id, token) = tokens.issue();
+        assert_eq!(tokens.verify(&token), Some(id));
+    }
+
+    #[test]
+    fn changed_id_or_tag_is_rejected() {
+        let tokens = SessionTokens::new(b"secret");
+        let (_, token) = tokens.issue();
+        let (id, tag) = token.split_once('.').unwrap();
+        let flip = |s: &str| {
+            let mut chars: Vec<char> = s.chars().collect();
+            chars[0] = if chars[0] == 'A' { 'B' } else { 'A' };
+            chars.into_iter().collect::<String>()
+        };
+
+        assert_eq!(tokens.verify(&format!("{}.{}", flip(id), tag)), None);
+        assert_eq!(tokens.verify(&format!("{}.{}", id, flip(tag))), None);
+        assert_eq!(tokens.verify(&format!("{}.{}", id, &tag[1..])), None);
+        assert_eq!(tokens.verify(id), None);
+        assert_eq!(tokens.verify(""), None);
+    }
+
+    #[test]
+    fn token_from_another_secret_is_rejected() {
+        let (_, token) = SessionTokens::new(b"secret").issue();
+        assert_eq!(SessionTokens::new(b"other").verify(&token), None);
+    }
+
+    #[test]
+    fn session_comes_from_header_or_cookie() {
+        let tokens = SessionTokens::new(b"secret");
+        let (id, token) = tokens.issue();
+
+        let req = TestRequest::default().insert_header((HEADER, token.as_str())).to_http_request();
+        assert_eq!(tokens.session_id(&req, false, IP), id);
+        let req = TestRequest::default().insert_header(("cookie", format!("{}={}", COOKIE, token))).to_http_request();
+        assert_eq!(tokens.session_id(&req, false, IP), id);
+        assert!(req.extensions().get::<Issued>().is_none());
+    }
+
+    #[test]
+    fn invalid_token_starts_a_new_session_or_falls_back_to_ip() {
+        let tokens = SessionTokens::new(b"secret");
+        let (id, token) = tokens.issue();
+        let forged = format!("{}x", token);
+
+        let req = TestRequest::default().insert_header((HEADER, forged.as_str())).to_http_request();
+        let fresh = tokens.session_id(&req, false, IP);
+        assert_ne!(fresh, id);
+        let Some(Issued(issued)) = req.extensions().get::<Issued>().cloned() else {
+            panic!("no token issued for the new session");
+        };
+        assert_eq!(tokens.verify(&issued), Some(fresh));
+
+        let req = TestRequest::default().insert_header((HEADER, forged.as_str())).to_http_request();
+        assert_eq!(tokens.session_id(&req, true, IP), format!("session_{}", general_purpose::STANDARD.encode("192.0.2.1")));
+    }
+
+    #[test]
+    fn strips_only_the_session_cookie() {
+        let header = format!("a=1; {}=tok; b=2", COOKIE);
+        assert_eq!(strip_cookie(header.as_bytes()), Some(b"a=1; b=2".to_vec()));
+        assert_eq!(strip_cookie(format!("{}=tok", COOKIE).as_bytes()), None);
+        assert_eq!(strip_cookie(b"proxy_session_x=1"), Some(b"proxy_session_x=1".to_vec()));
+    }
+}
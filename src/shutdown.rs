@@ -0,0 +1,120 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServerHandle, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Requests whose response has not been fully sent yet
+///
+/// actix-server's own graceful stop can lose track of open connections when its accept thread
+/// exits first, so draining on shutdown waits on this count instead.
+#[derive(Default)]
+pub struct InFlight {
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Resolve once no request is in flight
+    async fn drained(&self) {
+        loop {
+            // Registered before the check so a request finishing in between is not missed
+            let idle = self.idle.notified();
+            if self.active() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Counts a request as in flight until dropped
+struct Guard(web::Data<InFlight>);
+
+impl Guard {
+    fn enter(in_flight: web::Data<InFlight>) -> Self {
+        in_flight.active.fetch_add(1, Ordering::AcqRel);
+        Self(in_flight)
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Keep each request counted in `InFlight` until its response body has been sent
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<TrackedBody<impl MessageBody>>, Error> {
+    let guard = req.app_data::<web::Data<InFlight>>().cloned().map(Guard::enter);
+    let response = next.call(req).await?;
+    Ok(response.map_body(|_, body| TrackedBody { body, _guard: guard }))
+}
+
+/// A response body that holds its request's in-flight guard until dropped
+pub struct TrackedBody<B> {
+    body: B,
+    _guard: Option<Guard>,
+}
+
+impl<B: MessageBody + Unpin> MessageBody for TrackedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+/// On SIGTERM or Ctrl-C, stop accepting connections and give in-flight requests up to
+/// `timeout` to finish before the servers stop; a second signal stops them at once
+pub async fn stop_on_signal(handles: Vec<ServerHandle>, in_flight: web::Data<InFlight>, timeout: Duration) {
+    signal().await;
+    tracing::info!(active = in_flight.active(), timeout_secs = timeout.as_secs(), "shutting down, draining in-flight requests");
+    for handle in &handles {
+        handle.pause().await;
+    }
+
+    tokio::select! {
+        () = in_flight.drained() => tracing::info!("in-flight requests finished"),
+        () = tokio::time::sleep(timeout) => {
+            tracing::warn!(active = in_flight.active(), "shutdown timeout reached, closing remaining requests");
+        }
+        () = signal() => {
+            tracing::warn!(active = in_flight.active(), "second signal, closing remaining requests");
+        }
+    }
+    futures_util::future::join_all(handles.iter().map(|handle| handle.stop(false))).await;
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
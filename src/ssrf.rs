@@ -0,0 +1,320 @@
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use url::Url;
+
+/// Which destinations `/proxy` refuses to connect to
+#[derive(Debug, Clone)]
+pub struct SsrfPolicy {
+    /// Block loopback, private, link-local, multicast and other non-public addresses
+    pub enabled: bool,
+    /// Extra networks to refuse, on top of the built-in ones
+    pub blocked: Vec<IpNet>,
+    /// Networks that may be reached even though they would otherwise be blocked
+    pub allowed: Vec<IpNet>,
+}
+
+impl Default for SsrfPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            blocked: Vec::new(),
+            allowed: Vec::new(),
+        }
+    }
+}
+
+/// A destination the policy refused, with a reason suitable for the client
+#[derive(Debug, Clone)]
+pub struct Blocked(pub String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Blocked {}
+
+impl SsrfPolicy {
+    /// Check a single address against the allow list, the built-in ranges and the block list
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Blocked> {
+        // An IPv6 address wrapping an IPv4 one reaches that IPv4 host, so the lists match either
+        let embedded = match ip {
+            IpAddr::V6(v6) => embedded_v4(v6).map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        let listed = |net: &&IpNet| net.contains(&ip) || embedded.is_some_and(|v4| net.contains(&v4));
+        if !self.enabled || self.allowed.iter().any(|net| listed(&net)) {
+            return Ok(());
+        }
+        if let Some(kind) = special_purpose(ip) {
+            return Err(Blocked(format!("{} is a {} address", ip, kind)));
+        }
+        if let Some(net) = self.blocked.iter().find(listed) {
+            return Err(Blocked(format!("{} is in blocked network {}", ip, net)));
+        }
+        Ok(())
+    }
+
+    /// Check a URL before it is fetched: only http(s), and every address its host resolves to
+    /// must be allowed
+    pub async fn check_url(&self, url: &Url) -> Result<(), Blocked> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Blocked(format!("scheme '{}' is not allowed", url.scheme())));
+        }
+        if !self.enabled {
+            return Ok(());
+        }
+
+        match url.host() {
+            Some(url::Host::Ipv4(ip)) => self.check_ip(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => self.check_ip(IpAddr::V6(ip)),
+            Some(url::Host::Domain(host)) => {
+                let port = url.port_or_known_default().unwrap_or(80);
+                let addrs = tokio::net::lookup_host((host, port))
+                    .await
+                    .map_err(|e| Blocked(format!("could not resolve {}: {}", host, e)))?;
+                for addr in addrs {
+                    self.check_ip(addr.ip())
+                        .map_err(|Blocked(reason)| Blocked(format!("{} resolves to a blocked address: {}", host, reason)))?;
+                }
+                Ok(())
+            }
+            None => Err(Blocked("URL has no host".to_string())),
+        }
+    }
+
+    /// Check a redirect target without blocking on DNS: the scheme, and the address when the
+    /// host is an IP literal. Hosts given by name are checked at connect time by [`GuardedResolver`].
+    pub fn check_redirect(&self, url: &Url) -> Result<(), Blocked> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Blocked(format!("redirect to scheme '{}' is not allowed", url.scheme())));
+        }
+        match url.host() {
+            Some(url::Host::Ipv4(ip)) => self.check_ip(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => self.check_ip(IpAddr::V6(ip)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// DNS resolver that refuses names resolving to blocked addresses.
+///
+/// Because the check happens on the addresses the connection is actually made to, a host
+/// can't pass the pre-flight check and then rebind to an internal address.
+pub struct GuardedResolver {
+    policy: Arc<SsrfPolicy>,
+}
+
+impl GuardedResolver {
+    pub fn new(policy: Arc<SsrfPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            for addr in &addrs {
+                policy.check_ip(addr.ip())
+                    .map_err(|Blocked(reason)| Blocked(format!("{} resolves to a blocked address: {}", host, reason)))?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The policy violation behind a failed request, if that is why it failed
+pub fn blocked_reason(err: &reqwest::Error) -> Option<String> {
+    let mut source: Option<&(dyn Error + 'static)> = err.source();
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<Blocked>() {
+            return Some(blocked.0.clone());
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// Name of the special-purpose range an address falls in, or `None` for public addresses
+fn special_purpose(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(v4) => special_purpose_v4(v4),
+        IpAddr::V6(v6) => special_purpose_v6(v6),
+    }
+}
+
+fn special_purpose_v4(ip: Ipv4Addr) -> Option<&'static str> {
+    let [a, b, _, _] = ip.octets();
+    if ip.is_unspecified() || a == 0 {
+        Some("unspecified")
+    } else if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_private() {
+        Some("private")
+    } else if ip.is_link_local() {
+        // Includes the 169.254.169.254 cloud metadata endpoint
+        Some("link-local")
+    } else if a == 100 && (64..128).contains(&b) {
+        Some("shared (carrier-grade NAT)")
+    } else if a == 198 && (b == 18 || b == 19) {
+        Some("benchmarking")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_broadcast() || a >= 240 {
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+/// The IPv4 host an IPv6 address leads to: IPv4-mapped (`::ffff:a.b.c.d`), IPv4-compatible
+/// (`::a.b.c.d`), NAT64 (`64:ff9b::a.b.c.d`), 6to4 (`2002:aabb:ccdd::`) and Teredo
+/// (`2001:0::`, client address inverted in the last 32 bits)
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let [.., a, b, c, d] = ip.octets();
+    let low = Ipv4Addr::new(a, b, c, d);
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        Some(v4)
+    } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] || segments[..6] == [0; 6] {
+        Some(low)
+    } else if segments[0] == 0x2002 {
+        let [a, b] = segments[1].to_be_bytes();
+        let [c, d] = segments[2].to_be_bytes();
+        Some(Ipv4Addr::new(a, b, c, d))
+    } else if segments[..2] == [0x2001, 0] {
+        Some(Ipv4Addr::from(!u32::from(low)))
+    } else {
+        None
+    }
+}
+
+fn special_purpose_v6(ip: Ipv6Addr) -> Option<&'static str> {
+    let segments = ip.segments();
+    if ip.is_unspecified() {
+        return Some("unspecified");
+    } else if ip.is_loopback() {
+        return Some("loopback");
+    }
+    if let Some(v4) = embedded_v4(ip) {
+        return special_purpose_v4(v4);
+    }
+
+    if ip.is_multicast() {
+        Some("multicast")
+    } else if segments[0] & 0xfe00 == 0xfc00 {
+        Some("unique local")
+    } else if segments[0] & 0xffc0 == 0xfe80 || segments[0] & 0xffc0 == 0xfec0 {
+        Some("link-local")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked(policy: &SsrfPolicy, ip: &str) -> bool {
+        policy.check_ip(ip.parse().unwrap()).is_err()
+    }
+
+    #[test]
+    fn blocks_special_purpose_ipv4() {
+        let policy = SsrfPolicy::default();
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "224.0.0.1", "255.255.255.255", "198.18.0.1"] {
+            assert!(blocked(&policy, ip), "{} should be blocked", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "172.32.0.1"] {
+            assert!(!blocked(&policy, ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_special_purpose_ipv6() {
+        let policy = SsrfPolicy::default();
+        for ip in ["::", "::1", "fc00::1", "fd12::1", "fe80::1", "ff02::1"] {
+            assert!(blocked(&policy, ip), "{} should be blocked", ip);
+        }
+        assert!(!blocked(&policy, "2606:4700::1111"));
+    }
+
+    #[test]
+    fn judges_ipv6_wrapping_ipv4_by_the_ipv4_host() {
+        let policy = SsrfPolicy::default();
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::7f00:1",
+            "::a00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2001:0:4136:e378:8000:63bf:80ff:fffe",
+        ] {
+            assert!(blocked(&policy, ip), "{} should be blocked", ip);
+        }
+        for ip in ["::ffff:8.8.8.8", "::808:808", "2002:808:808::1", "64:ff9b::808:808"] {
+            assert!(!blocked(&policy, ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn allow_and_block_lists_match_embedded_ipv4() {
+        let policy = SsrfPolicy {
+            enabled: true,
+            blocked: vec!["8.8.8.0/24".parse().unwrap()],
+            allowed: vec!["127.0.0.2/32".parse().unwrap()],
+        };
+        assert!(!blocked(&policy, "127.0.0.2"));
+        assert!(!blocked(&policy, "::ffff:127.0.0.2"));
+        assert!(blocked(&policy, "127.0.0.3"));
+        assert!(blocked(&policy, "8.8.8.8"));
+        assert!(blocked(&policy, "::ffff:8.8.8.8"));
+        assert!(blocked(&policy, "2002:808:808::"));
+    }
+
+    #[test]
+    fn disabled_policy_allows_everything() {
+        let policy = SsrfPolicy { enabled: false, ..SsrfPolicy::default() };
+        assert!(!blocked(&policy, "127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn checks_url_scheme_and_literal_hosts() {
+        let policy = SsrfPolicy::default();
+        let check = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            let policy = policy.clone();
+            async move { policy.check_url(&url).await }
+        };
+        assert!(check("file:///etc/passwd").await.is_err());
+        assert!(check("ftp://93.184.216.34/").await.is_err());
+        assert!(check("http://127.0.0.1:8080/").await.is_err());
+        assert!(check("http://[::ffff:7f00:1]/").await.is_err());
+        assert!(check("http://[::7f00:1]/").await.is_err());
+        assert!(check("http://2130706433/").await.is_err(), "decimal IPv4 is normalised to 127.0.0.1");
+        assert!(check("http://0x7f.1/").await.is_err());
+        assert!(check("https://93.184.216.34/").await.is_ok());
+        assert!(check("http://localhost/").await.is_err());
+    }
+
+    #[test]
+    fn checks_redirect_targets() {
+        let policy = SsrfPolicy::default();
+        let check = |url: &str| policy.check_redirect(&Url::parse(url).unwrap());
+        assert!(check("gopher://example.com/").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data/").is_err());
+        assert!(check("http://[2002:a9fe:a9fe::]/").is_err());
+        assert!(check("https://example.com/").is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use rand::Rng;
+use std::io::IsTerminal;
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+/// Header carrying the request ID in both directions
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable, one line per event with its span fields
+    Pretty,
+    /// One JSON object per event, for log shippers
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "pretty" | "text" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoggingSettings {
+    pub format: LogFormat,
+    /// `tracing` filter directives, e.g. `info` or `info,vpn_server=debug`
+    pub level: String,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self { format: LogFormat::Pretty, level: "info".to_string() }
+    }
+}
+
+impl LoggingSettings {
+    pub fn filter(&self) -> Result<EnvFilter, String> {
+        EnvFilter::try_new(&self.level).map_err(|e| format!("invalid log level '{}': {}", self.level, e))
+    }
+}
+
+/// Install the global subscriber
+pub fn init(settings: &LoggingSettings) -> Result<(), String> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(settings.filter()?)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal());
+    let result = match settings.format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// Run each request inside a span carrying its ID, method, path and (once known) session and
+/// destination host, log its status and duration, and echo the ID in `X-Request-Id`
+pub async fn request_span(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(generate_request_id, str::to_string);
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        session_id = tracing::field::Empty,
+        target_host = tracing::field::Empty,
+        status = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    );
+    // Health probes arrive every few seconds and would drown out everything else
+    let is_probe = matches!(req.path(), "/healthz" | "/readyz");
+    let started = Instant::now();
+    let mut response = next.call(req).instrument(span.clone()).await?;
+
+    let status = response.status();
+    span.record("status", status.as_u16());
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    span.in_scope(|| {
+        if status.is_server_error() {
+            tracing::warn!("request finished");
+        } else if is_probe {
+            tracing::debug!("request finished");
+        } else {
+            tracing::info!("request finished");
+        }
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+/// What the handlers learned about a request, kept in its extensions for the access log and metrics
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    /// Authenticated subject, if credentials were presented
+    pub subject: Option<String>,
+    pub session_id: Option<String>,
+    /// Destination URL and host of a proxied request
+    pub target_url: Option<String>,
+    pub target_host: Option<String>,
+    /// Status the upstream answered with
+    pub upstream_status: Option<u16>,
+}
+
+impl RequestInfo {
+    pub fn of(req: &HttpRequest) -> Self {
+        req.extensions().get::<RequestInfo>().cloned().unwrap_or_default()
+    }
+
+    fn update(req: &HttpRequest, f: impl FnOnce(&mut RequestInfo)) {
+        let mut extensions = req.extensions_mut();
+        match extensions.get_mut::<RequestInfo>() {
+            Some(info) => f(info),
+            None => {
+                let mut info = RequestInfo::default();
+                f(&mut info);
+                extensions.insert(info);
+            }
+        }
+    }
+}
+
+pub fn record_subject(req: &HttpRequest, subject: &str) {
+    RequestInfo::update(req, |info| info.subject = Some(subject.to_string()));
+}
+
+/// Record the session on the current request span and for the access log
+pub fn record_session(req: &HttpRequest, session_id: &str) {
+    RequestInfo::update(req, |info| info.session_id = Some(session_id.to_string()));
+    tracing::Span::current().record("session_id", session_id);
+}
+
+/// Record the session and destination on the current request span, the access log and the metrics
+pub fn record_target(req: &HttpRequest, session_id: &str, target_url: &str, target_host: &str) {
+    record_session(req, session_id);
+    RequestInfo::update(req, |info| {
+        info.target_url = Some(target_url.to_string());
+        info.target_host = Some(target_host.to_string());
+    });
+    tracing::Span::current().record("target_host", target_host);
+}
+
+pub fn record_upstream_status(req: &HttpRequest, status: u16) {
+    RequestInfo::update(req, |info| info.upstream_status = Some(status));
+}
+
+/// Client-supplied IDs are kept if short and free of anything that could break log lines
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn generate_request_id() -> String {
+    format!("{:032x}", rand::thread_rng().r#gen::<u128>())
+}
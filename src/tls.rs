@@ -0,0 +1,216 @@
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// A certificate chain and private key (both PEM), served to clients asking for one of `names`
+#[derive(Debug, Clone)]
+pub struct CertificatePaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Server names this certificate is chosen for via SNI; exact or `*.domain`. The first
+    /// certificate also serves clients that send no name or one that matches nothing.
+    pub names: Vec<String>,
+}
+
+/// HTTPS listeners and the certificates they present
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    /// Addresses served over HTTPS; TLS is off when empty
+    pub listeners: Vec<String>,
+    pub certificates: Vec<CertificatePaths>,
+    /// Plain-HTTP addresses that only redirect to the first HTTPS listener
+    pub redirect_listeners: Vec<String>,
+    /// Host, with an optional port, that plain-HTTP redirects point at instead of the
+    /// request's `Host` header
+    pub public_host: Option<String>,
+    /// How often certificate files are checked for changes, in seconds
+    pub reload_secs: u64,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            listeners: Vec::new(),
+            certificates: Vec::new(),
+            redirect_listeners: Vec::new(),
+            public_host: None,
+            reload_secs: 30,
+        }
+    }
+}
+
+impl TlsSettings {
+    pub fn enabled(&self) -> bool {
+        !self.listeners.is_empty()
+    }
+}
+
+/// Split a `Host` header value into its host and port, refusing anything else (paths, user
+/// info) that would change where a URL built from it points. IPv6 literals stay bracketed.
+pub fn parse_authority(value: &str) -> Option<(String, Option<u16>)> {
+    let url = url::Url::parse(&format!("http://{}", value.trim())).ok()?;
+    let plain = url.username().is_empty()
+        && url.password().is_none()
+        && url.path() == "/"
+        && url.query().is_none()
+        && url.fragment().is_none()
+        && !value.contains(['/', '?', '#', '@', '\\']);
+    let host = url.host()?.to_string();
+    plain.then(|| (host, url.port()))
+}
+
+/// Certificates loaded from disk, indexed by server name
+struct CertSet {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl CertSet {
+    fn load(certificates: &[CertificatePaths], provider: &CryptoProvider) -> Result<Self, String> {
+        let mut by_name = HashMap::new();
+        let mut default = None;
+        for paths in certificates {
+            let key = Arc::new(load_certified_key(paths, provider)?);
+            for name in &paths.names {
+                by_name.insert(name.trim().trim_end_matches('.').to_ascii_lowercase(), key.clone());
+            }
+            default.get_or_insert(key);
+        }
+        let default = default.ok_or("no certificates configured")?;
+        Ok(Self { by_name, default })
+    }
+
+    /// The certificate for an exact name, then for its `*.parent` wildcard, then the default
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(|n| n.trim_end_matches('.').to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+        self.by_name
+            .get(&name)
+            .or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.by_name.get(&format!("*.{}", parent))
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// The active certificates, reloaded when their files change
+pub struct CertStore {
+    certificates: Vec<CertificatePaths>,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertSet>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertStore").field("certificates", &self.certificates).finish()
+    }
+}
+
+impl CertStore {
+    pub fn open(settings: &TlsSettings) -> Result<Self, String> {
+        let provider = Arc::new(ring::default_provider());
+        let set = CertSet::load(&settings.certificates, &provider)?;
+        Ok(Self {
+            modified: Mutex::new(modified_times(&settings.certificates)),
+            certificates: settings.certificates.clone(),
+            provider,
+            current: RwLock::new(Arc::new(set)),
+        })
+    }
+
+    /// Configuration for an HTTPS listener, picking certificates from this store per connection
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, String> {
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        Ok(config)
+    }
+
+    /// Re-read every certificate. If any fails to load, the previous set stays active.
+    pub fn reload(&self) {
+        *self.modified.lock().unwrap() = modified_times(&self.certificates);
+        match CertSet::load(&self.certificates, &self.provider) {
+            Ok(set) => {
+                tracing::info!(certificates = self.certificates.len(), "reloaded TLS certificates");
+                crate::audit::record("tls_certificates_reloaded", &[("certificates", &self.certificates.len().to_string())]);
+                *self.current.write().unwrap() = Arc::new(set);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "keeping previous TLS certificates");
+                crate::audit::record("tls_certificate_reload_failed", &[("error", &e)]);
+            }
+        }
+    }
+
+    /// Reload whenever a certificate or key file changes (checked every `interval`) or on SIGHUP
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        #[cfg(unix)]
+        {
+            let store = self.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                    return;
+                };
+                while hangup.recv().await.is_some() {
+                    store.reload();
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if modified_times(&self.certificates) != *self.modified.lock().unwrap() {
+                    self.reload();
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().lookup(client_hello.server_name()))
+    }
+}
+
+fn load_certified_key(paths: &CertificatePaths, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+    let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|e| format!("{}: {}", path.display(), e));
+
+    let chain = rustls_pemfile::certs(&mut open(&paths.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", paths.cert.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", paths.cert.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&paths.key)?)
+        .map_err(|e| format!("{}: {}", paths.key.display(), e))?
+        .ok_or_else(|| format!("{}: no private key found", paths.key.display()))?;
+
+    CertifiedKey::from_der(chain, key, provider)
+        .map_err(|e| format!("{} / {}: {}", paths.cert.display(), paths.key.display(), e))
+}
+
+fn modified_times(certificates: &[CertificatePaths]) -> Vec<Option<SystemTime>> {
+    certificates
+        .iter()
+        .flat_map(|paths| [&paths.cert, &paths.key])
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}